@@ -50,6 +50,18 @@ pub fn parse_metainfo(content: &Vec<u8>) -> Torrent {
         }
     }
 
+    // Web seeds come as a single URL or a list of them
+    let web_seeds = match dict.get("url-list") {
+        Some(url @ Value::Str(_)) => http_urls(std::slice::from_ref(url)),
+        Some(Value::List(urls)) => http_urls(urls),
+        _ => vec![],
+    };
+    let http_seeds = match dict.get("httpseeds") {
+        Some(Value::List(urls)) => http_urls(urls),
+        _ => vec![],
+    };
+
+    let info_bytes = get_info_bytes(content, 0).unwrap().to_vec();
     let info_hash = get_info_hash(content, 0).unwrap();
     // Print as a hex string
     println!(
@@ -60,6 +72,9 @@ pub fn parse_metainfo(content: &Vec<u8>) -> Torrent {
     Torrent {
         trackers,
         info_hash,
+        web_seeds,
+        http_seeds,
+        info_bytes,
         info: match &dict["info"] {
             Value::Dict(info_map) => {
                 let name = match &info_map["name"] {
@@ -110,12 +125,15 @@ pub fn parse_metainfo(content: &Vec<u8>) -> Torrent {
                     None => None,
                 };
 
+                let private = matches!(info_map.get("private"), Some(Value::Number(1)));
+
                 Info {
                     name,
                     piece_length,
                     pieces,
                     length,
                     files,
+                    private,
                 }
             }
             _ => panic!("info is not a dictionary"),
@@ -123,6 +141,18 @@ pub fn parse_metainfo(content: &Vec<u8>) -> Torrent {
     }
 }
 
+/// The HTTP(S) URLs among `urls`.
+fn http_urls(urls: &[Value]) -> Vec<String> {
+    urls.iter()
+        .filter_map(|url| match url {
+            Value::Str(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                Some(url.clone())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Value {
     Number(i64),
@@ -133,20 +163,34 @@ pub enum Value {
     Hashes(Vec<[u8; 20]>),
     Hash([u8; 20]),
     Peers(Vec<[u8; 6]>),
+    Peers6(Vec<[u8; 18]>),
+}
+
+impl Value {
+    /// Raw bytes of a string value. Byte strings that happen to be valid
+    /// UTF-8 decode as `Str`, so binary fields may come back as either.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Str(s) => Some(s.as_bytes()),
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
 }
 
 pub fn decode_dictionary(content: &[u8], index: &mut usize) -> Result<Value, String> {
-    if content[*index] != torrent::DICTIONARY_START {
+    let byte = byte_at(content, *index)?;
+    if byte != torrent::DICTIONARY_START {
         return Err(format!(
             "Expected dictionary start 'd' at index {}, found '{}'",
-            index, content[*index] as char
+            index, byte as char
         ));
     }
 
     *index += 1; // move past 'd'
     let mut map = HashMap::new();
 
-    while content[*index] != torrent::DICTIONARY_END {
+    while byte_at(content, *index)? != torrent::DICTIONARY_END {
         let key = get_string(content, index)?;
         if key == "pieces" {
             map.insert(key, Value::Hashes(parse_hashes(content, index)?));
@@ -158,27 +202,26 @@ pub fn decode_dictionary(content: &[u8], index: &mut usize) -> Result<Value, Str
             continue;
         }
 
+        if key == "peers6" {
+            map.insert(key, Value::Peers6(parse_peers(content, index)?));
+            continue;
+        }
+
         let value = parse_next(content, index)?;
         map.insert(key, value);
     }
-
-    if content[*index] != torrent::DICTIONARY_END {
-        return Err(format!(
-            "Expected dictionary end 'e' at index {}, found '{}'",
-            index, content[*index] as char
-        ));
-    }
     *index += 1; // move past 'e'
 
     Ok(Value::Dict(map))
 }
 
 fn parse_next(content: &[u8], index: &mut usize) -> Result<Value, String> {
-    if content[*index] == torrent::INTEGER_START {
+    let byte = byte_at(content, *index)?;
+    if byte == torrent::INTEGER_START {
         parse_number(content, index)
-    } else if content[*index] == torrent::DICTIONARY_START {
+    } else if byte == torrent::DICTIONARY_START {
         decode_dictionary(content, index)
-    } else if content[*index] == torrent::LIST_START {
+    } else if byte == torrent::LIST_START {
         parse_list(content, index)
     } else {
         let bytes = get_bytes(content, index)?;
@@ -192,11 +235,12 @@ fn parse_next(content: &[u8], index: &mut usize) -> Result<Value, String> {
 
 fn parse_number(content: &[u8], index: &mut usize) -> Result<Value, String> {
     *index += 1;
-    let number = get_next_number(content, index);
-    if content[*index] != torrent::INTEGER_END {
+    let number = get_next_number(content, index)?;
+    let byte = byte_at(content, *index)?;
+    if byte != torrent::INTEGER_END {
         return Err(format!(
             "Expected integer end 'e' at index {}, found '{}'",
-            index, content[*index] as char
+            index, byte as char
         ));
     }
     *index += 1; // move past 'e'
@@ -206,84 +250,54 @@ fn parse_number(content: &[u8], index: &mut usize) -> Result<Value, String> {
 fn parse_list(content: &[u8], index: &mut usize) -> Result<Value, String> {
     *index += 1; // move past 'l'
     let mut list = Vec::new();
-    while content[*index] != torrent::LIST_END {
+    while byte_at(content, *index)? != torrent::LIST_END {
         list.push(parse_next(content, index)?);
     }
 
-    if content[*index] != torrent::LIST_END {
-        return Err(format!(
-            "Expected list end 'e' at index {}, found '{}'",
-            index, content[*index] as char
-        ));
-    }
-
     *index += 1; // move past 'e'
     Ok(Value::List(list))
 }
 
-// Fix the parse_hashes function
 fn parse_hashes(content: &[u8], index: &mut usize) -> Result<Vec<[u8; 20]>, String> {
-    let size = get_next_number(content, index);
-
-    // Check for colon separator
-    if content[*index] != torrent::COLON {
-        return Err(format!(
-            "Expected colon ':' after pieces length at index {}, found '{}'",
-            index, content[*index] as char
-        ));
-    }
-
-    *index += 1; // move past ':'
-
-    let mut hashes = Vec::new();
-    let mut remaining = size;
-
-    while remaining >= 20 {
-        let hash_slice = &content[*index..*index + 20];
-        let mut hash = [0u8; 20];
-        hash.copy_from_slice(hash_slice);
-        hashes.push(hash);
-        *index += 20;
-        remaining -= 20;
-    }
-
-    Ok(hashes)
+    let bytes = get_bytes(content, index)?;
+    Ok(bytes
+        .chunks_exact(20)
+        .map(|hash| hash.try_into().unwrap())
+        .collect())
 }
 
-fn parse_peers(content: &[u8], index: &mut usize) -> Result<Vec<[u8; 6]>, String> {
-    let size = get_next_number(content, index) as usize;
-    if content[*index] != torrent::COLON {
-        return Err(format!(
-            "Expected colon ':' after peers length at index {}, found '{}'",
-            index, content[*index] as char
-        ));
-    }
-
-    *index += 1; // move past ':'
-    let mut peers = Vec::new();
-    let end = *index + size;
-    while *index + 6 <= end {
-        let peer_slice = &content[*index..*index + 6];
-        let mut peer = [0u8; 6];
-        peer.copy_from_slice(peer_slice);
-        peers.push(peer);
-        *index += 6;
-    }
-
-    Ok(peers)
+fn parse_peers<const N: usize>(content: &[u8], index: &mut usize) -> Result<Vec<[u8; N]>, String> {
+    // Any trailing partial entry is skipped
+    let bytes = get_bytes(content, index)?;
+    Ok(bytes
+        .chunks_exact(N)
+        .map(|peer| peer.try_into().unwrap())
+        .collect())
 }
 
 fn get_bytes(content: &[u8], index: &mut usize) -> Result<Vec<u8>, String> {
-    let size = get_next_number(content, index) as usize;
-    if content[*index] != torrent::COLON {
+    let start = *index;
+    let size = usize::try_from(get_next_number(content, index)?)
+        .map_err(|_| format!("Negative byte string length at index {}", start))?;
+    let byte = byte_at(content, *index)?;
+    if byte != torrent::COLON {
         return Err(format!(
             "Expected colon ':' after byte string length at index {}, found '{}'",
-            index, content[*index] as char
+            index, byte as char
         ));
     }
 
     *index += 1;
-    let bytes = content[*index..*index + size].to_vec();
+    let bytes = index
+        .checked_add(size)
+        .and_then(|end| content.get(*index..end))
+        .ok_or_else(|| {
+            format!(
+                "Byte string of {} bytes at index {} runs past the end",
+                size, start
+            )
+        })?
+        .to_vec();
     *index += size;
     Ok(bytes)
 }
@@ -293,26 +307,37 @@ fn get_string(content: &[u8], index: &mut usize) -> Result<String, String> {
     String::from_utf8(bytes).map_err(|e| format!("Invalid UTF-8 string at index {}: {}", index, e))
 }
 
-fn get_next_number(content: &[u8], index: &mut usize) -> i64 {
+fn get_next_number(content: &[u8], index: &mut usize) -> Result<i64, String> {
+    let start = *index;
     let mut n: i64 = 0;
     let mut negative: i64 = 1;
     loop {
-        let char = content[*index] as char;
+        let char = byte_at(content, *index)? as char;
         if n == 0 && char == '-' {
             negative = -1;
             *index += 1;
             continue;
         }
 
-        if !char.is_ascii_digit() {
+        let Some(digit) = char.to_digit(10) else {
             break;
-        }
-        n *= 10;
-        n += char.to_digit(10).unwrap() as i64;
+        };
+        n = n
+            .checked_mul(10)
+            .and_then(|n| n.checked_add(digit as i64))
+            .ok_or_else(|| format!("Number at index {} is too large", start))?;
         *index += 1;
     }
 
-    n * negative
+    Ok(n * negative)
+}
+
+/// The byte at `index`, or an error for input that ends too early.
+fn byte_at(content: &[u8], index: usize) -> Result<u8, String> {
+    content
+        .get(index)
+        .copied()
+        .ok_or_else(|| format!("Unexpected end of input at index {}", index))
 }
 
 #[allow(dead_code)]
@@ -328,6 +353,7 @@ pub fn print_map(map: &HashMap<String, Value>) {
             Value::List(l) => print_list(l),
             Value::Hash(h) => print!("{:?} ", h),
             Value::Peers(p) => print!("{:?} ", p),
+            Value::Peers6(p) => print!("{:?} ", p),
         };
     }
 }
@@ -343,6 +369,7 @@ fn print_list(list: &Vec<Value>) {
             Value::List(l) => print_list(l),
             Value::Hash(h) => print!("{:?} ", h),
             Value::Peers(p) => print!("{:?} ", p),
+            Value::Peers6(p) => print!("{:?} ", p),
         }
     }
     println!();
@@ -355,6 +382,14 @@ fn print_hashes(hashes: &Vec<[u8; 20]>) {
 }
 
 pub fn get_info_hash(content: &Vec<u8>, start: usize) -> Result<[u8; 20], String> {
+    let info = get_info_bytes(content, start)?;
+    let mut hasher = Sha1::new();
+    hasher.update(info);
+    Ok(hasher.finalize().into())
+}
+
+/// Finds the raw bencoded "info" dictionary, exactly as it appears in the file.
+pub fn get_info_bytes(content: &[u8], start: usize) -> Result<&[u8], String> {
     let mut index = start;
 
     // Find the "info" dictionary
@@ -363,33 +398,28 @@ pub fn get_info_hash(content: &Vec<u8>, start: usize) -> Result<[u8; 20], String
             index += 1;
             let key = get_string(content, &mut index)?;
             if key == "info" {
-                // We found the "info" key, now hash the corresponding dictionary
-                let start = index - key.len() - 1; // include the length prefix and colon
-                let mut hasher = Sha1::new();
+                // We found the "info" key, now take the corresponding dictionary
+                let start = index;
                 decode_dictionary(content, &mut index)?; // parse to move the index forward
                 let end = index; // end of the "info" dictionary
 
-                hasher.update(&content[start..end]);
-                return Ok(hasher.finalize().into());
+                return Ok(&content[start..end]);
             } else {
                 // Skip this dictionary entry
-                return get_info_hash(content, index);
+                return get_info_bytes(content, index);
             }
         } else {
             let next = parse_next(content, &mut index)?;
-            dbg!(&next);
             if let Value::Str(value) = next {
                 if value == "info" {
                     let start = index;
-                    let mut hasher = Sha1::new();
-                    decode_dictionary(content, &mut index); // parse to move the index forward
+                    decode_dictionary(content, &mut index)?; // parse to move the index forward
                     let end = index; // end of the "info" dictionary
-                    hasher.update(&content[start..end]);
-                    return Ok(hasher.finalize().into());
+                    return Ok(&content[start..end]);
                 }
             }
         }
     }
 
-    panic!("'info' dictionary not found");
+    Err("'info' dictionary not found".to_string())
 }
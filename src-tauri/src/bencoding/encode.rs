@@ -74,6 +74,7 @@ pub fn encode_value(value: &Value) -> Vec<u8> {
         Value::Hashes(h) => encode_hashes(h),
         Value::Hash(h) => h.to_vec(),
         Value::Peers(p) => p.concat(),
+        Value::Peers6(p) => p.concat(),
     }
 }
 
@@ -81,7 +82,11 @@ pub fn encode_dictionary(dict: &HashMap<String, Value>) -> Vec<u8> {
     let mut ret = Vec::<u8>::new();
     ret.push(DICTIONARY_START);
 
-    for (key, value) in dict.iter() {
+    // Bencoded dictionaries must have their keys in sorted order
+    let mut entries: Vec<_> = dict.iter().collect();
+    entries.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+
+    for (key, value) in entries {
         ret.extend_from_slice(&encode_string(key));
         ret.extend_from_slice(&encode_value(value));
     }
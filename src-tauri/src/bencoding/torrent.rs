@@ -1,4 +1,4 @@
-use std::{collections::HashMap, hash::Hash};
+use std::{collections::HashMap, hash::Hash, ops::Range};
 
 use serde::{Deserialize, Serialize};
 
@@ -15,6 +15,15 @@ pub struct Torrent {
     pub trackers: Vec<Tracker>,
     pub info: Info,
     pub info_hash: [u8; 20],
+    /// HTTP(S) mirrors of the content from `url-list` (BEP 19).
+    #[serde(default)]
+    pub web_seeds: Vec<String>,
+    /// Seeding scripts from `httpseeds` (BEP 17).
+    #[serde(default)]
+    pub http_seeds: Vec<String>,
+    /// The bencoded info dictionary, kept around to serve it to other peers.
+    #[serde(skip)]
+    pub info_bytes: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -24,6 +33,8 @@ pub struct Info {
     pub pieces: Vec<[u8; 20]>,
     pub length: Option<i64>,
     pub files: Option<Vec<File>>,
+    /// Private torrents (BEP 27) may only get peers from their trackers.
+    pub private: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -47,6 +58,25 @@ impl Torrent {
         }
     }
 
+    /// The pieces holding any part of file `file_index`, empty for files
+    /// without data.
+    pub fn file_pieces(&self, file_index: usize) -> Range<u32> {
+        let Some(files) = &self.info.files else {
+            return 0..self.info.pieces.len() as u32;
+        };
+        let Some(file) = files.get(file_index) else {
+            return 0..0;
+        };
+        if file.length == 0 {
+            return 0..0;
+        }
+
+        let start: u64 = files[..file_index].iter().map(|f| f.length as u64).sum();
+        let end = start + file.length as u64;
+        let piece_length = self.info.piece_length;
+        (start / piece_length) as u32..end.div_ceil(piece_length) as u32
+    }
+
     pub fn get_piece_length(&self, piece_index: usize) -> u32 {
         let piece_length = self.info.piece_length;
         let total_length = self.total_length();
@@ -1,5 +1,5 @@
 use core::panic;
-use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 
 use url::Url;
 
@@ -18,10 +18,10 @@ pub trait HTTPResponse {
 
 #[derive(Copy, Clone)]
 pub enum Action {
-    ConnectRequest = 0,
-    ConnectResponse = 1,
-    AnnounceRequest = 2,
-    AnnounceResponse = 3,
+    Connect = 0,
+    Announce = 1,
+    Scrape = 2,
+    Error = 3,
 }
 
 #[derive(Copy, Clone)]
@@ -30,6 +30,8 @@ pub enum Event {
     Completed = 1,
     Started = 2,
     Stopped = 3,
+    /// We're a partial seed, downloading nothing more (BEP 21).
+    Paused = 4,
 }
 
 impl Event {
@@ -39,6 +41,7 @@ impl Event {
             Event::Completed => "completed",
             Event::Started => "started",
             Event::Stopped => "stopped",
+            Event::Paused => "paused",
         }
     }
 }
@@ -177,11 +180,15 @@ impl HTTPResponse for TrackerResponse {
                 None
             };
 
-            let peers = if let Value::Peers(s) = &map["peers"] {
+            let mut peers: Vec<Peer> = if let Some(Value::Peers(s)) = map.get("peers") {
                 s.iter().map(|x| Peer::from(*x)).collect()
             } else {
                 vec![]
             };
+            // IPv6 peers come in their own key (BEP 7)
+            if let Some(Value::Peers6(s)) = map.get("peers6") {
+                peers.extend(s.iter().map(Peer::from));
+            }
 
             TrackerResponse {
                 failure: None,
@@ -199,6 +206,46 @@ impl HTTPResponse for TrackerResponse {
     }
 }
 
+/// Magic constant that starts every UDP tracker connect request (BEP 15).
+const UDP_TRACKER_PROTOCOL_ID: u64 = 0x41727101980;
+
+pub struct ConnectRequest {
+    pub transaction_id: u32,
+}
+
+impl From<ConnectRequest> for Vec<u8> {
+    fn from(request: ConnectRequest) -> Self {
+        let mut buf = [0; 16];
+        buf[0..8].copy_from_slice(&UDP_TRACKER_PROTOCOL_ID.to_be_bytes());
+        buf[8..12].copy_from_slice(&(Action::Connect as u32).to_be_bytes());
+        buf[12..16].copy_from_slice(&request.transaction_id.to_be_bytes());
+        buf.to_vec()
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectResponse {
+    pub action: u32,
+    pub transaction_id: u32,
+    pub connection_id: u64,
+}
+
+impl TryFrom<&[u8]> for ConnectResponse {
+    type Error = String;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() < 16 {
+            return Err(format!("Connect response too short: {} bytes", bytes.len()));
+        }
+
+        Ok(ConnectResponse {
+            action: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            transaction_id: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            connection_id: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        })
+    }
+}
+
 pub struct AnnounceRequest {
     pub connection_id: u64,
     pub action: Action,
@@ -227,11 +274,10 @@ impl From<AnnounceRequest> for Vec<u8> {
         buf[64..72].copy_from_slice(&request.left.to_be_bytes());
         buf[72..80].copy_from_slice(&request.uploaded.to_be_bytes());
         buf[80..84].copy_from_slice(&(request.event as u32).to_be_bytes());
-        if let Some(ip) = &request.ip {
-            match ip {
-                IpAddr::V4(ipv4) => buf[84..88].copy_from_slice(&ipv4.octets()),
-                IpAddr::V6(_) => buf[84..88].copy_from_slice(&[0, 0, 0, 0]), // or handle IPv6 as needed
-            }
+        // The field only fits an IPv4 address. IPv6 clients leave it 0 and
+        // the tracker uses the address the announce came from
+        if let Some(IpAddr::V4(ipv4)) = &request.ip {
+            buf[84..88].copy_from_slice(&ipv4.octets());
         }
         buf[88..92].copy_from_slice(&request.key.to_be_bytes());
         buf[92..96].copy_from_slice(&request.num_want.to_be_bytes());
@@ -249,12 +295,28 @@ pub struct Peer {
 
 impl Peer {
     pub fn to_string(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
+        SocketAddr::new(self.ip, self.port).to_string()
+    }
+
+    /// The compact form used by trackers, the DHT and PEX: 6 bytes for IPv4
+    /// peers and 18 bytes for IPv6 peers.
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut bytes = match self.ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        bytes.extend_from_slice(&self.port.to_be_bytes());
+        bytes
     }
 }
 
 impl From<String> for Peer {
     fn from(s: String) -> Self {
+        // Handles both `1.2.3.4:6881` and `[::1]:6881`
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Peer::from(addr);
+        }
+
         let parts: Vec<&str> = s.split(':').collect();
         if parts.len() != 2 {
             panic!("Invalid peer string: {}", s);
@@ -270,6 +332,15 @@ impl From<String> for Peer {
     }
 }
 
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer {
+            ip: addr.ip().to_canonical(),
+            port: addr.port(),
+        }
+    }
+}
+
 impl From<[u8; 6]> for Peer {
     fn from(bytes: [u8; 6]) -> Self {
         Peer {
@@ -281,21 +352,33 @@ impl From<[u8; 6]> for Peer {
 
 impl From<&[u8; 6]> for Peer {
     fn from(bytes: &[u8; 6]) -> Self {
+        Peer::from(*bytes)
+    }
+}
+
+impl From<[u8; 18]> for Peer {
+    fn from(bytes: [u8; 18]) -> Self {
         Peer {
-            ip: IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[0..4]).unwrap())),
-            port: u16::from_be_bytes(bytes[4..6].try_into().unwrap()),
+            ip: IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[0..16]).unwrap())),
+            port: u16::from_be_bytes(bytes[16..18].try_into().unwrap()),
         }
     }
 }
 
+impl From<&[u8; 18]> for Peer {
+    fn from(bytes: &[u8; 18]) -> Self {
+        Peer::from(*bytes)
+    }
+}
+
 impl TryFrom<&[u8]> for Peer {
     type Error = String;
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
-        let peer_bytes: [u8; 6] = bytes
-            .try_into()
-            .map_err(|_| "Invalid peer bytes, expected 6 bytes".to_string())?;
-
-        Ok(Peer::from(peer_bytes))
+        match bytes.len() {
+            6 => Ok(Peer::from(<[u8; 6]>::try_from(bytes).unwrap())),
+            18 => Ok(Peer::from(<[u8; 18]>::try_from(bytes).unwrap())),
+            _ => Err("Invalid peer bytes, expected 6 or 18 bytes".to_string()),
+        }
     }
 }
 
@@ -311,13 +394,22 @@ pub struct AnnounceResponse {
 
 impl From<&[u8]> for AnnounceResponse {
     fn from(bytes: &[u8]) -> Self {
+        AnnounceResponse::from_bytes(bytes, false)
+    }
+}
+
+impl AnnounceResponse {
+    /// Trackers answer announces sent over IPv6 with 18 byte IPv6 peers
+    /// instead of 6 byte IPv4 ones (BEP 15).
+    pub fn from_bytes(bytes: &[u8], is_ipv6: bool) -> Self {
         let action = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
         let transaction_id = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
         let interval = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
         let leechers = u32::from_be_bytes(bytes[12..16].try_into().unwrap());
         let seeders = u32::from_be_bytes(bytes[16..20].try_into().unwrap());
+        let peer_size = if is_ipv6 { 18 } else { 6 };
         let peers: Vec<Peer> = bytes[20..]
-            .chunks_exact(6)
+            .chunks_exact(peer_size)
             .map(Peer::try_from)
             .filter_map(Result::ok)
             .collect();
@@ -1,6 +1,6 @@
 use std::{
     collections::{HashMap, HashSet},
-    net::UdpSocket,
+    sync::Arc,
     time::Duration,
 };
 
@@ -12,21 +12,22 @@ use crate::{
         krpc_request::{KRPCRequest, KRPCRequestGetPeers, KRPCRequestPing},
         krpc_response::KRPCResponse,
     },
+    util::dual_stack,
+    utp::socket::UtpSocket,
 };
 
+/// How long we wait for each DHT reply.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct DhtClient {
-    socket: UdpSocket,
+    /// Shared with uTP, which hands us everything that isn't uTP.
+    socket: Arc<UtpSocket>,
     node_id: [u8; 20],
     pub nodes: Vec<DhtNode>,
 }
 
 impl DhtClient {
-    pub fn new(trackers: Vec<String>) -> Self {
-        let socket = UdpSocket::bind("0.0.0.0:6881").unwrap();
-        socket
-            .set_read_timeout(Some(Duration::from_secs(1)))
-            .unwrap();
-
+    pub fn new(socket: Arc<UtpSocket>, trackers: Vec<String>) -> Self {
         let mut node_id = [0u8; 20];
         rand::rng().fill(&mut node_id);
 
@@ -134,9 +135,9 @@ impl DhtClient {
 
         let req = KRPCRequestGetPeers::new(self.node_id, *info_hash);
         let encoded: Vec<u8> = req.clone().into();
-        let addr = node.location.to_string();
+        let addr = dual_stack::resolve_for(self.local_addr()?, &node.location)?;
         self.socket
-            .send_to(&encoded, &addr)
+            .send_datagram(&encoded, addr)
             .map_err(|e| format!("Failed to send get_peers to {}: {}", addr, e))?;
 
         Ok(req)
@@ -147,9 +148,9 @@ impl DhtClient {
         let ping_request = KRPCRequestPing::new(self.node_id);
         let encoded: Vec<u8> = ping_request.into();
 
-        let addr = node.location.to_string();
+        let addr = dual_stack::resolve_for(self.local_addr()?, &node.location)?;
         self.socket
-            .send_to(&encoded, &addr)
+            .send_datagram(&encoded, addr)
             .map_err(|e| format!("Failed to send ping to {}: {}", addr, e))?;
 
         if let Some(res) = self.recv_response() {
@@ -167,20 +168,25 @@ impl DhtClient {
     }
 
     pub fn recv_response(&self) -> Option<KRPCResponse> {
-        let mut buf = [0u8; 65536];
         loop {
-            match self.socket.recv_from(&mut buf) {
-                Ok((size, src)) => match KRPCResponse::try_from(&buf[..size]) {
+            match self.socket.recv_datagram(RESPONSE_TIMEOUT) {
+                Some((buf, src)) => match KRPCResponse::try_from(&buf[..]) {
                     Ok(res) => return Some(res),
                     Err(err) => {
                         println!("Failed to parse from {}: {}", src, err);
                         continue; // skip garbage, keep waiting
                     }
                 },
-                Err(_) => return None, // timeout = genuinely no response
+                None => return None, // timeout = genuinely no response
             }
         }
     }
+
+    fn local_addr(&self) -> Result<std::net::SocketAddr, String> {
+        self.socket
+            .local_addr()
+            .map_err(|e| format!("Failed to get local address: {}", e))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -121,6 +121,14 @@ impl From<KRPCRequestGetPeers> for Vec<u8> {
                     "info_hash".to_string(),
                     Value::Bytes(req.info_hash.to_vec()),
                 );
+                // Ask for both IPv4 and IPv6 nodes (BEP 32)
+                args.insert(
+                    "want".to_string(),
+                    Value::List(vec![
+                        Value::Bytes(b"n4".to_vec()),
+                        Value::Bytes(b"n6".to_vec()),
+                    ]),
+                );
                 args
             }),
         );
@@ -129,32 +129,16 @@ impl TryFrom<&HashMap<String, Value>> for KRPCResponseFindNode {
             _ => return Err("Missing or invalid node ID in response".to_string()),
         };
 
-        let nodes = match res.get("nodes") {
-            Some(Value::Bytes(b)) => {
-                if !b.len().is_multiple_of(26) {
-                    return Err(
-                        "Invalid 'nodes' value: length must be a multiple of 26".to_string()
-                    );
-                }
-                (0..b.len())
-                    .step_by(26)
-                    .map(|i| {
-                        let node_info = &b[i..i + 26];
-
-                        let node_id: [u8; 20] = node_info[0..20].try_into().unwrap();
-                        let ip = format!(
-                            "{}.{}.{}.{}",
-                            node_info[20], node_info[21], node_info[22], node_info[23]
-                        );
-                        let port = ((node_info[24] as u16) << 8) | (node_info[25] as u16);
-                        let location = format!("{}:{}", ip, port);
-
-                        DhtNode::new(Some(node_id), location)
-                    })
-                    .collect()
-            }
-            _ => return Err("Missing or invalid 'nodes' value in response".to_string()),
-        };
+        if !res.contains_key("nodes") && !res.contains_key("nodes6") {
+            return Err("Missing or invalid 'nodes' value in response".to_string());
+        }
+        let mut nodes = vec![];
+        if let Some(b) = res.get("nodes").and_then(Value::as_bytes) {
+            nodes.extend(parse_compact_nodes(b, false)?);
+        }
+        if let Some(b) = res.get("nodes6").and_then(Value::as_bytes) {
+            nodes.extend(parse_compact_nodes(b, true)?);
+        }
 
         Ok(KRPCResponseFindNode {
             transaction_id,
@@ -195,18 +179,13 @@ impl TryFrom<&HashMap<String, Value>> for KRPCResponseGetPeers {
             _ => None,
         };
 
+        // Each value is a compact peer, 6 bytes for IPv4 and 18 for IPv6
         let peers = match res.get("values") {
             Some(Value::Peers(p)) => Some(p.iter().map(Peer::from).collect()),
             Some(Value::List(list)) => {
                 let parsed: Vec<Peer> = list
                     .iter()
-                    .filter_map(|v| {
-                        if let Value::Bytes(b) = v {
-                            b.as_slice().try_into().ok()
-                        } else {
-                            None
-                        }
-                    })
+                    .filter_map(|v| Peer::try_from(v.as_bytes()?).ok())
                     .collect();
                 if parsed.is_empty() {
                     None
@@ -217,36 +196,14 @@ impl TryFrom<&HashMap<String, Value>> for KRPCResponseGetPeers {
             _ => None,
         };
 
-        let nodes = match res.get("nodes") {
-            Some(Value::Bytes(b)) if b.is_empty() => None,
-            Some(Value::Str(s)) if s.is_empty() => None,
-            Some(Value::Bytes(b)) => {
-                if b.len() % 26 != 0 {
-                    return Err(
-                        "Invalid 'nodes' value: length must be a multiple of 26".to_string()
-                    );
-                }
-                Some(
-                    (0..b.len())
-                        .step_by(26)
-                        .map(|i| {
-                            let node_info = &b[i..i + 26];
-
-                            let node_id: [u8; 20] = node_info[0..20].try_into().unwrap();
-                            let ip = format!(
-                                "{}.{}.{}.{}",
-                                node_info[20], node_info[21], node_info[22], node_info[23]
-                            );
-                            let port = ((node_info[24] as u16) << 8) | (node_info[25] as u16);
-                            let location = format!("{}:{}", ip, port);
-
-                            DhtNode::new(Some(node_id), location)
-                        })
-                        .collect(),
-                )
-            }
-            _ => None,
-        };
+        let mut nodes = vec![];
+        if let Some(b) = res.get("nodes").and_then(Value::as_bytes) {
+            nodes.extend(parse_compact_nodes(b, false)?);
+        }
+        if let Some(b) = res.get("nodes6").and_then(Value::as_bytes) {
+            nodes.extend(parse_compact_nodes(b, true)?);
+        }
+        let nodes = if nodes.is_empty() { None } else { Some(nodes) };
 
         Ok(KRPCResponseGetPeers {
             transaction_id,
@@ -258,6 +215,27 @@ impl TryFrom<&HashMap<String, Value>> for KRPCResponseGetPeers {
     }
 }
 
+/// Compact node info is a 20 byte node id followed by a compact peer, 26
+/// bytes per node in `nodes` and 38 in `nodes6` (BEP 32).
+fn parse_compact_nodes(bytes: &[u8], ipv6: bool) -> Result<Vec<DhtNode>, String> {
+    let node_size = if ipv6 { 38 } else { 26 };
+    if !bytes.len().is_multiple_of(node_size) {
+        return Err(format!(
+            "Invalid nodes value: length must be a multiple of {}",
+            node_size
+        ));
+    }
+
+    Ok(bytes
+        .chunks_exact(node_size)
+        .filter_map(|node_info| {
+            let node_id: [u8; 20] = node_info[0..20].try_into().unwrap();
+            let peer = Peer::try_from(&node_info[20..]).ok()?;
+            Some(DhtNode::new(Some(node_id), peer.to_string()))
+        })
+        .collect())
+}
+
 impl TryFrom<&HashMap<String, Value>> for KRPCError {
     type Error = String;
 
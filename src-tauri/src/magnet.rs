@@ -0,0 +1,144 @@
+use url::Url;
+
+use crate::{
+    bencoding::{decode::Value, encode::encode_value, torrent::Tracker},
+    connection::Peer,
+};
+
+/// A parsed `magnet:` link. Only BitTorrent info hashes (`urn:btih:`) are
+/// supported.
+#[derive(Debug)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    pub display_name: Option<String>,
+    pub trackers: Vec<Tracker>,
+    /// Peers listed directly in the link with `x.pe`.
+    pub peers: Vec<Peer>,
+}
+
+impl TryFrom<&str> for MagnetLink {
+    type Error = String;
+
+    fn try_from(link: &str) -> Result<Self, Self::Error> {
+        let url = Url::parse(link).map_err(|e| format!("Invalid magnet link: {}", e))?;
+        if url.scheme() != "magnet" {
+            return Err(format!("Not a magnet link: {}", link));
+        }
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                "tr" => trackers.push(match value.starts_with("http") {
+                    true => Tracker::Http(value.into_owned()),
+                    false => Tracker::Udp(value.into_owned()),
+                }),
+                "x.pe" => {
+                    if let Ok(addr) = value.parse::<std::net::SocketAddr>() {
+                        peers.push(Peer {
+                            ip: addr.ip(),
+                            port: addr.port(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash.ok_or("Magnet link has no urn:btih info hash")?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+impl MagnetLink {
+    /// The name to save the link's .torrent file under: the display name
+    /// with anything that could leave the directory taken out, or the hex
+    /// info hash if nothing usable is left.
+    pub fn file_name(&self) -> String {
+        let name: String = self
+            .display_name
+            .as_deref()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' | ':' => '_',
+                c if c.is_control() => '_',
+                c => c,
+            })
+            .collect();
+        let name = match name.trim().trim_start_matches('.') {
+            "" => hex::encode(self.info_hash),
+            name => name.to_string(),
+        };
+        format!("{}.torrent", name)
+    }
+
+    /// Wraps a downloaded info dictionary into a .torrent file, keeping the
+    /// trackers from the link.
+    pub fn to_torrent_file(&self, info_bytes: &[u8]) -> Vec<u8> {
+        let mut content = b"d".to_vec();
+        if !self.trackers.is_empty() {
+            let tiers = self
+                .trackers
+                .iter()
+                .map(|tracker| Value::List(vec![Value::Str(String::from(tracker.clone()))]))
+                .collect();
+            content.extend_from_slice(b"13:announce-list");
+            content.extend(encode_value(&Value::List(tiers)));
+        }
+        content.extend_from_slice(b"4:info");
+        content.extend_from_slice(info_bytes);
+        content.push(b'e');
+        content
+    }
+}
+
+/// Info hashes come either as 40 hex characters or 32 base32 characters.
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], String> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).map_err(|e| format!("Invalid hex info hash: {}", e))?,
+        32 => decode_base32(hash).ok_or("Invalid base32 info hash")?,
+        _ => return Err(format!("Invalid info hash length: {}", hash.len())),
+    };
+
+    bytes
+        .try_into()
+        .map_err(|_| "Info hash is not 20 bytes".to_string())
+}
+
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut buffer = 0u64;
+    let mut bits = 0;
+
+    for c in input.chars() {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(bytes)
+}
@@ -9,37 +9,36 @@
 mod bencoding;
 mod connection;
 mod dht;
+mod magnet;
 mod peer;
+mod session;
+mod tracker;
 mod util;
+mod utp;
+mod web_seed;
 
-use std::{
-    fs::create_dir_all,
-    io::Write,
-    sync::{
-        atomic::{AtomicU64, Ordering::SeqCst},
-        Arc, RwLock,
-    },
-    thread,
-};
+use std::{fs::create_dir_all, io::Write, sync::Arc};
 
 use dotenvy::dotenv;
-use rayon::prelude::*;
-use sha1::{Digest, Sha1};
+use tokio::sync::mpsc;
 
 use crate::{
-    bencoding::{
-        decode,
-        torrent::{Torrent, Tracker},
-    },
-    connection::{Event, HTTPResponse, Peer, ToUrl, TrackerRequest, TrackerResponse},
-    dht::dht_node::DhtClient,
+    bencoding::{decode, torrent::Torrent},
+    magnet::MagnetLink,
     peer::{
-        peer_protocol::{connect_to_peer, PeerProtocolError},
+        extensions::ut_metadata::fetch_metadata,
+        peer_id::ClientIdentity,
+        peer_protocol::LISTEN_PORT,
         types::{PieceProgress, TorrentProgress},
     },
+    session::{Session, TorrentHandle},
+    tracker::get_peers_dht,
+    util::{dual_stack, ip_filter::Blocklist},
+    utp::socket::UtpSocket,
 };
 
-fn main() {
+#[tokio::main]
+async fn main() {
     // bittorrent_lib::run();
     dotenv().ok();
 
@@ -52,171 +51,111 @@ fn main() {
                 .expect("Failed to get parent directory")
                 .to_path_buf()
         });
+    // One peer ID for everything we do this session
+    let identity = ClientIdentity::from_env();
+    let blocklist = Arc::new(Blocklist::from_env());
+
+    // uTP and the DHT share our UDP port
+    let utp = dual_stack::bind_udp(LISTEN_PORT)
+        .or_else(|_| dual_stack::bind_udp(0))
+        .and_then(UtpSocket::from_std)
+        .expect("Failed to bind UDP socket");
+
     let pattern = search_dir.join("*.torrent");
     println!("Searching for .torrent files in: {}", pattern.display());
 
-    let path = glob::glob(pattern.to_str().unwrap())
+    let mut contents: Vec<Vec<u8>> = glob::glob(pattern.to_str().unwrap())
         .expect("Failed to read glob pattern")
-        .next()
-        .expect("No .torrent files found")
-        .expect("Failed to read path");
-    let content = std::fs::read(path).expect("Failed to read file");
-    let torrent = decode::parse_metainfo(&content);
-
-    dbg!(&torrent.trackers);
-
-    let start_time = std::time::Instant::now();
-    let progress: Arc<RwLock<TorrentProgress>> = Arc::new(RwLock::new((&torrent).into()));
-    let completed_pieces = Arc::new(AtomicU64::new(0));
-    let total_pieces = torrent.info.pieces.len() as u64;
-
-    // Find all files in pieces directory
-    let pieces_dir = std::env::var("PIECES_DIR").expect("Env var PIECES_DIR not set");
-    let files: Vec<_> = std::fs::read_dir(&pieces_dir)
-        .unwrap_or_else(|_| {
-            create_dir_all(&pieces_dir).expect("Failed to create pieces directory");
-            std::fs::read_dir(&pieces_dir).expect("Failed to read pieces directory")
-        })
+        .map(|path| std::fs::read(path.expect("Failed to read path")).expect("Failed to read file"))
         .collect();
-
-    let loaded_pieces: Vec<(u32, Vec<u8>)> = files
-        .par_iter()
-        .filter_map(|entry| {
-            let entry = entry.as_ref().expect("Failed to read directory entry");
-            let path = entry.path();
-
-            if !path.is_file() {
-                return None;
-            }
-
-            let piece_index = path.file_stem()?.to_str()?.parse::<u32>().ok()?;
-
-            let data = std::fs::read(&path).expect("Failed to read piece file");
-
-            let expected_length = torrent.get_piece_length(piece_index as usize);
-            if data.len() as u32 != expected_length {
-                println!("Warning: Piece {} incorrect length", piece_index);
-                return None;
-            }
-
-            let expected_hash = torrent.info.pieces[piece_index as usize];
-            let actual_hash: [u8; 20] = Sha1::digest(&data).into();
-            if expected_hash != actual_hash {
-                println!("Warning: Piece {} incorrect hash", piece_index);
-                return None;
-            }
-
-            Some((piece_index, data))
-        })
-        .collect();
-
-    let count = loaded_pieces.len() as u64;
-    let mut prog = progress.write().unwrap();
-    for (piece_index, data) in loaded_pieces {
-        prog.pieces
-            .insert(piece_index, PieceProgress::Completed(data));
+    let mut session = Session::new(identity, Arc::clone(&blocklist), utp);
+    if contents.is_empty() {
+        // Without a .torrent file we can still start from a magnet link
+        let link = std::env::var("MAGNET_LINK")
+            .expect("No .torrent files found and env var MAGNET_LINK not set");
+        let magnet = MagnetLink::try_from(link.as_str()).expect("Failed to parse magnet link");
+        let content = get_torrent_from_magnet(&magnet, identity, &session, &blocklist)
+            .await
+            .expect("Failed to get metadata for magnet link");
+
+        // Keep the metadata around so the next run doesn't need peers for it
+        let torrent_path = search_dir.join(magnet.file_name());
+        if let Err(e) = std::fs::write(&torrent_path, &content) {
+            println!("Failed to save {}: {}", torrent_path.display(), e);
+        }
+        contents.push(content);
     }
-    drop(prog);
-    completed_pieces.fetch_add(count, SeqCst);
-
-    println!(
-        "Found existing {}/{} pieces",
-        completed_pieces.load(SeqCst),
-        torrent.info.pieces.len()
-    );
 
-    let torrent = Arc::new(torrent);
-    let mut threads = vec![];
-
-    // Every second, print progress until all pieces are complete
-    loop {
-        let completed = completed_pieces.load(SeqCst);
-        let percent = (completed as f64 / total_pieces as f64) * 100.0;
-        let connected_peers = progress.read().unwrap().connected_peers.len();
-        println!(
-            "Progress - {}/{} peices ({:.2}%) - Connected Peers: {}",
-            completed, total_pieces, percent, connected_peers
-        );
+    // Only download some of the files, leaving us a partial seed when done
+    let selected_files = selected_files_from_env();
+    for content in contents {
+        let torrent = decode::parse_metainfo(&content);
+        dbg!(&torrent.trackers);
+        session.add_torrent(torrent, selected_files.clone());
+    }
 
-        // Check if all pieces are complete
-        if completed >= total_pieces {
-            break;
+    let (commands_tx, mut commands) = mpsc::unbounded_channel();
+    let interrupt_tx = commands_tx.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let _ = interrupt_tx.send("quit".to_string());
         }
-
-        if connected_peers < 100 {
-            let peers = get_peers_from_torrent(&torrent).expect("Failed to get peers from torrent");
-            let peers = peers
-                .into_iter()
-                .filter(|p| !progress.read().unwrap().connected_peers.contains(p))
-                .collect::<Vec<_>>();
-
-            println!("Added {} new peers", peers.len());
-            for peer in peers {
-                if !progress.read().unwrap().connected_peers.contains(&peer) {
-                    let progress = Arc::clone(&progress);
-                    let torrent = Arc::clone(&torrent);
-                    let completed_pieces = Arc::clone(&completed_pieces);
-                    threads.push(thread::spawn(move || {
-                        progress
-                            .write()
-                            .unwrap()
-                            .connected_peers
-                            .insert(peer.clone());
-                        match connect_to_peer(
-                            &peer,
-                            &torrent,
-                            progress.clone(),
-                            completed_pieces.clone(),
-                        ) {
-                            Ok(_) => {}
-                            Err(err) => match err {
-                                PeerProtocolError::ReceivedError(e) => {
-                                    println!(
-                                        "Receive error with peer {}:{} - {}",
-                                        peer.ip, peer.port, e
-                                    );
-                                }
-                                PeerProtocolError::Unknown(e) => {
-                                    println!(
-                                        "Unknown error with peer {}:{} - {}",
-                                        peer.ip, peer.port, e
-                                    );
-                                }
-                                _ => {}
-                            },
-                        }
-
-                        // Delete peer from list
-                        progress.write().unwrap().connected_peers.remove(&peer);
-                    }));
-                }
+    });
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if commands_tx.send(line).is_err() {
+                break;
             }
         }
+    });
+
+    // Every second, print progress and save each torrent as it completes. We
+    // keep seeding them until told to quit, with `quit` or Ctrl-C
+    'run: loop {
+        while let Ok(line) = commands.try_recv() {
+            if line.trim() == "quit" {
+                break 'run;
+            }
+            session.run_command(&line);
+        }
+        for handle in session.tick().await {
+            save_torrent(handle);
+        }
 
-        std::thread::sleep(std::time::Duration::from_secs(1));
-    }
-
-    // Wait for all threads to finish
-    for thread in threads {
-        thread.join().expect("Failed to join thread");
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
     }
 
-    let end_time = std::time::Instant::now();
+    // Pieces are written in the background, make sure the last ones made it
+    session.flush();
+    session.stop().await;
+}
 
+/// Writes a finished torrent out to the downloads directory.
+fn save_torrent(handle: &TorrentHandle) {
+    let torrent = &handle.torrent;
     println!(
-        "Download complete! Time taken: {:.2?}",
-        end_time.duration_since(start_time)
+        "{} - Download complete! Time taken: {:.2?}",
+        torrent.info.name,
+        handle.started_at.elapsed()
     );
     let donwloads_dir = std::env::var("DOWNLOADS_DIR").unwrap_or_else(|_| "/downloads".to_string());
     create_dir_all(&donwloads_dir).expect("Failed to create downloads directory");
     println!("Saving file to downloads directory: {}", donwloads_dir);
 
+    let progress = handle.progress.read().unwrap();
+    if let Some(files) = &handle.selected_files {
+        save_selected_files(torrent, &progress, &donwloads_dir, files);
+        println!("Files saved successfully!");
+        return;
+    }
+
     // Build file from pieces
     let mut output_file = std::fs::File::create(format!("{}/{}", donwloads_dir, torrent.info.name))
         .expect("Failed to create output file");
     for i in 0..torrent.info.pieces.len() {
-        let progress = progress.read().unwrap();
-
         let piece_data = progress
             .pieces
             .get(&(i as u32))
@@ -232,117 +171,96 @@ fn main() {
     println!("File saved successfully!");
 }
 
-fn get_peers_from_torrent(torrent: &Torrent) -> Result<Vec<Peer>, String> {
-    let http_trackers = torrent
-        .trackers
-        .iter()
-        .filter(|t| matches!(t, Tracker::Http(_)))
-        .map(|t| String::from(t.clone()))
-        .collect::<Vec<_>>();
-    let dht_trackers: Vec<_> = torrent
-        .trackers
-        .iter()
-        .filter_map(|t| {
-            if let Tracker::Dht(addr) = t {
-                Some(addr.clone())
-            } else {
-                None
-            }
-        })
-        .chain([
-            "router.bittorrent.com:6881".to_string(),
-            "dht.transmissionbt.com:6881".to_string(),
-            "router.utorrent.com:6881".to_string(),
-        ])
-        .collect();
-
-    return get_peers_dht(&torrent.info_hash, dht_trackers);
-
-    if http_trackers.is_empty() {
-        return get_peers_dht(&torrent.info_hash, dht_trackers);
-    }
-
-    Ok(http_trackers
-        .into_iter()
-        .flat_map(|tracker| {
-            let response = match get_peers_http(torrent, &tracker) {
-                Ok(res) => res,
-                Err(err) => {
-                    println!("Error getting peers from tracker {}: {}", tracker, err);
-                    return vec![];
-                }
-            };
-
-            println!("Tracker Response: {:?}", response);
-
-            if let Some(err) = response.failure {
-                println!("Tracker failure reason: {:?}", err);
-                return vec![];
-            }
-
-            let response = response.success.expect("No success response from tracker");
-            println!("Interval: {}", response.interval);
-            println!("Leechers: {}", response.incomplete.unwrap_or(0));
-            println!("Seeders: {}", response.complete.unwrap_or(0));
-            println!("Peers: {}", response.peers.len());
-
-            if response.peers.is_empty() {
-                println!("No peers available from tracker");
-                return vec![];
-            }
-
-            response.peers
-        })
-        .collect())
-}
-
-fn get_peers_dht(info_hash: &[u8; 20], trackers: Vec<String>) -> Result<Vec<Peer>, String> {
-    println!("No HTTP trackers found, falling back to DHT");
-    DhtClient::new(trackers).get_peers(info_hash)
+/// Indices of the files to download from `SELECTED_FILES`, comma-separated.
+/// Everything is downloaded when it isn't set.
+fn selected_files_from_env() -> Option<Vec<usize>> {
+    let files = std::env::var("SELECTED_FILES").ok()?;
+    Some(
+        files
+            .split(',')
+            .filter_map(|index| index.trim().parse().ok())
+            .collect(),
+    )
 }
 
-fn get_peers_http(torrent: &Torrent, tracker: &str) -> Result<TrackerResponse, String> {
-    println!("Testing HTTP tracker: {}", tracker);
-
-    let left = if let Some(length) = torrent.info.length {
-        length as u64
-    } else {
-        torrent.info.files.as_ref().unwrap()[0].length as u64
+/// Writes each of `files` under `dir`, laid out as the torrent describes.
+fn save_selected_files(torrent: &Torrent, progress: &TorrentProgress, dir: &str, files: &[usize]) {
+    let piece_length = torrent.info.piece_length;
+    let layout: Vec<(Vec<String>, u64)> = match &torrent.info.files {
+        Some(files) => files
+            .iter()
+            .map(|file| {
+                let mut path = vec![torrent.info.name.clone()];
+                path.extend(file.path.iter().cloned());
+                (path, file.length as u64)
+            })
+            .collect(),
+        None => vec![(vec![torrent.info.name.clone()], torrent.total_length())],
     };
 
-    // send a connect request
-    let connection_request = TrackerRequest {
-        info_hash: torrent.info_hash,
-        peer_id: *b"-TR2940-fuckmek6wWLc",
-        downloaded: 0,
-        left,
-        uploaded: 0,
-        event: Event::Started,
-        ip: None,
-        key: None,
-        num_want: Some(100),
-        port: 6969,
-        compact: 1,
-        no_peer_id: false,
-        tracker_id: None,
-    };
+    let mut file_start = 0;
+    for (file_index, (path, length)) in layout.into_iter().enumerate() {
+        let start = file_start;
+        file_start += length;
+        if !files.contains(&file_index) {
+            continue;
+        }
 
-    let url = format!("{}{}", tracker, connection_request.to_url_params());
-    println!("Request URL: {}", url);
-    let response = reqwest::blocking::get(&url).map_err(|_| "Failed to send request")?;
-    let status = response.status();
-    println!("Response Status: {}", status);
+        let mut data = Vec::with_capacity(length as usize);
+        for index in torrent.file_pieces(file_index) {
+            let Some(PieceProgress::Completed(piece)) = progress.pieces.get(&index) else {
+                println!("Missing piece {} of file {}", index, file_index);
+                return;
+            };
+            let piece_start = index as u64 * piece_length;
+            let from = start.saturating_sub(piece_start) as usize;
+            let to =
+                ((start + length).min(piece_start + piece.len() as u64) - piece_start) as usize;
+            data.extend_from_slice(&piece[from..to]);
+        }
 
-    let bytes = response.bytes().expect("Failed to read bytes");
-    let text = String::from_utf8_lossy(&bytes);
-    println!("Response Body: {:?}", text);
+        let path: std::path::PathBuf = std::iter::once(dir.to_string()).chain(path).collect();
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).expect("Failed to create output directory");
+        }
+        std::fs::write(&path, &data).expect("Failed to write output file");
+    }
+}
 
-    if !status.is_success() {
-        return Err("Failed to get a successful response from the tracker".to_string());
+/// Fetches the info dictionary for a magnet link from the swarm and returns
+/// the contents of an equivalent .torrent file.
+async fn get_torrent_from_magnet(
+    magnet: &MagnetLink,
+    identity: ClientIdentity,
+    session: &Session,
+    blocklist: &Blocklist,
+) -> Result<Vec<u8>, String> {
+    let info_hash = magnet.info_hash;
+    let dht_trackers = vec![
+        "router.bittorrent.com:6881".to_string(),
+        "dht.transmissionbt.com:6881".to_string(),
+        "router.utorrent.com:6881".to_string(),
+    ];
+    let mut peers = magnet.peers.clone();
+    let utp = Arc::clone(session.utp());
+    let dht_peers =
+        tokio::task::spawn_blocking(move || get_peers_dht(&utp, &info_hash, dht_trackers))
+            .await
+            .map_err(|_| "Peer lookup panicked")?;
+    match dht_peers {
+        Ok(dht_peers) => peers.extend(dht_peers),
+        Err(e) => println!("DHT lookup for magnet link failed: {}", e),
     }
 
-    let tracker_response = TrackerResponse::from_http_response(bytes.as_ref());
-    dbg!(&tracker_response);
+    peers.retain(|peer| {
+        let blocked = blocklist.is_blocked(&peer.ip);
+        if blocked {
+            blocklist.record_blocked();
+        }
+        !blocked
+    });
 
-    Ok(tracker_response)
+    println!("Fetching metadata from {} peers", peers.len());
+    let info_bytes = fetch_metadata(info_hash, identity, session.transport(), peers).await?;
+    Ok(magnet.to_torrent_file(&info_bytes))
 }
@@ -0,0 +1,174 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+};
+
+use crate::{
+    peer::types::{PeerMessage, PeerMessageID},
+    util::rate_limiter::{RateLimit, TokenBucket},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+/// Client-wide rate limits, and the settings every peer connection is
+/// throttled with. Rates are in bytes per second, 0 meaning unlimited, and
+/// can all be changed while running.
+pub struct Bandwidth {
+    pub global: RateLimit,
+    peer_download: AtomicU64,
+    peer_upload: AtomicU64,
+    /// Count every byte on the wire rather than just piece data.
+    count_overhead: AtomicBool,
+    /// Leave peers on the local network unthrottled.
+    exempt_local: AtomicBool,
+}
+
+impl Bandwidth {
+    pub fn new(global: RateLimit, peer_download: u64, peer_upload: u64) -> Self {
+        Bandwidth {
+            global,
+            peer_download: AtomicU64::new(peer_download),
+            peer_upload: AtomicU64::new(peer_upload),
+            count_overhead: AtomicBool::new(false),
+            exempt_local: AtomicBool::new(false),
+        }
+    }
+
+    /// Limits are read in KiB/s from `DOWNLOAD_LIMIT`, `UPLOAD_LIMIT`,
+    /// `PEER_DOWNLOAD_LIMIT` and `PEER_UPLOAD_LIMIT`. `RATE_LIMIT_OVERHEAD`
+    /// and `RATE_LIMIT_EXEMPT_LAN` turn on the options of the same name.
+    pub fn from_env() -> Self {
+        let bandwidth = Bandwidth::new(
+            RateLimit::new(
+                limit_from_env("DOWNLOAD_LIMIT"),
+                limit_from_env("UPLOAD_LIMIT"),
+            ),
+            limit_from_env("PEER_DOWNLOAD_LIMIT"),
+            limit_from_env("PEER_UPLOAD_LIMIT"),
+        );
+        bandwidth.set_count_overhead(flag_from_env("RATE_LIMIT_OVERHEAD"));
+        bandwidth.set_exempt_local(flag_from_env("RATE_LIMIT_EXEMPT_LAN"));
+        bandwidth
+    }
+
+    /// Applies to connected peers as well as new ones.
+    pub fn set_peer_limit(&self, direction: Direction, rate: u64) {
+        match direction {
+            Direction::Download => self.peer_download.store(rate, Relaxed),
+            Direction::Upload => self.peer_upload.store(rate, Relaxed),
+        }
+    }
+
+    pub fn set_count_overhead(&self, count_overhead: bool) {
+        self.count_overhead.store(count_overhead, Relaxed);
+    }
+
+    pub fn set_exempt_local(&self, exempt_local: bool) {
+        self.exempt_local.store(exempt_local, Relaxed);
+    }
+}
+
+/// The global, torrent and peer buckets one connection's traffic goes
+/// through.
+pub struct PeerThrottle {
+    bandwidth: Arc<Bandwidth>,
+    torrent: Arc<RateLimit>,
+    peer: RateLimit,
+    is_local: bool,
+}
+
+impl PeerThrottle {
+    pub fn new(bandwidth: Arc<Bandwidth>, torrent: Arc<RateLimit>, ip: &IpAddr) -> Self {
+        let peer = RateLimit::new(
+            bandwidth.peer_download.load(Relaxed),
+            bandwidth.peer_upload.load(Relaxed),
+        );
+        PeerThrottle {
+            bandwidth,
+            torrent,
+            peer,
+            is_local: is_local_address(ip),
+        }
+    }
+
+    /// Waits until `message` is allowed through in `direction`.
+    pub async fn wait(&self, direction: Direction, message: &PeerMessage) {
+        if self.is_local && self.bandwidth.exempt_local.load(Relaxed) {
+            return;
+        }
+
+        let bytes = if self.bandwidth.count_overhead.load(Relaxed) {
+            // Length prefix, ID and payload
+            4 + message.length as usize
+        } else {
+            match message.id {
+                PeerMessageID::Piece => message.payload.len().saturating_sub(8),
+                _ => 0,
+            }
+        };
+        if bytes == 0 {
+            return;
+        }
+
+        let (global, torrent, peer, peer_rate) = match direction {
+            Direction::Download => (
+                &self.bandwidth.global.download,
+                &self.torrent.download,
+                &self.peer.download,
+                &self.bandwidth.peer_download,
+            ),
+            Direction::Upload => (
+                &self.bandwidth.global.upload,
+                &self.torrent.upload,
+                &self.peer.upload,
+                &self.bandwidth.peer_upload,
+            ),
+        };
+        peer.set_rate(peer_rate.load(Relaxed));
+
+        let delay = [global, torrent, peer]
+            .iter()
+            .map(|bucket: &&TokenBucket| bucket.take(bytes))
+            .max()
+            .unwrap_or_default();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Loopback, private and link-local addresses.
+fn is_local_address(ip: &IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+/// Per-torrent limits from `TORRENT_DOWNLOAD_LIMIT` and
+/// `TORRENT_UPLOAD_LIMIT`, in KiB/s.
+pub fn torrent_limit_from_env() -> RateLimit {
+    RateLimit::new(
+        limit_from_env("TORRENT_DOWNLOAD_LIMIT"),
+        limit_from_env("TORRENT_UPLOAD_LIMIT"),
+    )
+}
+
+/// A limit in KiB/s from `name`, as bytes per second.
+fn limit_from_env(name: &str) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .map_or(0, |kib| kib * 1024)
+}
+
+fn flag_from_env(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use rand::seq::IteratorRandom;
+
+use crate::peer::types::PeerStats;
+
+#[cfg(test)]
+mod tests;
+
+/// Peers we upload to at once on merit, besides the optimistic unchoke.
+pub const UPLOAD_SLOTS: usize = 4;
+/// How often the slots are handed out again.
+pub const ROUND_INTERVAL: Duration = Duration::from_secs(10);
+/// How many rounds the optimistic unchoke stays with the same peer.
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+/// Decides which interested peers we upload to. Every round the slots go to
+/// the peers we download from the fastest, or while seeding to the peers
+/// that waited longest for one. One more peer gets an optimistic unchoke, so
+/// newcomers get a chance to show what they've got.
+pub struct Choker {
+    unchoked: HashSet<String>,
+    optimistic: Option<String>,
+    /// When each peer last held a slot, for the round robin while seeding.
+    last_unchoked: HashMap<String, Instant>,
+    next_round: Instant,
+    rounds: u32,
+}
+
+impl Choker {
+    pub fn new(now: Instant) -> Self {
+        Choker {
+            unchoked: HashSet::new(),
+            optimistic: None,
+            last_unchoked: HashMap::new(),
+            next_round: now,
+            rounds: 0,
+        }
+    }
+
+    /// Whether we upload to `peer`, given whether it's interested. Between
+    /// rounds, an interested peer takes a free slot if there is one.
+    pub fn is_unchoked(&mut self, peer: &str, interested: bool) -> bool {
+        if !interested {
+            self.unchoked.remove(peer);
+            return false;
+        }
+        if self.optimistic.as_deref() == Some(peer) || self.unchoked.contains(peer) {
+            return true;
+        }
+        if self.unchoked.len() < UPLOAD_SLOTS {
+            self.unchoked.insert(peer.to_string());
+            return true;
+        }
+        false
+    }
+
+    /// Hands the slots out again once a round is due.
+    pub fn run_round(&mut self, peers: &HashMap<String, PeerStats>, seeding: bool, now: Instant) {
+        if now < self.next_round {
+            return;
+        }
+        self.next_round = now + ROUND_INTERVAL;
+
+        let mut interested: Vec<(&String, &PeerStats)> = peers
+            .iter()
+            .filter(|(_, stats)| stats.is_interested)
+            .collect();
+        if seeding {
+            // Peers that never had a slot first, then the longest waiting
+            interested.sort_by_key(|(peer, _)| self.last_unchoked.get(*peer).copied());
+        } else {
+            interested.sort_by(|(_, a), (_, b)| b.download_rate.total_cmp(&a.download_rate));
+        }
+        self.unchoked = interested
+            .iter()
+            .take(UPLOAD_SLOTS)
+            .map(|(peer, _)| peer.to_string())
+            .collect();
+
+        let keep_optimistic = !self.rounds.is_multiple_of(OPTIMISTIC_ROUNDS)
+            && self.optimistic.as_ref().is_some_and(|peer| {
+                !self.unchoked.contains(peer) && peers.get(peer).is_some_and(|s| s.is_interested)
+            });
+        if !keep_optimistic {
+            self.optimistic = interested
+                .iter()
+                .map(|(peer, _)| *peer)
+                .filter(|peer| !self.unchoked.contains(*peer))
+                .choose(&mut rand::rng())
+                .cloned();
+        }
+        self.rounds += 1;
+
+        for peer in self.unchoked.iter().chain(&self.optimistic) {
+            self.last_unchoked.insert(peer.clone(), now);
+        }
+    }
+
+    /// Frees whatever slot `peer` held once it's gone.
+    pub fn peer_closed(&mut self, peer: &str) {
+        self.unchoked.remove(peer);
+        self.last_unchoked.remove(peer);
+        if self.optimistic.as_deref() == Some(peer) {
+            self.optimistic = None;
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::peer::{
+    choker::{Choker, ROUND_INTERVAL, UPLOAD_SLOTS},
+    types::PeerStats,
+};
+
+fn stats(download_rate: f64, is_interested: bool) -> PeerStats {
+    PeerStats {
+        download_rate,
+        rtt: None,
+        queue_depth: 0,
+        inflight: 0,
+        is_choked: false,
+        is_snubbed: false,
+        is_seed: false,
+        is_incoming: false,
+        is_interested,
+        listen_port: None,
+        client: "unknown".to_string(),
+        capabilities: vec![],
+        hash_failures: 0,
+    }
+}
+
+/// Interested peers `peer0`, `peer1`, ..., each downloading faster than the
+/// one before.
+fn interested_peers(count: usize) -> HashMap<String, PeerStats> {
+    (0..count)
+        .map(|i| (format!("peer{}", i), stats(i as f64 * 1000.0, true)))
+        .collect()
+}
+
+fn unchoked(choker: &mut Choker, peers: &HashMap<String, PeerStats>) -> Vec<String> {
+    let mut unchoked: Vec<String> = peers
+        .keys()
+        .filter(|peer| choker.is_unchoked(peer, true))
+        .cloned()
+        .collect();
+    unchoked.sort();
+    unchoked
+}
+
+#[test]
+fn fastest_peers_get_the_slots_while_downloading() {
+    let peers = interested_peers(8);
+    let mut choker = Choker::new(Instant::now());
+    choker.run_round(&peers, false, Instant::now());
+
+    // The four fastest and an optimistic unchoke among the rest
+    let unchoked = unchoked(&mut choker, &peers);
+    assert_eq!(unchoked.len(), UPLOAD_SLOTS + 1);
+    for peer in ["peer4", "peer5", "peer6", "peer7"] {
+        assert!(unchoked.contains(&peer.to_string()));
+    }
+}
+
+#[test]
+fn uninterested_peers_stay_choked() {
+    let mut peers = interested_peers(2);
+    peers.insert("idle".to_string(), stats(10_000.0, false));
+    let mut choker = Choker::new(Instant::now());
+    choker.run_round(&peers, false, Instant::now());
+
+    assert!(!choker.is_unchoked("idle", false));
+    assert!(choker.is_unchoked("peer0", true));
+    // A free slot goes to whoever asks between rounds
+    assert!(choker.is_unchoked("newcomer", true));
+}
+
+#[test]
+fn slots_rotate_while_seeding() {
+    let peers = interested_peers(2 * UPLOAD_SLOTS + 2);
+    let start = Instant::now();
+    let mut choker = Choker::new(start);
+    choker.run_round(&peers, true, start);
+    let first = unchoked(&mut choker, &peers);
+
+    choker.run_round(&peers, true, start + ROUND_INTERVAL);
+    let second = unchoked(&mut choker, &peers);
+    let repeated = second.iter().filter(|peer| first.contains(peer)).count();
+    // Only the optimistic unchoke may land on a peer from the last round
+    assert!(repeated <= 1, "{:?} then {:?}", first, second);
+}
+
+#[test]
+fn rounds_wait_for_the_interval() {
+    let start = Instant::now();
+    let mut choker = Choker::new(start);
+    choker.run_round(&interested_peers(UPLOAD_SLOTS), false, start);
+
+    let faster = (0..UPLOAD_SLOTS)
+        .map(|i| (format!("fast{}", i), stats(1e6, true)))
+        .collect();
+    choker.run_round(&faster, false, start + Duration::from_secs(1));
+    assert!(choker.is_unchoked("peer0", true));
+    assert!(!choker.is_unchoked("fast0", true));
+}
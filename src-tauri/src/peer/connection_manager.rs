@@ -0,0 +1,133 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{connection::Peer, util::ip_filter::Blocklist};
+
+/// Wait before redialing a peer after the first failure, doubling with each
+/// failure after that...
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+/// ...up to this.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// How long before we call a peer back after a session with it ended.
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Score lost per failed connection attempt, in KiB/s of throughput.
+const FAILURE_PENALTY: f64 = 20.0;
+/// Score lost per piece the peer sent that failed its hash check.
+const HASH_FAILURE_PENALTY: f64 = 100.0;
+
+/// What we remember about a peer address, connected or not.
+#[derive(Default)]
+struct PeerRecord {
+    /// Failed attempts since the last one that worked.
+    failures: u32,
+    hash_failures: u32,
+    /// Download rate the last time we were connected, in bytes per second.
+    download_rate: f64,
+    retry_at: Option<Instant>,
+}
+
+impl PeerRecord {
+    fn score(&self) -> f64 {
+        self.download_rate / 1024.0
+            - FAILURE_PENALTY * self.failures as f64
+            - HASH_FAILURE_PENALTY * self.hash_failures as f64
+    }
+}
+
+/// Decides which peers to dial: caps the total number of connections, and
+/// ranks the peers we know of by how they did before, holding back the ones
+/// that failed until their backoff runs out. Nothing on the blocklist gets
+/// through, in either direction.
+pub struct ConnectionManager {
+    max_connections: usize,
+    blocklist: Arc<Blocklist>,
+    connected: HashSet<Peer>,
+    peers: HashMap<Peer, PeerRecord>,
+}
+
+impl ConnectionManager {
+    pub fn new(max_connections: usize, blocklist: Arc<Blocklist>) -> Self {
+        ConnectionManager {
+            max_connections,
+            blocklist,
+            connected: HashSet::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Remembers a peer from a tracker, the DHT or PEX as one we could dial.
+    pub fn add_candidate(&mut self, peer: Peer) {
+        // Blocked peers are kept too, so each is only counted once. Whether
+        // they get dialed is decided against the current blocklist.
+        if !self.peers.contains_key(&peer) && self.blocklist.is_blocked(&peer.ip) {
+            self.blocklist.record_blocked();
+        }
+        self.peers.entry(peer).or_default();
+    }
+
+    /// Connections we can still open before hitting the cap.
+    pub fn free_slots(&self) -> usize {
+        self.max_connections.saturating_sub(self.connected.len())
+    }
+
+    /// Counts a new connection against the cap. False if there's no room,
+    /// `peer` is blocked or we are already connected to it.
+    pub fn opened(&mut self, peer: &Peer) -> bool {
+        if self.blocklist.is_blocked(&peer.ip) {
+            self.blocklist.record_blocked();
+            return false;
+        }
+        self.free_slots() > 0 && self.connected.insert(peer.clone())
+    }
+
+    /// A connection ended. `succeeded` says whether we got as far as a
+    /// handshake, failures back the peer off exponentially.
+    pub fn closed(&mut self, peer: &Peer, succeeded: bool, now: Instant) {
+        self.connected.remove(peer);
+        let Some(record) = self.peers.get_mut(peer) else {
+            return;
+        };
+
+        if succeeded {
+            record.failures = 0;
+            record.retry_at = Some(now + RECONNECT_DELAY);
+        } else {
+            record.failures += 1;
+            let backoff = INITIAL_BACKOFF
+                .saturating_mul(1 << (record.failures - 1).min(16))
+                .min(MAX_BACKOFF);
+            record.retry_at = Some(now + backoff);
+        }
+    }
+
+    /// Keeps a connected peer's latest throughput and hash failures for
+    /// scoring.
+    pub fn record_stats(&mut self, peer: &Peer, download_rate: f64, hash_failures: u32) {
+        if let Some(record) = self.peers.get_mut(peer) {
+            record.download_rate = download_rate;
+            record.hash_failures = hash_failures;
+        }
+    }
+
+    /// Up to `limit` peers worth dialing now, best scoring first. Peers in
+    /// `exclude` are skipped.
+    pub fn candidates(&self, limit: usize, exclude: &HashSet<Peer>, now: Instant) -> Vec<Peer> {
+        let mut candidates: Vec<(&Peer, &PeerRecord)> = self
+            .peers
+            .iter()
+            .filter(|(peer, _)| !self.connected.contains(*peer) && !exclude.contains(*peer))
+            .filter(|(peer, _)| !self.blocklist.is_blocked(&peer.ip))
+            .filter(|(_, record)| record.retry_at.is_none_or(|retry_at| retry_at <= now))
+            .collect();
+        candidates.sort_by(|(_, a), (_, b)| b.score().total_cmp(&a.score()));
+
+        candidates
+            .into_iter()
+            .take(limit)
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+}
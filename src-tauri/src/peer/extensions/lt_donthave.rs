@@ -0,0 +1,48 @@
+use crate::peer::extensions::{ExtensionContext, ExtensionHandler};
+
+pub const LT_DONTHAVE: &str = "lt_donthave";
+
+/// Lets peers take back pieces they announced (BEP 54). Our own retractions
+/// are sent by the session as they happen.
+pub struct LtDonthave;
+
+impl ExtensionHandler for LtDonthave {
+    fn name(&self) -> &'static str {
+        LT_DONTHAVE
+    }
+
+    fn on_message(&mut self, ctx: &mut ExtensionContext, payload: &[u8]) {
+        let Some(index) = payload.get(..4) else {
+            println!("{} - Invalid lt_donthave message", ctx.peer_state.peer);
+            return;
+        };
+        let index = u32::from_be_bytes(index.try_into().unwrap());
+        let peer_state = &mut *ctx.peer_state;
+        let Some(byte) = peer_state.bitfield.get_mut(index as usize / 8) else {
+            return;
+        };
+        *byte &= !(1 << (7 - index % 8));
+
+        // Nothing more of the piece is coming from this peer
+        peer_state.requested_pieces.retain(|&i| i != index);
+        peer_state.suggested_pieces.retain(|&i| i != index);
+        peer_state.allowed_fast.remove(&index);
+        let begins: Vec<u32> = peer_state
+            .pending_requests
+            .keys()
+            .filter(|(i, _)| *i == index)
+            .map(|&(_, begin)| begin)
+            .collect();
+
+        let mut progress = ctx.progress.write().unwrap();
+        for begin in begins {
+            peer_state.pending_requests.remove(&(index, begin));
+            progress.release_block(&peer_state.peer, index, begin);
+        }
+        if peer_state.revealed_pieces.is_some() {
+            if let Some(super_seed) = &mut progress.super_seed {
+                super_seed.peer_lost(index);
+            }
+        }
+    }
+}
@@ -0,0 +1,318 @@
+pub mod lt_donthave;
+pub mod upload_only;
+pub mod ut_holepunch;
+pub mod ut_metadata;
+pub mod ut_pex;
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    bencoding::{
+        decode::{decode_dictionary, Value},
+        encode::encode_dictionary,
+        torrent::Torrent,
+    },
+    peer::types::{PeerMessage, PeerMessageID, PeerState, TorrentProgress},
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Reserved byte and bit that advertise the extension protocol (BEP 10).
+pub const EXTENSION_PROTOCOL_BYTE: usize = 5;
+pub const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
+/// Extended message id reserved for the extension handshake itself.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// Number of outstanding requests we tell peers they may queue with us.
+const OUR_REQQ: i64 = 250;
+
+/// A BEP 10 extension. Each connection gets its own set of handlers, so
+/// handlers can keep per-peer state.
+pub trait ExtensionHandler: Send {
+    /// Name the extension is registered under in the `m` dictionary.
+    fn name(&self) -> &'static str;
+
+    /// Adds extension specific keys to our extension handshake.
+    fn handshake_fields(&self, _fields: &mut HashMap<String, Value>) {}
+
+    /// Called whenever the peer sends an extension handshake.
+    fn on_handshake(&mut self, _ctx: &mut ExtensionContext, _handshake: &ExtendedHandshake) {}
+
+    /// Called for every message the peer sends for this extension.
+    fn on_message(&mut self, ctx: &mut ExtensionContext, payload: &[u8]);
+
+    /// Called on the session's regular tick.
+    fn on_tick(&mut self, _ctx: &mut ExtensionContext) {}
+}
+
+/// What an extension handler gets to work with while handling an event.
+pub struct ExtensionContext<'a> {
+    pub peer_state: &'a mut PeerState,
+    pub torrent: &'a Torrent,
+    pub progress: &'a Arc<RwLock<TorrentProgress>>,
+    peer_ids: &'a HashMap<String, u8>,
+    outgoing: &'a mut Vec<PeerMessage>,
+}
+
+impl<'a> ExtensionContext<'a> {
+    pub fn new(
+        peer_state: &'a mut PeerState,
+        torrent: &'a Torrent,
+        progress: &'a Arc<RwLock<TorrentProgress>>,
+        peer_ids: &'a HashMap<String, u8>,
+        outgoing: &'a mut Vec<PeerMessage>,
+    ) -> Self {
+        ExtensionContext {
+            peer_state,
+            torrent,
+            progress,
+            peer_ids,
+            outgoing,
+        }
+    }
+
+    /// Whether the peer advertised the named extension.
+    pub fn peer_supports(&self, name: &str) -> bool {
+        self.peer_ids.contains_key(name)
+    }
+
+    /// Queues an extended message for the named extension, using the id the
+    /// peer picked for it. Returns false if the peer doesn't support it.
+    pub fn send(&mut self, name: &str, payload: Vec<u8>) -> bool {
+        match self.peer_ids.get(name) {
+            Some(&id) => {
+                self.outgoing.push(create_extended(id, payload));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Queues a message outside the extension protocol.
+    pub fn send_message(&mut self, message: PeerMessage) {
+        self.outgoing.push(message);
+    }
+}
+
+/// The parts of a peer's extension handshake we care about. Extension
+/// specific keys are left in `fields` for the handlers.
+#[derive(Debug, Default)]
+pub struct ExtendedHandshake {
+    pub extensions: HashMap<String, u8>,
+    pub version: Option<String>,
+    pub port: Option<u16>,
+    pub reqq: Option<u32>,
+    pub fields: HashMap<String, Value>,
+}
+
+impl TryFrom<&[u8]> for ExtendedHandshake {
+    type Error = String;
+
+    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+        let mut fields = match decode_dictionary(payload, &mut 0)? {
+            Value::Dict(d) => d,
+            _ => return Err("Extension handshake is not a dictionary".to_string()),
+        };
+
+        let extensions = match fields.remove("m") {
+            Some(Value::Dict(m)) => m
+                .into_iter()
+                .filter_map(|(name, id)| match id {
+                    Value::Number(id) if (0..=255).contains(&id) => Some((name, id as u8)),
+                    _ => None,
+                })
+                .collect(),
+            _ => HashMap::new(),
+        };
+
+        let version = match fields.remove("v") {
+            Some(Value::Str(v)) => Some(v),
+            _ => None,
+        };
+
+        let port = match fields.remove("p") {
+            Some(Value::Number(p)) => u16::try_from(p).ok(),
+            _ => None,
+        };
+
+        let reqq = match fields.remove("reqq") {
+            Some(Value::Number(reqq)) => Some(reqq.clamp(0, u32::MAX as i64) as u32),
+            _ => None,
+        };
+
+        Ok(ExtendedHandshake {
+            extensions,
+            version,
+            port,
+            reqq,
+            fields,
+        })
+    }
+}
+
+/// Per-connection extension state: our handlers, which local id each one was
+/// given, and the ids the peer picked for the extensions it supports.
+pub struct ExtensionRegistry {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    peer_ids: HashMap<String, u8>,
+    pub peer_handshake: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new(handlers: Vec<Box<dyn ExtensionHandler>>) -> Self {
+        ExtensionRegistry {
+            handlers,
+            peer_ids: HashMap::new(),
+            peer_handshake: None,
+        }
+    }
+
+    /// Our local id for a handler is its position in the list plus one, as 0
+    /// is the handshake.
+    fn local_id(index: usize) -> u8 {
+        (index + 1) as u8
+    }
+
+    /// Our extension handshake. `client_version` goes out as `v` unless
+    /// we're anonymous.
+    pub fn create_handshake(
+        &self,
+        listen_port: u16,
+        peer_ip: &IpAddr,
+        client_version: Option<&str>,
+    ) -> PeerMessage {
+        let m = self
+            .handlers
+            .iter()
+            .enumerate()
+            .map(|(i, handler)| {
+                (
+                    handler.name().to_string(),
+                    Value::Number(Self::local_id(i) as i64),
+                )
+            })
+            .collect();
+
+        let mut handshake = HashMap::new();
+        for handler in &self.handlers {
+            handler.handshake_fields(&mut handshake);
+        }
+        handshake.insert("m".to_string(), Value::Dict(m));
+        if let Some(client_version) = client_version {
+            handshake.insert("v".to_string(), Value::Str(client_version.to_string()));
+        }
+        handshake.insert("p".to_string(), Value::Number(listen_port as i64));
+        handshake.insert("reqq".to_string(), Value::Number(OUR_REQQ));
+        let your_ip = match peer_ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        handshake.insert("yourip".to_string(), Value::Bytes(your_ip));
+
+        create_extended(EXTENDED_HANDSHAKE_ID, encode_dictionary(&handshake))
+    }
+
+    /// Dispatches an `Extended` message to the handshake logic or to the
+    /// handler registered under the id it was sent with.
+    pub fn handle_message(
+        &mut self,
+        payload: &[u8],
+        peer_state: &mut PeerState,
+        torrent: &Torrent,
+        progress: &Arc<RwLock<TorrentProgress>>,
+    ) -> Vec<PeerMessage> {
+        let mut outgoing = vec![];
+        let Some((&extension_id, payload)) = payload.split_first() else {
+            return outgoing;
+        };
+
+        if extension_id == EXTENDED_HANDSHAKE_ID {
+            let handshake = match ExtendedHandshake::try_from(payload) {
+                Ok(handshake) => handshake,
+                Err(e) => {
+                    println!("{} - Invalid extension handshake: {}", peer_state.peer, e);
+                    return outgoing;
+                }
+            };
+
+            // Later handshakes only update what they mention, and an id of 0
+            // switches an extension off
+            for (name, id) in &handshake.extensions {
+                if *id == 0 {
+                    self.peer_ids.remove(name);
+                } else {
+                    self.peer_ids.insert(name.clone(), *id);
+                }
+            }
+            if let Some(reqq) = handshake.reqq {
+                peer_state.peer_reqq = Some(reqq);
+            }
+            if let Some(port) = handshake.port {
+                peer_state.listen_port = Some(port);
+            }
+            // More telling than what the peer id gives away
+            if let Some(version) = &handshake.version {
+                peer_state.client = version.clone();
+            }
+
+            let mut ctx =
+                ExtensionContext::new(peer_state, torrent, progress, &self.peer_ids, &mut outgoing);
+            for handler in &mut self.handlers {
+                handler.on_handshake(&mut ctx, &handshake);
+            }
+            self.peer_handshake = Some(handshake);
+            return outgoing;
+        }
+
+        let Some(handler) = self
+            .handlers
+            .get_mut((extension_id as usize).wrapping_sub(1))
+        else {
+            // println!("{} - Unknown extension id {}", peer_state.peer, extension_id);
+            return outgoing;
+        };
+
+        let mut ctx =
+            ExtensionContext::new(peer_state, torrent, progress, &self.peer_ids, &mut outgoing);
+        handler.on_message(&mut ctx, payload);
+        outgoing
+    }
+
+    /// A message for the named extension, if the peer supports it.
+    pub fn create_message(&self, name: &str, payload: Vec<u8>) -> Option<PeerMessage> {
+        let &id = self.peer_ids.get(name)?;
+        Some(create_extended(id, payload))
+    }
+
+    pub fn tick(
+        &mut self,
+        peer_state: &mut PeerState,
+        torrent: &Torrent,
+        progress: &Arc<RwLock<TorrentProgress>>,
+    ) -> Vec<PeerMessage> {
+        let mut outgoing = vec![];
+        let mut ctx =
+            ExtensionContext::new(peer_state, torrent, progress, &self.peer_ids, &mut outgoing);
+        for handler in &mut self.handlers {
+            handler.on_tick(&mut ctx);
+        }
+        outgoing
+    }
+}
+
+pub fn create_extended(extension_id: u8, payload: Vec<u8>) -> PeerMessage {
+    let mut buf = Vec::with_capacity(1 + payload.len());
+    buf.push(extension_id);
+    buf.extend_from_slice(&payload);
+    PeerMessage {
+        id: PeerMessageID::Extended,
+        length: (1 + buf.len()) as u32,
+        payload: buf,
+    }
+}
@@ -0,0 +1,40 @@
+use crate::peer::extensions::ExtendedHandshake;
+
+#[test]
+fn parses_handshake() {
+    let handshake =
+        ExtendedHandshake::try_from(&b"d1:md6:ut_pexi1ee1:pi6881e4:reqqi500e1:v4:teste"[..])
+            .unwrap();
+    assert_eq!(handshake.extensions.get("ut_pex"), Some(&1));
+    assert_eq!(handshake.port, Some(6881));
+    assert_eq!(handshake.reqq, Some(500));
+    assert_eq!(handshake.version.as_deref(), Some("test"));
+}
+
+#[test]
+fn truncated_handshake_is_an_error() {
+    for payload in [&b""[..], b"d", b"d1:m", b"d1:md6:ut_pexi1", b"d1:p5:ab"] {
+        assert!(
+            ExtendedHandshake::try_from(payload).is_err(),
+            "{:?}",
+            String::from_utf8_lossy(payload)
+        );
+    }
+}
+
+#[test]
+fn oversized_lengths_are_an_error() {
+    for payload in [
+        &b"d1:v99999:teste"[..],
+        b"d18446744073709551615:ve",
+        b"d99999999999999999999999:ve",
+        b"d1:pi99999999999999999999999ee",
+        b"d-5:abcdee",
+    ] {
+        assert!(
+            ExtendedHandshake::try_from(payload).is_err(),
+            "{:?}",
+            String::from_utf8_lossy(payload)
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+use crate::{
+    bencoding::decode::Value,
+    peer::extensions::{ExtendedHandshake, ExtensionContext, ExtensionHandler},
+};
+
+pub const UPLOAD_ONLY: &str = "upload_only";
+
+/// Tells the peer when we stop downloading, as a seed or a partial seed
+/// (BEP 21), so two peers that only upload don't keep a useless connection.
+pub struct UploadOnly {
+    /// What the peer last heard from us.
+    upload_only: bool,
+    /// Bytes we had when the connection started.
+    downloaded: u64,
+}
+
+impl UploadOnly {
+    pub fn new(upload_only: bool, downloaded: u64) -> Self {
+        UploadOnly {
+            upload_only,
+            downloaded,
+        }
+    }
+}
+
+impl ExtensionHandler for UploadOnly {
+    fn name(&self) -> &'static str {
+        UPLOAD_ONLY
+    }
+
+    fn handshake_fields(&self, fields: &mut HashMap<String, Value>) {
+        fields.insert(
+            UPLOAD_ONLY.to_string(),
+            Value::Number(self.upload_only as i64),
+        );
+        fields.insert(
+            "downloaded".to_string(),
+            Value::Number(self.downloaded as i64),
+        );
+    }
+
+    fn on_handshake(&mut self, ctx: &mut ExtensionContext, handshake: &ExtendedHandshake) {
+        if let Some(Value::Number(upload_only)) = handshake.fields.get(UPLOAD_ONLY) {
+            ctx.peer_state.upload_only = *upload_only != 0;
+        }
+    }
+
+    fn on_message(&mut self, ctx: &mut ExtensionContext, payload: &[u8]) {
+        if let Some(&upload_only) = payload.first() {
+            ctx.peer_state.upload_only = upload_only != 0;
+        }
+    }
+
+    fn on_tick(&mut self, ctx: &mut ExtensionContext) {
+        let upload_only = ctx.progress.read().unwrap().is_upload_only();
+        if upload_only != self.upload_only && ctx.send(UPLOAD_ONLY, vec![upload_only as u8]) {
+            self.upload_only = upload_only;
+        }
+    }
+}
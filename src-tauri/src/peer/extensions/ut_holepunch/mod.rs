@@ -0,0 +1,331 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    connection::Peer,
+    peer::extensions::{ExtensionContext, ExtensionHandler},
+};
+
+#[cfg(test)]
+mod tests;
+
+pub const UT_HOLEPUNCH: &str = "ut_holepunch";
+
+/// Messages a session can have waiting for its peer from other sessions.
+pub const HOLEPUNCH_QUEUE_SIZE: usize = 16;
+/// Most connect messages we act on between two runs of the main loop.
+const MAX_PENDING_CONNECTS: usize = 32;
+/// Stop remembering where PEX peers came from past this many.
+const MAX_SOURCES: usize = 500;
+
+/// Why a relay couldn't introduce us to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchError {
+    /// The address isn't one anybody can be at.
+    NoSuchPeer = 1,
+    /// The relay isn't connected to the peer.
+    NotConnected = 2,
+    /// The peer doesn't speak ut_holepunch.
+    NoSupport = 3,
+    /// We asked to be introduced to ourselves.
+    NoSelf = 4,
+}
+
+impl TryFrom<u32> for HolepunchError {
+    type Error = String;
+
+    fn try_from(code: u32) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(HolepunchError::NoSuchPeer),
+            2 => Ok(HolepunchError::NotConnected),
+            3 => Ok(HolepunchError::NoSupport),
+            4 => Ok(HolepunchError::NoSelf),
+            _ => Err(format!("Unknown ut_holepunch error code {}", code)),
+        }
+    }
+}
+
+impl fmt::Display for HolepunchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            HolepunchError::NoSuchPeer => "no such peer",
+            HolepunchError::NotConnected => "relay not connected to the peer",
+            HolepunchError::NoSupport => "peer doesn't support holepunching",
+            HolepunchError::NoSelf => "can't holepunch to ourselves",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// A ut_holepunch message (BEP 55). Each one names the peer it is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HolepunchMessage {
+    /// Asks the relay to introduce us to the peer.
+    Rendezvous(Peer),
+    /// Tells us to connect to the peer, who is being told the same.
+    Connect(Peer),
+    Error(Peer, HolepunchError),
+}
+
+impl HolepunchMessage {
+    pub fn peer(&self) -> &Peer {
+        match self {
+            HolepunchMessage::Rendezvous(peer)
+            | HolepunchMessage::Connect(peer)
+            | HolepunchMessage::Error(peer, _) => peer,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for HolepunchMessage {
+    type Error = String;
+
+    fn try_from(payload: &[u8]) -> Result<Self, String> {
+        let [msg_type, addr_type, rest @ ..] = payload else {
+            return Err("ut_holepunch message too short".to_string());
+        };
+        let (ip, rest) = match addr_type {
+            0 if rest.len() >= 4 => (
+                IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&rest[..4]).unwrap())),
+                &rest[4..],
+            ),
+            1 if rest.len() >= 16 => (
+                IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&rest[..16]).unwrap())),
+                &rest[16..],
+            ),
+            0 | 1 => return Err("ut_holepunch message too short".to_string()),
+            _ => return Err(format!("Unknown ut_holepunch address type {}", addr_type)),
+        };
+        let [p0, p1, e0, e1, e2, e3, ..] = *rest else {
+            return Err("ut_holepunch message too short".to_string());
+        };
+        let peer = Peer::from(SocketAddr::new(ip, u16::from_be_bytes([p0, p1])));
+
+        match msg_type {
+            0 => Ok(HolepunchMessage::Rendezvous(peer)),
+            1 => Ok(HolepunchMessage::Connect(peer)),
+            2 => {
+                let error = HolepunchError::try_from(u32::from_be_bytes([e0, e1, e2, e3]))?;
+                Ok(HolepunchMessage::Error(peer, error))
+            }
+            _ => Err(format!("Unknown ut_holepunch message type {}", msg_type)),
+        }
+    }
+}
+
+impl From<&HolepunchMessage> for Vec<u8> {
+    fn from(message: &HolepunchMessage) -> Self {
+        let (msg_type, error) = match message {
+            HolepunchMessage::Rendezvous(_) => (0, 0),
+            HolepunchMessage::Connect(_) => (1, 0),
+            HolepunchMessage::Error(_, error) => (2, *error as u32),
+        };
+        let peer = message.peer();
+        let mut buf = vec![msg_type];
+        match peer.ip {
+            IpAddr::V4(ip) => {
+                buf.push(0);
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.push(1);
+                buf.extend_from_slice(&ip.octets());
+            }
+        }
+        buf.extend_from_slice(&peer.port.to_be_bytes());
+        buf.extend_from_slice(&error.to_be_bytes());
+        buf
+    }
+}
+
+/// What sessions share to relay for each other and to follow up on
+/// introductions. Connect messages wait in `dial` and `punch` for the main
+/// loop, which owns the sockets.
+#[derive(Default)]
+pub struct Holepunch {
+    /// Sessions whose peer speaks ut_holepunch, by peer address.
+    relays: HashMap<String, mpsc::Sender<HolepunchMessage>>,
+    /// The peer that told us about each candidate over PEX.
+    sources: HashMap<Peer, String>,
+    /// Peers we asked a relay to introduce us to.
+    requested: HashSet<Peer>,
+    /// Peers we asked for and should now connect to over uTP.
+    dial: Vec<Peer>,
+    /// Peers that asked for us. They do the connecting, we only open our NAT.
+    punch: Vec<Peer>,
+}
+
+impl Holepunch {
+    /// Makes `peer`'s session available as a relay through `sender`.
+    pub fn add_relay(&mut self, peer: &str, sender: mpsc::Sender<HolepunchMessage>) {
+        self.relays.insert(peer.to_string(), sender);
+    }
+
+    /// Whether we can ask `peer` for introductions.
+    pub fn is_relay(&self, peer: &str) -> bool {
+        self.relays.contains_key(peer)
+    }
+
+    /// The session with `peer` ended.
+    pub fn peer_closed(&mut self, peer: &str) {
+        self.relays.remove(peer);
+        self.sources.retain(|_, source| source != peer);
+    }
+
+    /// Remembers that `source` told us about `candidate`, so we can ask it
+    /// for an introduction if we can't reach the candidate directly.
+    pub fn learned_from(&mut self, candidate: Peer, source: &str) {
+        if self.sources.len() < MAX_SOURCES {
+            self.sources.insert(candidate, source.to_string());
+        }
+    }
+
+    /// Asks the peer that told us about `target` to introduce us. Each
+    /// target is only tried once. False if there's nobody to ask.
+    pub fn request(&mut self, target: &Peer) -> bool {
+        let Some(source) = self.sources.remove(target) else {
+            return false;
+        };
+        let Some(relay) = self.relays.get(&source) else {
+            return false;
+        };
+        if relay
+            .try_send(HolepunchMessage::Rendezvous(target.clone()))
+            .is_err()
+        {
+            return false;
+        }
+        self.requested.insert(target.clone());
+        true
+    }
+
+    /// Passes a rendezvous from `from` on to `target`'s session. `connected`
+    /// says whether we have a session with `target` at all.
+    pub fn relay(&self, from: &Peer, target: &Peer, connected: bool) -> Result<(), HolepunchError> {
+        if target.port == 0 || target.ip.is_unspecified() {
+            return Err(HolepunchError::NoSuchPeer);
+        }
+        if target == from {
+            return Err(HolepunchError::NoSelf);
+        }
+        match self.relays.get(&target.to_string()) {
+            Some(relay) => relay
+                .try_send(HolepunchMessage::Connect(from.clone()))
+                .map_err(|_| HolepunchError::NotConnected),
+            None if connected => Err(HolepunchError::NoSupport),
+            None => Err(HolepunchError::NotConnected),
+        }
+    }
+
+    /// A relay told us to connect to `peer`.
+    pub fn connect_received(&mut self, peer: Peer) {
+        if self.dial.len() + self.punch.len() >= MAX_PENDING_CONNECTS {
+            return;
+        }
+        if self.requested.remove(&peer) {
+            self.dial.push(peer);
+        } else {
+            self.punch.push(peer);
+        }
+    }
+
+    /// A relay couldn't introduce us to `peer`.
+    pub fn error_received(&mut self, peer: &Peer) {
+        self.requested.remove(peer);
+    }
+
+    /// The peers to connect to, and the peers to open our NAT to, since the
+    /// last call.
+    pub fn take_connects(&mut self) -> (Vec<Peer>, Vec<Peer>) {
+        (
+            std::mem::take(&mut self.dial),
+            std::mem::take(&mut self.punch),
+        )
+    }
+}
+
+/// NAT traversal (BEP 55). Relays introductions between the peers we're
+/// connected to, and hands the introductions we get to the main loop.
+/// Outgoing rendezvous and relayed connects come in through the session's
+/// holepunch queue.
+pub struct UtHolepunch {
+    sender: mpsc::Sender<HolepunchMessage>,
+}
+
+impl UtHolepunch {
+    /// `sender` feeds this session's holepunch queue.
+    pub fn new(sender: mpsc::Sender<HolepunchMessage>) -> Self {
+        UtHolepunch { sender }
+    }
+}
+
+impl ExtensionHandler for UtHolepunch {
+    fn name(&self) -> &'static str {
+        UT_HOLEPUNCH
+    }
+
+    fn on_handshake(&mut self, ctx: &mut ExtensionContext, _handshake: &super::ExtendedHandshake) {
+        let mut progress = ctx.progress.write().unwrap();
+        if ctx.peer_supports(UT_HOLEPUNCH) {
+            progress
+                .holepunch
+                .add_relay(&ctx.peer_state.peer, self.sender.clone());
+        } else {
+            progress.holepunch.relays.remove(&ctx.peer_state.peer);
+        }
+    }
+
+    fn on_message(&mut self, ctx: &mut ExtensionContext, payload: &[u8]) {
+        let message = match HolepunchMessage::try_from(payload) {
+            Ok(message) => message,
+            Err(e) => {
+                println!(
+                    "{} - Invalid ut_holepunch message: {}",
+                    ctx.peer_state.peer, e
+                );
+                return;
+            }
+        };
+
+        match message {
+            HolepunchMessage::Rendezvous(target) => {
+                let from = Peer::from(ctx.peer_state.peer.clone());
+                let result = {
+                    let progress = ctx.progress.read().unwrap();
+                    let target_key = target.to_string();
+                    let connected = progress.peer_ids.values().any(|peer| *peer == target_key);
+                    progress.holepunch.relay(&from, &target, connected)
+                };
+                let reply = match result {
+                    Ok(()) => HolepunchMessage::Connect(target),
+                    Err(error) => HolepunchMessage::Error(target, error),
+                };
+                ctx.send(UT_HOLEPUNCH, Vec::from(&reply));
+            }
+            HolepunchMessage::Connect(peer) => {
+                let mut progress = ctx.progress.write().unwrap();
+                if !progress.connected_peers.contains(&peer) {
+                    progress.holepunch.connect_received(peer);
+                }
+            }
+            HolepunchMessage::Error(peer, error) => {
+                println!(
+                    "{} - Holepunch to {} failed: {}",
+                    ctx.peer_state.peer,
+                    peer.to_string(),
+                    error
+                );
+                ctx.progress
+                    .write()
+                    .unwrap()
+                    .holepunch
+                    .error_received(&peer);
+            }
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{atomic::AtomicU64, Arc, RwLock},
+    time::Duration,
+};
+
+use tokio::{sync::mpsc, time::timeout};
+
+use crate::{
+    bencoding::torrent::Torrent,
+    connection::Peer,
+    peer::{
+        extensions::ut_holepunch::{Holepunch, HolepunchError, HolepunchMessage},
+        mse::EncryptionPolicy,
+        peer_protocol::{accept_peer, connect_to_peer, holepunch_peer},
+        transport::Transport,
+        types::TorrentProgress,
+    },
+    utp::socket::UtpSocket,
+};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn make_torrent() -> Torrent {
+    Torrent::for_test("holepunch.bin", 16 * 1024, &[0; 64 * 1024], None)
+}
+
+/// One client on loopback: a uTP socket, and just enough of the main loop
+/// to accept connections and act on the introductions it gets.
+struct Node {
+    addr: SocketAddr,
+    torrent: Arc<Torrent>,
+    transport: Transport,
+    progress: Arc<RwLock<TorrentProgress>>,
+    completed_pieces: Arc<AtomicU64>,
+}
+
+impl Node {
+    fn start(torrent: &Arc<Torrent>) -> Arc<Node> {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let utp = UtpSocket::from_std(socket).unwrap();
+        let node = Arc::new(Node {
+            addr: utp.local_addr().unwrap(),
+            torrent: Arc::clone(torrent),
+            transport: Transport::new(EncryptionPolicy::PlaintextFallback, Some(utp.clone()), 8),
+            progress: Arc::new(RwLock::new(TorrentProgress::for_test(torrent))),
+            completed_pieces: Arc::new(AtomicU64::new(0)),
+        });
+
+        let accepting = Arc::clone(&node);
+        tokio::spawn(async move {
+            while let Some(stream) = utp.accept().await {
+                let node = Arc::clone(&accepting);
+                tokio::spawn(async move {
+                    let peer = Peer::from(stream.peer_addr());
+                    let Ok((stream, _)) = node
+                        .transport
+                        .accept(Box::new(stream), &[node.torrent.info_hash])
+                        .await
+                    else {
+                        return;
+                    };
+                    let _ = accept_peer(
+                        stream,
+                        &peer,
+                        &node.torrent,
+                        Arc::clone(&node.progress),
+                        Arc::clone(&node.completed_pieces),
+                    )
+                    .await;
+                });
+            }
+        });
+
+        let introduced = Arc::clone(&node);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                let (to_dial, to_punch) = introduced
+                    .progress
+                    .write()
+                    .unwrap()
+                    .holepunch
+                    .take_connects();
+                for peer in &to_punch {
+                    introduced.transport.punch(peer);
+                }
+                for peer in to_dial {
+                    let node = Arc::clone(&introduced);
+                    tokio::spawn(async move {
+                        let _ = holepunch_peer(
+                            &peer,
+                            &node.torrent,
+                            &node.transport,
+                            Arc::clone(&node.progress),
+                            Arc::clone(&node.completed_pieces),
+                        )
+                        .await;
+                    });
+                }
+            }
+        });
+
+        node
+    }
+
+    fn peer(&self) -> Peer {
+        Peer::from(self.addr)
+    }
+
+    fn connect(self: &Arc<Self>, other: &Node) {
+        let node = Arc::clone(self);
+        let peer = other.peer();
+        tokio::spawn(async move {
+            let _ = connect_to_peer(
+                &peer,
+                &node.torrent,
+                &node.transport,
+                Arc::clone(&node.progress),
+                Arc::clone(&node.completed_pieces),
+            )
+            .await;
+        });
+    }
+
+    fn is_connected_to(&self, other: &Node) -> bool {
+        let other = other.peer().to_string();
+        self.progress
+            .read()
+            .unwrap()
+            .peer_ids
+            .values()
+            .any(|peer| *peer == other)
+    }
+
+    fn can_relay_through(&self, other: &Node) -> bool {
+        self.progress
+            .read()
+            .unwrap()
+            .holepunch
+            .is_relay(&other.peer().to_string())
+    }
+}
+
+async fn wait_until(condition: impl Fn() -> bool) {
+    timeout(TEST_TIMEOUT, async {
+        while !condition() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    })
+    .await
+    .expect("condition never held");
+}
+
+/// The initiator and the target both connected to the relay, and each side
+/// knows the other speaks ut_holepunch.
+async fn start_swarm() -> (Arc<Node>, Arc<Node>, Arc<Node>) {
+    let torrent = Arc::new(make_torrent());
+    let relay = Node::start(&torrent);
+    let initiator = Node::start(&torrent);
+    let target = Node::start(&torrent);
+
+    initiator.connect(&relay);
+    target.connect(&relay);
+    wait_until(|| {
+        initiator.can_relay_through(&relay)
+            && relay.can_relay_through(&initiator)
+            && relay.can_relay_through(&target)
+    })
+    .await;
+    (initiator, relay, target)
+}
+
+#[test]
+fn messages_round_trip() {
+    let v4 = Peer::from(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 6881));
+    let v6 = Peer::from(SocketAddr::new(
+        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+        51413,
+    ));
+    for message in [
+        HolepunchMessage::Rendezvous(v4.clone()),
+        HolepunchMessage::Connect(v6.clone()),
+        HolepunchMessage::Error(v4, HolepunchError::NoSupport),
+        HolepunchMessage::Error(v6, HolepunchError::NoSelf),
+    ] {
+        let bytes = Vec::from(&message);
+        assert_eq!(HolepunchMessage::try_from(&bytes[..]).unwrap(), message);
+    }
+}
+
+#[test]
+fn message_layout_matches_bep_55() {
+    let peer = Peer::from(SocketAddr::new(Ipv4Addr::new(1, 2, 3, 4).into(), 0x1ae1));
+    assert_eq!(
+        Vec::from(&HolepunchMessage::Error(peer, HolepunchError::NotConnected)),
+        [2, 0, 1, 2, 3, 4, 0x1a, 0xe1, 0, 0, 0, 2]
+    );
+}
+
+#[test]
+fn rejects_malformed_messages() {
+    for payload in [
+        &[0, 0, 1, 2, 3, 4, 0, 1][..],
+        &[0, 1, 1, 2, 3, 4, 0, 1, 0, 0, 0, 0],
+        &[0, 2, 1, 2, 3, 4, 0, 1, 0, 0, 0, 0],
+        &[3, 0, 1, 2, 3, 4, 0, 1, 0, 0, 0, 0],
+        &[2, 0, 1, 2, 3, 4, 0, 1, 0, 0, 0, 9],
+    ] {
+        assert!(HolepunchMessage::try_from(payload).is_err());
+    }
+}
+
+#[test]
+fn relay_reports_why_it_cannot_introduce() {
+    let from = Peer::from(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 6881));
+    let target = Peer::from(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6881));
+    let mut holepunch = Holepunch::default();
+
+    assert_eq!(
+        holepunch.relay(&from, &from, false),
+        Err(HolepunchError::NoSelf)
+    );
+    assert_eq!(
+        holepunch.relay(
+            &from,
+            &Peer::from(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 1)),
+            false
+        ),
+        Err(HolepunchError::NoSuchPeer)
+    );
+    assert_eq!(
+        holepunch.relay(&from, &target, false),
+        Err(HolepunchError::NotConnected)
+    );
+    assert_eq!(
+        holepunch.relay(&from, &target, true),
+        Err(HolepunchError::NoSupport)
+    );
+
+    let (sender, mut receiver) = mpsc::channel(1);
+    holepunch.add_relay(&target.to_string(), sender);
+    assert_eq!(holepunch.relay(&from, &target, true), Ok(()));
+    assert_eq!(
+        receiver.try_recv().unwrap(),
+        HolepunchMessage::Connect(from)
+    );
+}
+
+#[test]
+fn only_the_requesting_side_dials() {
+    let relay = Peer::from(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 6881));
+    let target = Peer::from(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6881));
+    let stranger = Peer::from(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 6881));
+    let mut holepunch = Holepunch::default();
+
+    // Nobody to ask until a relay told us about the target
+    assert!(!holepunch.request(&target));
+    let (sender, mut receiver) = mpsc::channel(1);
+    holepunch.add_relay(&relay.to_string(), sender);
+    holepunch.learned_from(target.clone(), &relay.to_string());
+    assert!(holepunch.request(&target));
+    assert_eq!(
+        receiver.try_recv().unwrap(),
+        HolepunchMessage::Rendezvous(target.clone())
+    );
+    // Each target is only asked for once
+    assert!(!holepunch.request(&target));
+
+    holepunch.connect_received(target.clone());
+    holepunch.connect_received(stranger.clone());
+    assert_eq!(holepunch.take_connects(), (vec![target], vec![stranger]));
+}
+
+#[tokio::test]
+async fn relay_introduces_two_peers_over_utp() {
+    let (initiator, relay, target) = start_swarm().await;
+    assert!(!initiator.is_connected_to(&target));
+
+    // As if the relay told us about the target over PEX and dialing it failed
+    {
+        let mut progress = initiator.progress.write().unwrap();
+        progress
+            .holepunch
+            .learned_from(target.peer(), &relay.peer().to_string());
+        assert!(progress.holepunch.request(&target.peer()));
+    }
+
+    wait_until(|| initiator.is_connected_to(&target) && target.is_connected_to(&initiator)).await;
+    // The introduction was used up
+    assert!(initiator
+        .progress
+        .read()
+        .unwrap()
+        .holepunch
+        .requested
+        .is_empty());
+    assert!(relay.is_connected_to(&initiator) && relay.is_connected_to(&target));
+}
+
+#[tokio::test]
+async fn relay_error_reaches_the_initiator() {
+    let (initiator, relay, target) = start_swarm().await;
+    // A client the relay has never heard of
+    let stranger = Peer::from(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9));
+
+    {
+        let mut progress = initiator.progress.write().unwrap();
+        progress
+            .holepunch
+            .learned_from(stranger.clone(), &relay.peer().to_string());
+        assert!(progress.holepunch.request(&stranger));
+    }
+
+    wait_until(|| {
+        initiator
+            .progress
+            .read()
+            .unwrap()
+            .holepunch
+            .requested
+            .is_empty()
+    })
+    .await;
+    assert_eq!(
+        initiator
+            .progress
+            .write()
+            .unwrap()
+            .holepunch
+            .take_connects(),
+        (vec![], vec![])
+    );
+    assert!(!initiator.is_connected_to(&target));
+}
@@ -0,0 +1,401 @@
+use std::{collections::HashMap, time::Duration};
+
+use sha1::{Digest, Sha1};
+use tokio::task::JoinSet;
+
+use crate::{
+    bencoding::{
+        decode::{decode_dictionary, Value},
+        encode::encode_dictionary,
+    },
+    connection::Peer,
+    peer::{
+        extensions::{
+            ExtendedHandshake, ExtensionContext, ExtensionHandler, ExtensionRegistry,
+            EXTENDED_HANDSHAKE_ID, EXTENSION_PROTOCOL_BIT, EXTENSION_PROTOCOL_BYTE,
+        },
+        peer_id::ClientIdentity,
+        peer_protocol::{PeerProtocolError, LISTEN_PORT},
+        transport::Transport,
+        types::{PeerHandshake, PeerMessageID, PROTOCOL_STRING},
+    },
+    util::peer_message_stream::PeerMessageStream,
+};
+
+#[cfg(test)]
+mod tests;
+
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// Metadata is exchanged in 16 KiB pieces.
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Refuse to download info dictionaries larger than this.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+/// How long a single peer gets to hand over the whole info dictionary.
+const METADATA_PEER_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many peers we ask for metadata at the same time.
+const MAX_METADATA_CONNECTIONS: usize = 8;
+
+#[derive(Debug)]
+pub enum MetadataMessage {
+    Request {
+        piece: u32,
+    },
+    Data {
+        piece: u32,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: u32,
+    },
+}
+
+impl TryFrom<&[u8]> for MetadataMessage {
+    type Error = String;
+
+    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+        let mut index = 0;
+        let dict = match decode_dictionary(payload, &mut index)? {
+            Value::Dict(d) => d,
+            _ => return Err("ut_metadata message is not a dictionary".to_string()),
+        };
+
+        let piece = match dict.get("piece") {
+            Some(Value::Number(n)) => u32::try_from(*n).map_err(|_| "Invalid metadata piece")?,
+            _ => return Err("ut_metadata message has no piece".to_string()),
+        };
+
+        match dict.get("msg_type") {
+            Some(Value::Number(0)) => Ok(MetadataMessage::Request { piece }),
+            Some(Value::Number(1)) => {
+                let total_size = match dict.get("total_size") {
+                    Some(Value::Number(n)) => {
+                        usize::try_from(*n).map_err(|_| "Invalid metadata size")?
+                    }
+                    _ => return Err("ut_metadata data has no total_size".to_string()),
+                };
+
+                // The piece data follows the bencoded dictionary
+                Ok(MetadataMessage::Data {
+                    piece,
+                    total_size,
+                    data: payload[index..].to_vec(),
+                })
+            }
+            Some(Value::Number(2)) => Ok(MetadataMessage::Reject { piece }),
+            other => Err(format!("Unknown ut_metadata msg_type: {:?}", other)),
+        }
+    }
+}
+
+impl From<&MetadataMessage> for Vec<u8> {
+    fn from(message: &MetadataMessage) -> Self {
+        let mut dict = HashMap::new();
+        let (msg_type, piece) = match message {
+            MetadataMessage::Request { piece } => (0, piece),
+            MetadataMessage::Data {
+                piece, total_size, ..
+            } => {
+                dict.insert("total_size".to_string(), Value::Number(*total_size as i64));
+                (1, piece)
+            }
+            MetadataMessage::Reject { piece } => (2, piece),
+        };
+        dict.insert("msg_type".to_string(), Value::Number(msg_type));
+        dict.insert("piece".to_string(), Value::Number(*piece as i64));
+
+        let mut buf = encode_dictionary(&dict);
+        if let MetadataMessage::Data { data, .. } = message {
+            buf.extend_from_slice(data);
+        }
+        buf
+    }
+}
+
+/// Serves our info dictionary to peers that ask for it (BEP 9).
+pub struct UtMetadata {
+    metadata_size: usize,
+}
+
+impl UtMetadata {
+    pub fn new(metadata_size: usize) -> Self {
+        UtMetadata { metadata_size }
+    }
+}
+
+impl ExtensionHandler for UtMetadata {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn handshake_fields(&self, fields: &mut HashMap<String, Value>) {
+        if self.metadata_size > 0 {
+            fields.insert(
+                "metadata_size".to_string(),
+                Value::Number(self.metadata_size as i64),
+            );
+        }
+    }
+
+    fn on_message(&mut self, ctx: &mut ExtensionContext, payload: &[u8]) {
+        let message = match MetadataMessage::try_from(payload) {
+            Ok(message) => message,
+            Err(e) => {
+                println!(
+                    "{} - Invalid ut_metadata message: {}",
+                    ctx.peer_state.peer, e
+                );
+                return;
+            }
+        };
+
+        // We already have the metadata, so only requests are interesting
+        let MetadataMessage::Request { piece } = message else {
+            return;
+        };
+
+        let info_bytes = &ctx.torrent.info_bytes;
+        let start = piece as usize * METADATA_PIECE_SIZE;
+        let reply = if start < info_bytes.len() {
+            let end = (start + METADATA_PIECE_SIZE).min(info_bytes.len());
+            MetadataMessage::Data {
+                piece,
+                total_size: info_bytes.len(),
+                data: info_bytes[start..end].to_vec(),
+            }
+        } else {
+            MetadataMessage::Reject { piece }
+        };
+        ctx.send(UT_METADATA, Vec::from(&reply));
+    }
+}
+
+/// Collects metadata pieces until the whole info dictionary is in.
+pub struct MetadataDownload {
+    total_size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl MetadataDownload {
+    pub fn new(total_size: usize) -> Result<Self, String> {
+        if total_size == 0 || total_size > MAX_METADATA_SIZE {
+            return Err(format!("Refusing metadata of {} bytes", total_size));
+        }
+
+        Ok(MetadataDownload {
+            total_size,
+            pieces: vec![None; total_size.div_ceil(METADATA_PIECE_SIZE)],
+        })
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.pieces.len() as u32
+    }
+
+    pub fn add_piece(&mut self, piece: u32, data: Vec<u8>) -> Result<(), String> {
+        let piece = piece as usize;
+        if piece >= self.pieces.len() {
+            return Err(format!("Metadata piece {} out of range", piece));
+        }
+
+        let expected = if piece == self.pieces.len() - 1 {
+            self.total_size - piece * METADATA_PIECE_SIZE
+        } else {
+            METADATA_PIECE_SIZE
+        };
+        if data.len() != expected {
+            return Err(format!(
+                "Metadata piece {} is {} bytes, expected {}",
+                piece,
+                data.len(),
+                expected
+            ));
+        }
+
+        self.pieces[piece] = Some(data);
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    /// Joins the pieces and checks them against the info hash.
+    pub fn finish(self, info_hash: &[u8; 20]) -> Result<Vec<u8>, String> {
+        let metadata: Vec<u8> = self.pieces.into_iter().flatten().flatten().collect();
+        let hash: [u8; 20] = Sha1::digest(&metadata).into();
+        if &hash != info_hash {
+            return Err("Metadata does not match the info hash".to_string());
+        }
+
+        Ok(metadata)
+    }
+}
+
+/// Downloads the info dictionary for `info_hash` from whichever of `peers`
+/// delivers it first.
+pub async fn fetch_metadata(
+    info_hash: [u8; 20],
+    identity: ClientIdentity,
+    transport: &Transport,
+    peers: Vec<Peer>,
+) -> Result<Vec<u8>, String> {
+    let mut peers = peers.into_iter();
+    let mut attempts = JoinSet::new();
+
+    loop {
+        while attempts.len() < MAX_METADATA_CONNECTIONS {
+            let Some(peer) = peers.next() else {
+                break;
+            };
+            let transport = transport.clone();
+            attempts.spawn(async move {
+                let result = tokio::time::timeout(
+                    METADATA_PEER_TIMEOUT,
+                    fetch_metadata_from_peer(&peer, &info_hash, &identity, &transport),
+                )
+                .await
+                .unwrap_or(Err(PeerProtocolError::ConnectionClosed));
+                (peer, result)
+            });
+        }
+
+        match attempts.join_next().await {
+            Some(Ok((_, Ok(metadata)))) => return Ok(metadata),
+            Some(Ok((peer, Err(e)))) => {
+                println!("Failed to get metadata from {}: {:?}", peer.to_string(), e);
+            }
+            Some(Err(_)) => {}
+            None => return Err("No peer could provide the metadata".to_string()),
+        }
+    }
+}
+
+async fn fetch_metadata_from_peer(
+    peer: &Peer,
+    info_hash: &[u8; 20],
+    identity: &ClientIdentity,
+    transport: &Transport,
+) -> Result<Vec<u8>, PeerProtocolError> {
+    let stream = transport.connect(peer, info_hash).await?;
+    let mut peer_message_stream = PeerMessageStream::new(Box::pin(stream));
+
+    let mut reserved = [0; 8];
+    reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+    let handshake = PeerHandshake {
+        pstr: PROTOCOL_STRING.to_owned(),
+        reserved,
+        info_hash: *info_hash,
+        peer_id: identity.peer_id,
+    };
+    let handshake_error = |e: std::io::Error| PeerProtocolError::HandshakeError(e.to_string());
+    peer_message_stream
+        .write_all(&Vec::from(&handshake))
+        .await
+        .map_err(handshake_error)?;
+
+    let mut response_buf = [0; 68];
+    peer_message_stream
+        .read_exact(&mut response_buf)
+        .await
+        .map_err(handshake_error)?;
+    let response =
+        PeerHandshake::try_from(response_buf).map_err(PeerProtocolError::HandshakeError)?;
+    if response.info_hash != *info_hash {
+        return Err(PeerProtocolError::HandshakeError(
+            "Peer sent a handshake for a different torrent".to_string(),
+        ));
+    }
+    if response.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT == 0 {
+        return Err(PeerProtocolError::HandshakeError(
+            "Peer does not support the extension protocol".to_string(),
+        ));
+    }
+
+    // Advertising ut_metadata without a metadata_size tells the peer we're
+    // the one missing it
+    let registry = ExtensionRegistry::new(vec![Box::new(UtMetadata::new(0))]);
+    let local_id = 1;
+    peer_message_stream
+        .write_all(&Vec::from(&registry.create_handshake(
+            LISTEN_PORT,
+            &peer.ip,
+            identity.client_version(),
+        )))
+        .await
+        .map_err(handshake_error)?;
+
+    let (mut reader, mut writer) = peer_message_stream.split();
+    let mut download: Option<MetadataDownload> = None;
+
+    loop {
+        let message = reader.read_message().await?;
+        if !matches!(message.id, PeerMessageID::Extended) {
+            continue;
+        }
+        let Some((&extension_id, payload)) = message.payload.split_first() else {
+            continue;
+        };
+
+        if extension_id == EXTENDED_HANDSHAKE_ID {
+            let handshake =
+                ExtendedHandshake::try_from(payload).map_err(PeerProtocolError::ReceivedError)?;
+            let peer_id = match handshake.extensions.get(UT_METADATA) {
+                Some(&id) if id != 0 => id,
+                _ => {
+                    return Err(PeerProtocolError::ReceivedError(
+                        "Peer does not support ut_metadata".to_string(),
+                    ))
+                }
+            };
+            let metadata_size = match handshake.fields.get("metadata_size") {
+                Some(Value::Number(n)) => usize::try_from(*n).unwrap_or(0),
+                _ => 0,
+            };
+
+            let new_download =
+                MetadataDownload::new(metadata_size).map_err(PeerProtocolError::ReceivedError)?;
+            for piece in 0..new_download.num_pieces() {
+                let request = MetadataMessage::Request { piece };
+                let message = super::create_extended(peer_id, Vec::from(&request));
+                writer
+                    .write_message(&message)
+                    .await
+                    .map_err(|_| PeerProtocolError::ConnectionClosed)?;
+            }
+            download = Some(new_download);
+            continue;
+        }
+
+        if extension_id != local_id {
+            continue;
+        }
+        let Some(current) = download.as_mut() else {
+            continue;
+        };
+
+        match MetadataMessage::try_from(payload).map_err(PeerProtocolError::ReceivedError)? {
+            MetadataMessage::Data { piece, data, .. } => {
+                current
+                    .add_piece(piece, data)
+                    .map_err(PeerProtocolError::ReceivedError)?;
+            }
+            MetadataMessage::Reject { piece } => {
+                return Err(PeerProtocolError::ReceivedError(format!(
+                    "Peer rejected metadata piece {}",
+                    piece
+                )))
+            }
+            MetadataMessage::Request { .. } => {}
+        }
+
+        if current.is_complete() {
+            return download
+                .take()
+                .unwrap()
+                .finish(info_hash)
+                .map_err(PeerProtocolError::ReceivedError);
+        }
+    }
+}
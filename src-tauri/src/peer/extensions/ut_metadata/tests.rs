@@ -0,0 +1,41 @@
+use crate::peer::extensions::ut_metadata::MetadataMessage;
+
+#[test]
+fn data_message_keeps_the_trailing_piece() {
+    let message: Vec<u8> = (&MetadataMessage::Data {
+        piece: 1,
+        total_size: 20_000,
+        data: b"piece data".to_vec(),
+    })
+        .into();
+    match MetadataMessage::try_from(&message[..]).unwrap() {
+        MetadataMessage::Data {
+            piece,
+            total_size,
+            data,
+        } => {
+            assert_eq!(piece, 1);
+            assert_eq!(total_size, 20_000);
+            assert_eq!(data, b"piece data");
+        }
+        other => panic!("Expected data, got {:?}", other),
+    }
+}
+
+#[test]
+fn malformed_messages_are_an_error() {
+    for payload in [
+        &b""[..],
+        b"d8:msg_typei1e5:piecei0e10:total_size",
+        b"d8:msg_typei1e5:piecei0e10:total_sizei1",
+        b"d8:msg_type999999:",
+        b"d8:msg_typei99999999999999999999e5:piecei0ee",
+        b"d8:msg_typei0e5:piecei-1ee",
+    ] {
+        assert!(
+            MetadataMessage::try_from(payload).is_err(),
+            "{:?}",
+            String::from_utf8_lossy(payload)
+        );
+    }
+}
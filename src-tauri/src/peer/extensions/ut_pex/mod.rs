@@ -0,0 +1,261 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bencoding::{
+        decode::{decode_dictionary, Value},
+        encode::encode_dictionary,
+    },
+    connection::Peer,
+    peer::extensions::{ExtensionContext, ExtensionHandler},
+};
+
+#[cfg(test)]
+mod tests;
+
+pub const UT_PEX: &str = "ut_pex";
+
+/// How often we send a peer our changes to the swarm.
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// PEX messages that arrive faster than this are dropped. BEP 11 asks for
+/// one per minute, this leaves some slack for timer drift.
+const PEX_MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+/// Most peers we put in, or accept from, a single message's added list.
+const MAX_PEX_PEERS: usize = 50;
+/// Stop collecting candidates once this many are waiting to be dialed.
+const MAX_CANDIDATE_PEERS: usize = 500;
+
+/// `added.f` flags.
+const PEX_FLAG_SEED: u8 = 0x02;
+const PEX_FLAG_HOLEPUNCH: u8 = 0x08;
+const PEX_FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Debug, Default)]
+pub struct PexMessage {
+    pub added: Vec<(Peer, u8)>,
+    pub dropped: Vec<Peer>,
+}
+
+impl TryFrom<&[u8]> for PexMessage {
+    type Error = String;
+
+    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+        let dict = match decode_dictionary(payload, &mut 0)? {
+            Value::Dict(d) => d,
+            _ => return Err("ut_pex message is not a dictionary".to_string()),
+        };
+
+        let compact_peers = |key: &str, size: usize| -> Vec<Peer> {
+            dict.get(key)
+                .and_then(Value::as_bytes)
+                .unwrap_or_default()
+                .chunks_exact(size)
+                .filter_map(|chunk| Peer::try_from(chunk).ok())
+                .collect()
+        };
+        let flags = |key: &str| -> Vec<u8> {
+            dict.get(key)
+                .and_then(Value::as_bytes)
+                .unwrap_or_default()
+                .to_vec()
+        };
+
+        // IPv6 peers are listed separately under the `6` keys
+        let mut added = vec![];
+        for (key, flags_key, size) in [("added", "added.f", 6), ("added6", "added6.f", 18)] {
+            let flags = flags(flags_key);
+            added.extend(
+                compact_peers(key, size)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, peer)| (peer, flags.get(i).copied().unwrap_or(0))),
+            );
+        }
+
+        let mut dropped = compact_peers("dropped", 6);
+        dropped.extend(compact_peers("dropped6", 18));
+
+        Ok(PexMessage { added, dropped })
+    }
+}
+
+impl From<&PexMessage> for Vec<u8> {
+    fn from(message: &PexMessage) -> Self {
+        let mut added = vec![];
+        let mut added_flags = vec![];
+        let mut added6 = vec![];
+        let mut added6_flags = vec![];
+        for (peer, flags) in &message.added {
+            if peer.ip.is_ipv4() {
+                added.extend(peer.to_compact());
+                added_flags.push(*flags);
+            } else {
+                added6.extend(peer.to_compact());
+                added6_flags.push(*flags);
+            }
+        }
+
+        let mut dropped = vec![];
+        let mut dropped6 = vec![];
+        for peer in &message.dropped {
+            if peer.ip.is_ipv4() {
+                dropped.extend(peer.to_compact());
+            } else {
+                dropped6.extend(peer.to_compact());
+            }
+        }
+
+        let mut dict = HashMap::new();
+        dict.insert("added".to_string(), Value::Bytes(added));
+        dict.insert("added.f".to_string(), Value::Bytes(added_flags));
+        dict.insert("dropped".to_string(), Value::Bytes(dropped));
+        if !added6.is_empty() || !dropped6.is_empty() {
+            dict.insert("added6".to_string(), Value::Bytes(added6));
+            dict.insert("added6.f".to_string(), Value::Bytes(added6_flags));
+            dict.insert("dropped6".to_string(), Value::Bytes(dropped6));
+        }
+        encode_dictionary(&dict)
+    }
+}
+
+/// Peer exchange (BEP 11). Tells the peer which peers we connected to or
+/// lost since the last message, and feeds the peers it tells us about into
+/// the candidate pool.
+#[derive(Default)]
+pub struct UtPex {
+    /// Peers the remote side currently knows we're connected to.
+    advertised: HashSet<Peer>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl UtPex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ExtensionHandler for UtPex {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    fn on_message(&mut self, ctx: &mut ExtensionContext, payload: &[u8]) {
+        let now = Instant::now();
+        if let Some(last_received) = self.last_received {
+            if now.duration_since(last_received) < PEX_MIN_RECEIVE_INTERVAL {
+                println!(
+                    "{} - Ignoring PEX message, sent too soon",
+                    ctx.peer_state.peer
+                );
+                return;
+            }
+        }
+        self.last_received = Some(now);
+
+        let message = match PexMessage::try_from(payload) {
+            Ok(message) => message,
+            Err(e) => {
+                println!("{} - Invalid ut_pex message: {}", ctx.peer_state.peer, e);
+                return;
+            }
+        };
+
+        let mut progress = ctx.progress.write().unwrap();
+        for peer in &message.dropped {
+            progress.candidate_peers.remove(peer);
+        }
+        for (peer, flags) in message.added.into_iter().take(MAX_PEX_PEERS) {
+            if progress.candidate_peers.len() >= MAX_CANDIDATE_PEERS {
+                break;
+            }
+            if peer.port != 0 && !progress.connected_peers.contains(&peer) {
+                // The sender can introduce us if we can't reach the peer
+                if flags & PEX_FLAG_HOLEPUNCH != 0 {
+                    progress
+                        .holepunch
+                        .learned_from(peer.clone(), &ctx.peer_state.peer);
+                }
+                progress.candidate_peers.insert(peer);
+            }
+        }
+    }
+
+    fn on_tick(&mut self, ctx: &mut ExtensionContext) {
+        if !ctx.peer_supports(UT_PEX) {
+            return;
+        }
+
+        let now = Instant::now();
+        if let Some(last_sent) = self.last_sent {
+            if now.duration_since(last_sent) < PEX_INTERVAL {
+                return;
+            }
+        }
+
+        // Only peers we completed a handshake with are worth passing on.
+        // Peers that connected to us did so from a port nobody else can reach
+        // them on, so they go out with the port they said they listen on, if
+        // they did.
+        let mut message = PexMessage::default();
+        let current: HashMap<Peer, u8> = {
+            let progress = ctx.progress.read().unwrap();
+            progress
+                .connected_peers
+                .iter()
+                .filter(|peer| peer.to_string() != ctx.peer_state.peer)
+                .filter_map(|peer| {
+                    let stats = progress.peer_stats.get(&peer.to_string())?;
+                    let (advertised, mut flags) = match stats.is_incoming {
+                        false => (peer.clone(), PEX_FLAG_REACHABLE),
+                        true => (
+                            Peer {
+                                ip: peer.ip,
+                                port: stats.listen_port.filter(|&port| port != 0)?,
+                            },
+                            0,
+                        ),
+                    };
+                    if stats.is_seed {
+                        flags |= PEX_FLAG_SEED;
+                    }
+                    if progress.holepunch.is_relay(&peer.to_string()) {
+                        flags |= PEX_FLAG_HOLEPUNCH;
+                    }
+                    Some((advertised, flags))
+                })
+                .collect()
+        };
+
+        for (peer, flags) in &current {
+            if message.added.len() >= MAX_PEX_PEERS {
+                break;
+            }
+            if !self.advertised.contains(peer) {
+                message.added.push((peer.clone(), *flags));
+            }
+        }
+        message.dropped = self
+            .advertised
+            .iter()
+            .filter(|peer| !current.contains_key(peer))
+            .take(MAX_PEX_PEERS)
+            .cloned()
+            .collect();
+
+        self.last_sent = Some(now);
+        if message.added.is_empty() && message.dropped.is_empty() {
+            return;
+        }
+
+        for peer in &message.dropped {
+            self.advertised.remove(peer);
+        }
+        for (peer, _) in &message.added {
+            self.advertised.insert(peer.clone());
+        }
+        ctx.send(UT_PEX, Vec::from(&message));
+    }
+}
@@ -0,0 +1,44 @@
+use crate::{connection::Peer, peer::extensions::ut_pex::PexMessage};
+
+#[test]
+fn parses_what_we_send() {
+    let peer = Peer {
+        ip: [10, 0, 0, 1].into(),
+        port: 6881,
+    };
+    let message: Vec<u8> = (&PexMessage {
+        added: vec![(peer.clone(), 0x02)],
+        dropped: vec![],
+    })
+        .into();
+
+    let parsed = PexMessage::try_from(&message[..]).unwrap();
+    assert_eq!(parsed.added, vec![(peer, 0x02)]);
+    assert!(parsed.dropped.is_empty());
+}
+
+#[test]
+fn truncated_message_is_an_error() {
+    for payload in [&b"d"[..], b"d5:added", b"d5:added6:\x0a\x00", b"d5:addedl"] {
+        assert!(
+            PexMessage::try_from(payload).is_err(),
+            "{:?}",
+            String::from_utf8_lossy(payload)
+        );
+    }
+}
+
+#[test]
+fn oversized_lengths_are_an_error() {
+    for payload in [
+        &b"d5:added600:\x0a\x00\x00\x01\x1a\xe1e"[..],
+        b"d7:dropped18446744073709551616:e",
+        b"d5:added-6:\x0a\x00\x00\x01\x1a\xe1e",
+    ] {
+        assert!(
+            PexMessage::try_from(payload).is_err(),
+            "{:?}",
+            String::from_utf8_lossy(payload)
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use std::{collections::HashSet, net::IpAddr};
+
+use sha1::{Digest, Sha1};
+
+/// Reserved byte and bit that advertise the Fast Extension (BEP 6).
+pub const FAST_EXTENSION_BYTE: usize = 7;
+pub const FAST_EXTENSION_BIT: u8 = 0x04;
+
+/// Number of pieces we let a peer download from us while it's choked.
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+/// Computes the canonical allowed fast set for a peer, as described in BEP 6.
+/// The set only depends on the peer's /24 and the info hash, so a peer can't
+/// collect extra pieces by reconnecting from a neighbouring address.
+///
+/// BEP 6 only defines the set for IPv4 peers, so IPv6 peers get an empty set.
+pub fn allowed_fast_set(
+    ip: &IpAddr,
+    info_hash: &[u8; 20],
+    num_pieces: u32,
+    k: usize,
+) -> HashSet<u32> {
+    let mut allowed = HashSet::new();
+    let IpAddr::V4(ipv4) = ip else {
+        return allowed;
+    };
+    if num_pieces == 0 {
+        return allowed;
+    }
+
+    let k = k.min(num_pieces as usize);
+    let masked = u32::from(*ipv4) & 0xFFFF_FF00;
+    let mut x = masked.to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+
+    while allowed.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for i in 0..5 {
+            if allowed.len() >= k {
+                break;
+            }
+
+            let j = i * 4;
+            let y = u32::from_be_bytes(x[j..j + 4].try_into().unwrap());
+            allowed.insert(y % num_pieces);
+        }
+    }
+
+    allowed
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use rand::seq::IteratorRandom;
+
+/// Most pieces a lazy bitfield leaves out.
+const LAZY_BITFIELD_PIECES: usize = 8;
+
+/// How we tell peers which pieces we have.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HaveSettings {
+    /// Leave a few pieces out of our bitfield and send them as `Have`s right
+    /// after, so a seed can't be spotted from its bitfield alone.
+    pub lazy_bitfield: bool,
+    /// Collect `Have`s and send them this often, rather than one per piece
+    /// as it completes.
+    pub batch_interval: Option<Duration>,
+    /// Once we're a seed, pretend to have nothing and reveal pieces one by
+    /// one (BEP 16).
+    pub super_seed: bool,
+}
+
+impl HaveSettings {
+    /// `LAZY_BITFIELD` and `SUPER_SEED` set to `1` or `true` turn on lazy
+    /// bitfields and super-seeding, and `HAVE_BATCH_INTERVAL` in milliseconds
+    /// turns on batching.
+    pub fn from_env() -> Self {
+        let flag = |name| {
+            std::env::var(name)
+                .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        };
+        let batch_interval = std::env::var("HAVE_BATCH_INTERVAL")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|&millis| millis > 0)
+            .map(Duration::from_millis);
+
+        HaveSettings {
+            lazy_bitfield: flag("LAZY_BITFIELD"),
+            batch_interval,
+            super_seed: flag("SUPER_SEED"),
+        }
+    }
+}
+
+/// Clears a few random pieces from `bitfield`, returning the ones left out so
+/// they can be sent as `Have`s instead.
+pub fn withhold_pieces(bitfield: &mut [u8]) -> Vec<u32> {
+    let withheld: Vec<u32> = (0..bitfield.len() as u32 * 8)
+        .filter(|&i| bitfield[i as usize / 8] & (1 << (7 - i % 8)) != 0)
+        .choose_multiple(&mut rand::rng(), LAZY_BITFIELD_PIECES);
+
+    for &i in &withheld {
+        bitfield[i as usize / 8] &= !(1 << (7 - i % 8));
+    }
+    withheld
+}
@@ -1,2 +1,12 @@
+pub mod bandwidth;
+pub mod choker;
+pub mod connection_manager;
+pub mod extensions;
+pub mod fast_extension;
+pub mod have;
+pub mod mse;
+pub mod peer_id;
 pub mod peer_protocol;
+pub mod super_seed;
+pub mod transport;
 pub mod types;
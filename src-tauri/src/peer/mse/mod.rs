@@ -0,0 +1,394 @@
+use std::time::Duration;
+
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    peer::peer_protocol::PeerProtocolError,
+    util::encrypted_stream::{EncryptedStream, Rc4},
+};
+
+#[cfg(test)]
+mod tests;
+
+/// The 768 bit prime Message Stream Encryption does its Diffie-Hellman
+/// exchange in. The generator is 2.
+const DH_PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const DH_GENERATOR: u32 = 2;
+/// Public keys and the shared secret are always sent as 96 bytes.
+const DH_KEY_SIZE: usize = 96;
+
+/// Longest random padding either side may put between handshake fields.
+const MAX_PAD: usize = 512;
+/// Verification constant, encrypted to let the other side sync up.
+const VC: [u8; 8] = [0; 8];
+
+/// `crypto_provide`/`crypto_select` bits.
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// How long the encryption handshake may take before we give up on it.
+const MSE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+
+const BITTORRENT_PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// How we deal with Message Stream Encryption (MSE/PE).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Try the encrypted handshake, but only obfuscate the handshake itself
+    /// and keep the message stream in plaintext when the peer allows it.
+    /// Peers that don't speak MSE get a plaintext connection.
+    PlaintextFallback,
+    /// Encrypt the whole stream with RC4 when the peer allows it, otherwise
+    /// fall back to plaintext.
+    PreferEncrypted,
+    /// Only talk to peers over fully RC4 encrypted connections.
+    RequireEncrypted,
+}
+
+impl EncryptionPolicy {
+    /// Reads the policy from `ENCRYPTION_POLICY`, defaulting to
+    /// prefer-encrypted.
+    pub fn from_env() -> Self {
+        match std::env::var("ENCRYPTION_POLICY").as_deref() {
+            Ok("plaintext-fallback") => EncryptionPolicy::PlaintextFallback,
+            Ok("require-encrypted") => EncryptionPolicy::RequireEncrypted,
+            Ok("prefer-encrypted") | Err(_) => EncryptionPolicy::PreferEncrypted,
+            Ok(other) => {
+                println!(
+                    "Unknown ENCRYPTION_POLICY {}, using prefer-encrypted",
+                    other
+                );
+                EncryptionPolicy::PreferEncrypted
+            }
+        }
+    }
+
+    pub fn allows_plaintext(self) -> bool {
+        self != EncryptionPolicy::RequireEncrypted
+    }
+
+    fn crypto_provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::RequireEncrypted => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    /// Picks one of the methods the initiating side offered.
+    fn crypto_select(self, provided: u32) -> Option<u32> {
+        let preference: &[u32] = match self {
+            EncryptionPolicy::PlaintextFallback => &[CRYPTO_PLAINTEXT, CRYPTO_RC4],
+            EncryptionPolicy::PreferEncrypted => &[CRYPTO_RC4, CRYPTO_PLAINTEXT],
+            EncryptionPolicy::RequireEncrypted => &[CRYPTO_RC4],
+        };
+        preference
+            .iter()
+            .copied()
+            .find(|method| provided & method != 0)
+    }
+}
+
+/// Runs the initiating side of the encryption handshake on a fresh
+/// connection.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<EncryptedStream<S>, PeerProtocolError> {
+    tokio::time::timeout(
+        MSE_HANDSHAKE_TIMEOUT,
+        run_initiate(stream, info_hash, policy),
+    )
+    .await
+    .unwrap_or_else(|_| Err(mse_error("Encryption handshake timed out")))
+}
+
+/// Runs the receiving side of the encryption handshake on a connection a
+/// peer opened to us, for any of the torrents in `info_hashes`. Returns the
+/// one the peer asked for, or `None` for a plaintext handshake, which is
+/// passed through untouched if the policy allows it.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(EncryptedStream<S>, Option<[u8; 20]>), PeerProtocolError> {
+    tokio::time::timeout(
+        MSE_HANDSHAKE_TIMEOUT,
+        run_accept(stream, info_hashes, policy),
+    )
+    .await
+    .unwrap_or_else(|_| Err(mse_error("Encryption handshake timed out")))
+}
+
+async fn run_initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<EncryptedStream<S>, PeerProtocolError> {
+    let mut reader = HandshakeReader::new(stream);
+
+    // 1. A->B: Ya, PadA
+    let (private_key, public_key) = generate_key_pair();
+    let mut message = public_key;
+    message.extend(random_pad());
+    reader.write_all(&message).await?;
+
+    // 2. B->A: Yb, PadB
+    let their_key = reader.read_exact(DH_KEY_SIZE).await?;
+    let secret = shared_secret(&private_key, &their_key);
+    let mut write_cipher = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut read_cipher = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+    //    ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let mut encrypted = VC.to_vec();
+    encrypted.extend_from_slice(&policy.crypto_provide().to_be_bytes());
+    // No PadC and no initial payload, the BitTorrent handshake follows
+    // through the stream
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    write_cipher.apply(&mut encrypted);
+    message.extend(encrypted);
+    reader.write_all(&message).await?;
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD). PadB is of
+    //    unknown length, so look for the encrypted VC to find where it ends.
+    let mut encrypted_vc = VC;
+    read_cipher.apply(&mut encrypted_vc);
+    reader.sync(&encrypted_vc, MAX_PAD).await?;
+
+    let mut fields = reader.read_exact(6).await?;
+    read_cipher.apply(&mut fields);
+    let crypto_select = u32::from_be_bytes(fields[0..4].try_into().unwrap());
+    let pad_length = u16::from_be_bytes(fields[4..6].try_into().unwrap()) as usize;
+    if pad_length > MAX_PAD {
+        return Err(mse_error("PadD is too long"));
+    }
+    let mut pad = reader.read_exact(pad_length).await?;
+    read_cipher.apply(&mut pad);
+
+    if crypto_select != CRYPTO_RC4 && crypto_select != CRYPTO_PLAINTEXT
+        || policy.crypto_provide() & crypto_select == 0
+    {
+        return Err(mse_error(&format!(
+            "Peer selected an invalid crypto method {}",
+            crypto_select
+        )));
+    }
+
+    Ok(reader.finish(crypto_select, read_cipher, write_cipher, vec![]))
+}
+
+async fn run_accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(EncryptedStream<S>, Option<[u8; 20]>), PeerProtocolError> {
+    let mut reader = HandshakeReader::new(stream);
+
+    reader.fill(BITTORRENT_PROTOCOL.len()).await?;
+    if reader.buf.starts_with(BITTORRENT_PROTOCOL) {
+        if !policy.allows_plaintext() {
+            return Err(mse_error("Peer tried to connect without encryption"));
+        }
+
+        let buf = std::mem::take(&mut reader.buf);
+        return Ok((EncryptedStream::plaintext(reader.stream, buf), None));
+    }
+
+    // 1. A->B: Ya, PadA
+    let their_key = reader.read_exact(DH_KEY_SIZE).await?;
+
+    // 2. B->A: Yb, PadB
+    let (private_key, public_key) = generate_key_pair();
+    let mut message = public_key;
+    message.extend(random_pad());
+    reader.write_all(&message).await?;
+    let secret = shared_secret(&private_key, &their_key);
+
+    // 3. A->B: HASH('req1', S) comes after PadA, of unknown length
+    reader.sync(&hash(&[b"req1", &secret]), MAX_PAD).await?;
+    let skey_hash = reader.read_exact(20).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let Some(info_hash) = info_hashes
+        .iter()
+        .find(|info_hash| skey_hash == xor(&hash(&[b"req2", *info_hash]), &req3))
+    else {
+        return Err(mse_error("Peer asked for a torrent we don't have"));
+    };
+
+    let mut read_cipher = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut write_cipher = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut fields = reader.read_exact(14).await?;
+    read_cipher.apply(&mut fields);
+    if fields[0..8] != VC {
+        return Err(mse_error("Invalid verification constant"));
+    }
+    let crypto_provide = u32::from_be_bytes(fields[8..12].try_into().unwrap());
+    let pad_length = u16::from_be_bytes(fields[12..14].try_into().unwrap()) as usize;
+    if pad_length > MAX_PAD {
+        return Err(mse_error("PadC is too long"));
+    }
+    let mut pad = reader.read_exact(pad_length).await?;
+    read_cipher.apply(&mut pad);
+
+    let mut initial_payload_length = reader.read_exact(2).await?;
+    read_cipher.apply(&mut initial_payload_length);
+    let initial_payload_length =
+        u16::from_be_bytes(initial_payload_length[0..2].try_into().unwrap()) as usize;
+    let mut initial_payload = reader.read_exact(initial_payload_length).await?;
+    read_cipher.apply(&mut initial_payload);
+
+    let Some(crypto_select) = policy.crypto_select(crypto_provide) else {
+        return Err(mse_error("No crypto method both sides accept"));
+    };
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD)
+    let mut message = VC.to_vec();
+    message.extend_from_slice(&crypto_select.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    write_cipher.apply(&mut message);
+    reader.write_all(&message).await?;
+
+    Ok((
+        reader.finish(crypto_select, read_cipher, write_cipher, initial_payload),
+        Some(*info_hash),
+    ))
+}
+
+/// Buffers reads during the handshake, as syncing on a pattern can read
+/// past the end of the handshake.
+struct HandshakeReader<S> {
+    stream: S,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> HandshakeReader<S> {
+    fn new(stream: S) -> Self {
+        HandshakeReader {
+            stream,
+            buf: vec![],
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> Result<(), PeerProtocolError> {
+        self.stream
+            .write_all(buf)
+            .await
+            .map_err(|e| mse_error(&format!("Failed to write: {}", e)))
+    }
+
+    async fn fill(&mut self, length: usize) -> Result<(), PeerProtocolError> {
+        while self.buf.len() < length {
+            self.buf.reserve(1024);
+            match self.stream.read_buf(&mut self.buf).await {
+                Ok(0) | Err(_) => return Err(PeerProtocolError::ConnectionClosed),
+                Ok(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_exact(&mut self, length: usize) -> Result<Vec<u8>, PeerProtocolError> {
+        self.fill(length).await?;
+        Ok(self.buf.drain(..length).collect())
+    }
+
+    /// Skips up to `max_skip` bytes until just past `pattern`.
+    async fn sync(&mut self, pattern: &[u8], max_skip: usize) -> Result<(), PeerProtocolError> {
+        loop {
+            if let Some(position) = self
+                .buf
+                .windows(pattern.len())
+                .position(|window| window == pattern)
+            {
+                if position > max_skip {
+                    break;
+                }
+                self.buf.drain(..position + pattern.len());
+                return Ok(());
+            }
+
+            if self.buf.len() >= max_skip + pattern.len() {
+                break;
+            }
+            self.fill(self.buf.len() + 1).await?;
+        }
+
+        Err(mse_error("Could not find the end of the padding"))
+    }
+
+    /// Wraps the stream once the handshake is done. Anything read past the
+    /// handshake belongs to the message stream.
+    fn finish(
+        self,
+        crypto_select: u32,
+        mut read_cipher: Rc4,
+        write_cipher: Rc4,
+        mut read_prefix: Vec<u8>,
+    ) -> EncryptedStream<S> {
+        let mut leftover = self.buf;
+        if crypto_select == CRYPTO_RC4 {
+            read_cipher.apply(&mut leftover);
+            read_prefix.extend(leftover);
+            EncryptedStream::encrypted(self.stream, read_cipher, write_cipher, read_prefix)
+        } else {
+            read_prefix.extend(leftover);
+            EncryptedStream::plaintext(self.stream, read_prefix)
+        }
+    }
+}
+
+fn generate_key_pair() -> (BigUint, Vec<u8>) {
+    let private_key = BigUint::from_bytes_be(&rand::rng().random::<[u8; 20]>());
+    let public_key = BigUint::from(DH_GENERATOR).modpow(&private_key, &dh_prime());
+    (private_key, to_key_bytes(&public_key))
+}
+
+fn shared_secret(private_key: &BigUint, their_key: &[u8]) -> Vec<u8> {
+    let their_key = BigUint::from_bytes_be(their_key);
+    to_key_bytes(&their_key.modpow(private_key, &dh_prime()))
+}
+
+fn dh_prime() -> BigUint {
+    BigUint::parse_bytes(DH_PRIME.as_bytes(), 16).unwrap()
+}
+
+/// Left pads a key to the fixed 96 bytes.
+fn to_key_bytes(key: &BigUint) -> Vec<u8> {
+    let bytes = key.to_bytes_be();
+    let mut padded = vec![0; DH_KEY_SIZE - bytes.len()];
+    padded.extend(bytes);
+    padded
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::rng();
+    let length = rng.random_range(0..=MAX_PAD);
+    (0..length).map(|_| rng.random()).collect()
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+fn mse_error(message: &str) -> PeerProtocolError {
+    PeerProtocolError::HandshakeError(message.to_string())
+}
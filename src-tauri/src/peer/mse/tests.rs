@@ -0,0 +1,147 @@
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::{
+    peer::mse::{
+        accept, generate_key_pair, hash, initiate, shared_secret, xor, EncryptionPolicy,
+        CRYPTO_PLAINTEXT, CRYPTO_RC4, DH_KEY_SIZE, VC,
+    },
+    util::encrypted_stream::Rc4,
+};
+
+const INFO_HASH: [u8; 20] = [7; 20];
+const OTHER_INFO_HASH: [u8; 20] = [9; 20];
+const POLICIES: [EncryptionPolicy; 3] = [
+    EncryptionPolicy::PlaintextFallback,
+    EncryptionPolicy::PreferEncrypted,
+    EncryptionPolicy::RequireEncrypted,
+];
+
+/// A plaintext BitTorrent handshake for `INFO_HASH`.
+fn bittorrent_handshake() -> Vec<u8> {
+    let mut handshake = b"\x13BitTorrent protocol".to_vec();
+    handshake.extend([0; 8]);
+    handshake.extend(INFO_HASH);
+    handshake.extend([1; 20]);
+    handshake
+}
+
+/// The initiating side of the handshake done by hand, as `initiate` never
+/// sends an initial payload. Returns the cipher for what we send next.
+async fn initiate_with_payload(stream: &mut DuplexStream, provide: u32, payload: &[u8]) -> Rc4 {
+    let (private_key, public_key) = generate_key_pair();
+    stream.write_all(&public_key).await.unwrap();
+    let mut their_key = [0; DH_KEY_SIZE];
+    stream.read_exact(&mut their_key).await.unwrap();
+    let secret = shared_secret(&private_key, &their_key);
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(
+        &hash(&[b"req2", &INFO_HASH]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let mut encrypted = VC.to_vec();
+    encrypted.extend(provide.to_be_bytes());
+    encrypted.extend(0u16.to_be_bytes());
+    encrypted.extend((payload.len() as u16).to_be_bytes());
+    encrypted.extend(payload);
+    let mut cipher = Rc4::new(&hash(&[b"keyA", &secret, &INFO_HASH]));
+    cipher.apply(&mut encrypted);
+    message.extend(encrypted);
+    stream.write_all(&message).await.unwrap();
+    cipher
+}
+
+#[tokio::test]
+async fn every_policy_pair_connects() {
+    for initiator in POLICIES {
+        for acceptor in POLICIES {
+            let (a, b) = duplex(64 * 1024);
+            let (initiated, accepted) = tokio::join!(
+                initiate(a, &INFO_HASH, initiator),
+                accept(b, &[OTHER_INFO_HASH, INFO_HASH], acceptor),
+            );
+            let mut initiated = initiated.unwrap();
+            let (mut accepted, info_hash) = accepted.unwrap();
+            assert_eq!(info_hash, Some(INFO_HASH));
+
+            // Plaintext only when both sides are fine with it and the
+            // accepting side prefers it
+            let encrypted = initiator == EncryptionPolicy::RequireEncrypted
+                || acceptor != EncryptionPolicy::PlaintextFallback;
+            assert_eq!(initiated.is_encrypted(), encrypted, "{:?}", initiator);
+            assert_eq!(accepted.is_encrypted(), encrypted, "{:?}", acceptor);
+
+            initiated.write_all(&bittorrent_handshake()).await.unwrap();
+            initiated.flush().await.unwrap();
+            let mut received = vec![0; 68];
+            accepted.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, bittorrent_handshake());
+
+            accepted.write_all(b"pong").await.unwrap();
+            accepted.flush().await.unwrap();
+            let mut reply = [0; 4];
+            initiated.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b"pong");
+        }
+    }
+}
+
+#[tokio::test]
+async fn require_encrypted_rejects_plaintext_handshakes() {
+    let (mut a, b) = duplex(64 * 1024);
+    a.write_all(&bittorrent_handshake()).await.unwrap();
+    assert!(accept(b, &[INFO_HASH], EncryptionPolicy::RequireEncrypted)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn plaintext_handshakes_pass_through() {
+    for policy in [
+        EncryptionPolicy::PlaintextFallback,
+        EncryptionPolicy::PreferEncrypted,
+    ] {
+        let (mut a, b) = duplex(64 * 1024);
+        a.write_all(&bittorrent_handshake()).await.unwrap();
+        a.write_all(b"more").await.unwrap();
+
+        // Whatever was read to spot the handshake is read again
+        let (mut accepted, info_hash) = accept(b, &[INFO_HASH], policy).await.unwrap();
+        assert_eq!(info_hash, None);
+        assert!(!accepted.is_encrypted());
+        let mut received = vec![0; 72];
+        accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received[..68], bittorrent_handshake());
+        assert_eq!(&received[68..], b"more");
+    }
+}
+
+#[tokio::test]
+async fn initial_payload_comes_before_the_stream() {
+    for (provide, policy) in [
+        (CRYPTO_RC4, EncryptionPolicy::RequireEncrypted),
+        (
+            CRYPTO_PLAINTEXT | CRYPTO_RC4,
+            EncryptionPolicy::PlaintextFallback,
+        ),
+    ] {
+        let (mut a, b) = duplex(64 * 1024);
+        let payload = bittorrent_handshake();
+        let (mut cipher, accepted) = tokio::join!(
+            initiate_with_payload(&mut a, provide, &payload),
+            accept(b, &[INFO_HASH], policy),
+        );
+        let (mut accepted, info_hash) = accepted.unwrap();
+        assert_eq!(info_hash, Some(INFO_HASH));
+
+        let mut more = b"more".to_vec();
+        if accepted.is_encrypted() {
+            cipher.apply(&mut more);
+        }
+        a.write_all(&more).await.unwrap();
+        let mut received = vec![0; 72];
+        accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received[..68], payload);
+        assert_eq!(&received[68..], b"more");
+    }
+}
@@ -0,0 +1,125 @@
+use rand::{distr::Alphanumeric, Rng};
+
+/// Our Azureus-style client code, as in `-TB0100-`.
+const CLIENT_CODE: &[u8; 2] = b"TB";
+/// Sent as `v` in our extension handshake.
+const CLIENT_VERSION: &str = concat!("bittorrent ", env!("CARGO_PKG_VERSION"));
+
+/// Who we present ourselves as to trackers and peers. Generated once per
+/// session.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIdentity {
+    pub peer_id: [u8; 20],
+    /// Don't tell anyone which client we are.
+    pub anonymous: bool,
+}
+
+impl ClientIdentity {
+    /// An Azureus-style peer ID (`-TB0100-` followed by 12 random
+    /// characters), or a fully random one in anonymous mode.
+    pub fn generate(anonymous: bool) -> Self {
+        let mut peer_id = [0; 20];
+        let random_start = if anonymous {
+            0
+        } else {
+            peer_id[0] = b'-';
+            peer_id[1..3].copy_from_slice(CLIENT_CODE);
+            peer_id[3] = version_char(env!("CARGO_PKG_VERSION_MAJOR"));
+            peer_id[4] = version_char(env!("CARGO_PKG_VERSION_MINOR"));
+            peer_id[5] = version_char(env!("CARGO_PKG_VERSION_PATCH"));
+            peer_id[6] = b'0';
+            peer_id[7] = b'-';
+            8
+        };
+
+        let mut rng = rand::rng();
+        for byte in &mut peer_id[random_start..] {
+            *byte = rng.sample(Alphanumeric);
+        }
+
+        ClientIdentity { peer_id, anonymous }
+    }
+
+    /// Anonymous mode is turned on by setting `ANONYMOUS_MODE` to `1` or
+    /// `true`.
+    pub fn from_env() -> Self {
+        let anonymous = std::env::var("ANONYMOUS_MODE")
+            .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"));
+        Self::generate(anonymous)
+    }
+
+    /// Client name and version for the extension handshake, none in
+    /// anonymous mode.
+    pub fn client_version(&self) -> Option<&'static str> {
+        (!self.anonymous).then_some(CLIENT_VERSION)
+    }
+}
+
+/// One version component as a single character, `0`-`9` then `A`-`Z`.
+fn version_char(component: &str) -> u8 {
+    let value = component.parse::<u32>().unwrap_or(0).min(35);
+    char::from_digit(value, 36).unwrap().to_ascii_uppercase() as u8
+}
+
+/// Azureus-style client codes we can name.
+const KNOWN_CLIENTS: &[(&[u8; 2], &str)] = &[
+    (CLIENT_CODE, "bittorrent"),
+    (b"AZ", "Vuze"),
+    (b"BC", "BitComet"),
+    (b"BI", "BiglyBT"),
+    (b"BT", "BitTorrent"),
+    (b"DE", "Deluge"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libtorrent"),
+    (b"lt", "rTorrent"),
+    (b"qB", "qBittorrent"),
+    (b"TR", "Transmission"),
+    (b"UM", "µTorrent Mac"),
+    (b"UT", "µTorrent"),
+    (b"WW", "WebTorrent"),
+];
+
+/// Works out which client sent `peer_id`, for display.
+pub fn client_name(peer_id: &[u8; 20]) -> String {
+    // Azureus style, `-XX1234-`
+    if peer_id[0] == b'-'
+        && peer_id[7] == b'-'
+        && peer_id[1..7].iter().all(u8::is_ascii_alphanumeric)
+    {
+        let code = &peer_id[1..3];
+        let name = KNOWN_CLIENTS
+            .iter()
+            .find(|(known, _)| known[..] == *code)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| String::from_utf8_lossy(code).into_owned());
+        return format!("{} {}", name, format_version(&peer_id[3..7]));
+    }
+
+    // Mainline style, `M7-4-3--`
+    if peer_id[0] == b'M' {
+        let version = String::from_utf8_lossy(&peer_id[1..8]);
+        let parts: Vec<_> = version.split('-').filter(|part| !part.is_empty()).collect();
+        if parts.len() == 3
+            && parts
+                .iter()
+                .all(|part| part.bytes().all(|b| b.is_ascii_digit()))
+        {
+            return format!("Mainline {}", parts.join("."));
+        }
+    }
+
+    "unknown".to_string()
+}
+
+/// Formats Azureus version characters as a dotted version, dropping
+/// trailing zero components.
+fn format_version(chars: &[u8]) -> String {
+    let mut parts: Vec<String> = chars
+        .iter()
+        .map(|&c| (c as char).to_digit(36).unwrap_or(0).to_string())
+        .collect();
+    while parts.len() > 2 && parts.last().is_some_and(|part| part == "0") {
+        parts.pop();
+    }
+    parts.join(".")
+}
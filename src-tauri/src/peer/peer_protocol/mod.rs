@@ -0,0 +1,1236 @@
+use rand::seq::IteratorRandom;
+// use tauri::http::request;
+
+use crate::{
+    bencoding::torrent::Torrent,
+    connection::Peer,
+    peer::{
+        bandwidth::{Direction, PeerThrottle},
+        extensions::{
+            lt_donthave::{LtDonthave, LT_DONTHAVE},
+            upload_only::UploadOnly,
+            ut_holepunch::{HolepunchMessage, UtHolepunch, HOLEPUNCH_QUEUE_SIZE, UT_HOLEPUNCH},
+            ut_metadata::UtMetadata,
+            ut_pex::UtPex,
+            ExtensionHandler, ExtensionRegistry, EXTENSION_PROTOCOL_BIT, EXTENSION_PROTOCOL_BYTE,
+        },
+        fast_extension::{
+            allowed_fast_set, ALLOWED_FAST_SET_SIZE, FAST_EXTENSION_BIT, FAST_EXTENSION_BYTE,
+        },
+        have::withhold_pieces,
+        peer_id::client_name,
+        transport::{PeerConnection, Transport},
+        types::{
+            BlockReservation, PeerHandshake, PeerMessage, PeerMessageID, PeerState, PieceProgress,
+            TorrentProgress, BLOCK_SIZE, PROTOCOL_STRING,
+        },
+    },
+    util::peer_message_stream::{PeerMessageStream, PeerMessageWriter},
+};
+use std::{
+    collections::{HashSet, VecDeque},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{
+        mpsc::{self, error::TryRecvError},
+        Notify,
+    },
+    time::MissedTickBehavior,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Port we tell peers to reach us on.
+pub const LISTEN_PORT: u16 = 6881;
+/// Reserved bit advertising a DHT node (BEP 5).
+const DHT_BYTE: usize = 7;
+const DHT_BIT: u8 = 0x01;
+
+/// How long a requested block stays reserved for the peer we asked for it.
+/// If the block hasn't arrived by then it's released and the peer is snubbed.
+pub const BLOCK_RESERVATION_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often a session publishes its `PeerStats` and resizes its request queue.
+const STATS_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+/// Messages queued for the writer task before senders have to wait for it.
+const WRITE_QUEUE_SIZE: usize = 64;
+/// Blocks waiting to go out before further requests get rejected, as many
+/// as the `reqq` we give peers.
+const MAX_QUEUED_UPLOADS: usize = 250;
+/// We send a keep-alive when we haven't written anything for this long...
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// ...and drop peers that haven't sent us anything for this long.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+/// Largest block we serve in a single request.
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+/// How many suggested pieces we remember per peer.
+const MAX_SUGGESTED_PIECES: usize = 16;
+/// Failed pieces a peer may send blocks of before it's banned, for when
+/// downloading them again never pins the corruption on anyone.
+const MAX_HASH_FAILURES: u32 = 3;
+
+#[derive(Debug)]
+pub enum PeerProtocolError {
+    FailedToConnect,
+    ConnectionClosed,
+    HandshakeError(String),
+    /// The peer broke the wire protocol, it gets disconnected.
+    InvalidMessage(String),
+    /// We banned the peer for sending corrupt data.
+    Banned,
+    ReceivedError(String),
+}
+
+pub async fn connect_to_peer(
+    peer: &Peer,
+    torrent: &Torrent,
+    transport: &Transport,
+    progress: Arc<RwLock<TorrentProgress>>,
+    completed_pieces: Arc<AtomicU64>,
+) -> Result<(), PeerProtocolError> {
+    let stream = transport.connect(peer, &torrent.info_hash).await?;
+    // println!("{} - Connected", peer);
+
+    run_connection(stream, peer, false, torrent, progress, completed_pieces).await
+}
+
+/// Connects over uTP to a peer a relay introduced us to (BEP 55). The peer
+/// is opening its NAT to us at the same time.
+pub async fn holepunch_peer(
+    peer: &Peer,
+    torrent: &Torrent,
+    transport: &Transport,
+    progress: Arc<RwLock<TorrentProgress>>,
+    completed_pieces: Arc<AtomicU64>,
+) -> Result<(), PeerProtocolError> {
+    let stream = transport.connect_utp(peer, &torrent.info_hash).await?;
+    run_connection(stream, peer, false, torrent, progress, completed_pieces).await
+}
+
+/// Runs a session on a connection the peer opened to us, over TCP or uTP,
+/// once `Transport::accept` found it's for this torrent.
+pub async fn accept_peer(
+    stream: PeerConnection,
+    peer: &Peer,
+    torrent: &Torrent,
+    progress: Arc<RwLock<TorrentProgress>>,
+    completed_pieces: Arc<AtomicU64>,
+) -> Result<(), PeerProtocolError> {
+    run_connection(stream, peer, true, torrent, progress, completed_pieces).await
+}
+
+async fn run_connection(
+    stream: PeerConnection,
+    peer: &Peer,
+    incoming: bool,
+    torrent: &Torrent,
+    progress: Arc<RwLock<TorrentProgress>>,
+    completed_pieces: Arc<AtomicU64>,
+) -> Result<(), PeerProtocolError> {
+    let ip = peer.ip;
+    let session = SessionEnded {
+        peer: peer.to_string(),
+        progress,
+    };
+
+    run_peer_session(
+        stream,
+        &session.peer,
+        &ip,
+        incoming,
+        torrent,
+        &session.progress,
+        completed_pieces,
+    )
+    .await
+}
+
+/// However a session ends, even by panicking, this forgets the peer so no
+/// blocks stay reserved for a peer we are no longer talking to.
+struct SessionEnded {
+    peer: String,
+    progress: Arc<RwLock<TorrentProgress>>,
+}
+
+impl Drop for SessionEnded {
+    fn drop(&mut self) {
+        let peer = &self.peer;
+        let mut progress = self
+            .progress
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        progress.release_peer_reservations(peer);
+        progress.peer_stats.remove(peer);
+        progress.peer_ids.retain(|_, connected| connected != peer);
+        progress.holepunch.peer_closed(peer);
+        progress.choker.peer_closed(peer);
+        if let Some(super_seed) = &mut progress.super_seed {
+            super_seed.withdraw_offer(peer);
+        }
+    }
+}
+
+async fn run_peer_session<S>(
+    stream: S,
+    peer: &str,
+    ip: &IpAddr,
+    incoming: bool,
+    torrent: &Torrent,
+    progress: &Arc<RwLock<TorrentProgress>>,
+    completed_pieces: Arc<AtomicU64>,
+) -> Result<(), PeerProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    // Subscribe before our bitfield is taken, so no completed piece falls
+    // between the two
+    let (mut haves, mut donthaves, have_settings) = {
+        let progress = progress.read().unwrap();
+        (
+            progress.have_tx.subscribe(),
+            progress.donthave_tx.subscribe(),
+            progress.have_settings,
+        )
+    };
+
+    let mut peer_message_stream = PeerMessageStream::new(Box::pin(stream));
+    let mut peer_state = handle_handshake(
+        torrent,
+        progress,
+        &mut peer_message_stream,
+        peer.to_string(),
+        ip,
+    )
+    .await?;
+    peer_state.is_incoming = incoming;
+
+    // Other sessions hand us holepunch messages for our peer through here
+    let (holepunch_tx, mut holepunch_rx) = mpsc::channel(HOLEPUNCH_QUEUE_SIZE);
+    let mut extensions = ExtensionRegistry::new(default_extensions(
+        torrent,
+        &progress.read().unwrap(),
+        holepunch_tx,
+    ));
+    if peer_state.supports_extensions {
+        let client_version = progress.read().unwrap().identity.client_version();
+        peer_message_stream
+            .write_all(&Vec::from(&extensions.create_handshake(
+                LISTEN_PORT,
+                ip,
+                client_version,
+            )))
+            .await
+            .map_err(|_| {
+                PeerProtocolError::HandshakeError("Failed to send extension handshake".to_string())
+            })?;
+    }
+
+    let (mut reader, writer) = peer_message_stream.split();
+    let throttle = {
+        let progress = progress.read().unwrap();
+        Arc::new(PeerThrottle::new(
+            progress.bandwidth.clone(),
+            progress.rate_limit.clone(),
+            ip,
+        ))
+    };
+
+    // All writes go through a bounded queue drained by a dedicated task, so a
+    // slow peer applies back-pressure to this session rather than to the
+    // whole runtime
+    let (outgoing, outgoing_rx) = mpsc::channel(WRITE_QUEUE_SIZE);
+    let uploads = Arc::new(UploadQueue::default());
+    let writer_task = tokio::spawn(write_messages(
+        writer,
+        outgoing_rx,
+        uploads.clone(),
+        throttle.clone(),
+    ));
+
+    let result = async {
+        let num_pieces = torrent.info.pieces.len() as u32;
+        let mut ticker = tokio::time::interval(STATS_UPDATE_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_message_at = Instant::now();
+
+        // Completed pieces wait here until the next flush when batching
+        let mut batched_haves = vec![];
+        let mut have_flush = tokio::time::interval(
+            have_settings
+                .batch_interval
+                .unwrap_or(STATS_UPDATE_INTERVAL),
+        );
+        have_flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                message = reader.read_message() => {
+                    // Holding off on the next read is what slows the peer down
+                    let message = message?;
+                    throttle.wait(Direction::Download, &message).await;
+                    let replies = handle_message(
+                        &message,
+                        &mut peer_state,
+                        &mut extensions,
+                        torrent,
+                        progress.clone(),
+                        completed_pieces.clone(),
+                        &uploads,
+                    )?;
+                    last_message_at = Instant::now();
+
+                    for reply in replies {
+                        send(&outgoing, reply).await?;
+                    }
+                }
+                Ok(index) = haves.recv() => {
+                    // No point telling the peer about pieces it already has
+                    if !bitfield_contains_piece(&peer_state.bitfield, index) {
+                        if have_settings.batch_interval.is_some() {
+                            batched_haves.push(index);
+                        } else {
+                            send(&outgoing, PeerMessage::create_have(index)).await?;
+                        }
+                    }
+                    if let Some(message) = update_interest(&mut peer_state, torrent, progress) {
+                        send(&outgoing, message).await?;
+                    }
+                }
+                Ok(index) = donthaves.recv() => {
+                    batched_haves.retain(|&i| i != index);
+                    let payload = index.to_be_bytes().to_vec();
+                    if let Some(message) = extensions.create_message(LT_DONTHAVE, payload) {
+                        send(&outgoing, message).await?;
+                    }
+                    if let Some(message) = update_interest(&mut peer_state, torrent, progress) {
+                        send(&outgoing, message).await?;
+                    }
+                }
+                Some(message) = holepunch_rx.recv() => {
+                    let payload = Vec::from(&message);
+                    if let Some(message) = extensions.create_message(UT_HOLEPUNCH, payload) {
+                        send(&outgoing, message).await?;
+                    }
+                }
+                _ = have_flush.tick(), if have_settings.batch_interval.is_some() => {
+                    for index in batched_haves.drain(..) {
+                        send(&outgoing, PeerMessage::create_have(index)).await?;
+                    }
+                }
+                _ = ticker.tick() => {
+                    let now = Instant::now();
+                    if now.duration_since(last_message_at) >= PEER_IDLE_TIMEOUT {
+                        return Err(PeerProtocolError::ConnectionClosed);
+                    }
+
+                    for cancel in expire_timed_out_requests(&mut peer_state, progress) {
+                        send(&outgoing, cancel).await?;
+                    }
+
+                    for message in extensions.tick(&mut peer_state, torrent, progress) {
+                        send(&outgoing, message).await?;
+                    }
+                    if let Some(have) = reveal_next_piece(&mut peer_state, progress) {
+                        send(&outgoing, have).await?;
+                    }
+                    if let Some(message) = update_choke(&mut peer_state, progress, now) {
+                        send(&outgoing, message).await?;
+                    }
+
+                    peer_state.update_request_queue_depth(now);
+                    let mut progress = progress.write().unwrap();
+                    let hash_failures =
+                        progress.hash_failures.get(&peer_state.peer).copied().unwrap_or(0);
+                    let stats = peer_state.stats(num_pieces, hash_failures);
+                    progress.peer_stats.insert(peer_state.peer.clone(), stats);
+                }
+            }
+
+            // The ban may have come from another session finishing a piece
+            if progress.read().unwrap().banned_ips.contains(ip) {
+                return Err(PeerProtocolError::Banned);
+            }
+
+            // Neither side wants anything from the other
+            if (peer_state.upload_only || peer_state.has_all_pieces(num_pieces))
+                && progress.read().unwrap().is_upload_only()
+            {
+                println!("{} - Closing upload-only connection", peer_state.peer);
+                break;
+            }
+
+            for request in reserve_blocks(&mut peer_state, torrent, progress) {
+                send(&outgoing, request).await?;
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+
+    // Dropping the sender lets the writer flush what's queued and close
+    drop(outgoing);
+    let _ = writer_task.await;
+
+    result
+}
+
+/// The extensions we offer on every connection for this torrent.
+fn default_extensions(
+    torrent: &Torrent,
+    progress: &TorrentProgress,
+    holepunch: mpsc::Sender<HolepunchMessage>,
+) -> Vec<Box<dyn ExtensionHandler>> {
+    let mut extensions: Vec<Box<dyn ExtensionHandler>> = vec![
+        Box::new(UtMetadata::new(torrent.info_bytes.len())),
+        Box::new(UploadOnly::new(
+            progress.is_upload_only(),
+            progress.completed_bytes(),
+        )),
+        Box::new(LtDonthave),
+    ];
+    // Private torrents only get peers from their trackers
+    if !torrent.info.private {
+        extensions.push(Box::new(UtPex::new()));
+        extensions.push(Box::new(UtHolepunch::new(holepunch)));
+    }
+    extensions
+}
+
+async fn send(
+    outgoing: &mpsc::Sender<PeerMessage>,
+    message: PeerMessage,
+) -> Result<(), PeerProtocolError> {
+    outgoing
+        .send(message)
+        .await
+        .map_err(|_| PeerProtocolError::ConnectionClosed)
+}
+
+/// Blocks we agreed to send that the writer hasn't picked up yet. Until it
+/// does, a Cancel from the peer can still take them back.
+#[derive(Default)]
+struct UploadQueue {
+    blocks: Mutex<VecDeque<PeerMessage>>,
+    ready: Notify,
+}
+
+impl UploadQueue {
+    fn push(&self, piece: PeerMessage) {
+        self.blocks.lock().unwrap().push_back(piece);
+        self.ready.notify_one();
+    }
+
+    fn pop(&self) -> Option<PeerMessage> {
+        self.blocks.lock().unwrap().pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.lock().unwrap().len()
+    }
+
+    /// Drops the queued block a Cancel names, returning whether it was
+    /// still there.
+    fn cancel(&self, index: u32, begin: u32, length: u32) -> bool {
+        let mut blocks = self.blocks.lock().unwrap();
+        let Some(position) = blocks.iter().position(|piece| {
+            piece.payload[0..4] == index.to_be_bytes()
+                && piece.payload[4..8] == begin.to_be_bytes()
+                && piece.payload.len() - 8 == length as usize
+        }) else {
+            return false;
+        };
+        blocks.remove(position);
+        true
+    }
+}
+
+/// Drains the session's outgoing queue, then the blocks waiting in
+/// `uploads`, onto the socket at the pace the throttle allows, filling quiet
+/// periods with keep-alives.
+async fn write_messages<W: AsyncWrite + Unpin>(
+    mut writer: PeerMessageWriter<W>,
+    mut outgoing: mpsc::Receiver<PeerMessage>,
+    uploads: Arc<UploadQueue>,
+    throttle: Arc<PeerThrottle>,
+) {
+    loop {
+        let message = match outgoing.try_recv() {
+            Ok(message) => message,
+            Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => match uploads.pop() {
+                Some(piece) => piece,
+                None => tokio::select! {
+                    message = outgoing.recv() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                    _ = uploads.ready.notified() => continue,
+                    _ = tokio::time::sleep(KEEP_ALIVE_INTERVAL) => PeerMessage::create_keep_alive(),
+                },
+            },
+        };
+
+        throttle.wait(Direction::Upload, &message).await;
+        if writer.write_message(&message).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = writer.shutdown().await;
+}
+
+/// Picks blocks to request from the peer and reserves them, returning the
+/// request messages to send.
+fn reserve_blocks(
+    peer_state: &mut PeerState,
+    torrent: &Torrent,
+    progress: &Arc<RwLock<TorrentProgress>>,
+) -> Vec<PeerMessage> {
+    let mut requests = vec![];
+    if peer_state.bitfield.is_empty()
+        || (peer_state.is_choked && peer_state.allowed_fast.is_empty())
+    {
+        return requests;
+    }
+
+    let max_inflight = peer_state.max_inflight();
+    if peer_state.inflight() >= max_inflight {
+        return requests;
+    }
+
+    let mut torrent_progress = progress.write().unwrap();
+    let completed_pieces: HashSet<u32> = torrent_progress
+        .pieces
+        .iter()
+        .filter(|(_, v)| matches!(v, PieceProgress::Completed(_)))
+        .map(|(k, _)| *k)
+        .collect();
+
+    // Pieces finished through other peers don't need anything from this one
+    peer_state
+        .requested_pieces
+        .retain(|i| !completed_pieces.contains(i));
+
+    let needed_pieces: Vec<u32> = (0..torrent.info.pieces.len() as u32)
+        .filter(|&i| bitfield_contains_piece(&peer_state.bitfield, i))
+        .filter(|&i| !completed_pieces.contains(&i))
+        .filter(|&i| torrent_progress.is_wanted(i))
+        .filter(|&i| peer_state.can_request_piece(i))
+        .collect();
+    peer_state
+        .suggested_pieces
+        .retain(|i| needed_pieces.contains(i));
+
+    // Keep the pipeline full: request blocks from the pieces we're already
+    // working on and pull in more pieces until the queue depth is reached
+    let now = Instant::now();
+    let mut piece_cursor = 0;
+    while peer_state.inflight() < max_inflight {
+        if piece_cursor == peer_state.requested_pieces.len() {
+            // Every piece holds at least one block, so more pieces than
+            // queue slots can't help
+            if piece_cursor >= max_inflight as usize {
+                break;
+            }
+
+            // Go with the peer's suggestions first, they're likely to be in
+            // its cache
+            let next_piece = peer_state
+                .suggested_pieces
+                .iter()
+                .find(|i| !peer_state.requested_pieces.contains(i))
+                .or_else(|| {
+                    needed_pieces
+                        .iter()
+                        .filter(|i| !peer_state.requested_pieces.contains(i))
+                        .choose(&mut rand::rng())
+                });
+            match next_piece {
+                Some(&piece_index) => peer_state.requested_pieces.push(piece_index),
+                None => break,
+            }
+        }
+
+        let piece_index = peer_state.requested_pieces[piece_cursor];
+        piece_cursor += 1;
+        if !peer_state.can_request_piece(piece_index) {
+            continue;
+        }
+
+        if let Some(PieceProgress::InProgress(piece_progress)) =
+            torrent_progress.pieces.get_mut(&piece_index)
+        {
+            let mut start = 0;
+            while start < torrent.get_piece_length(piece_index as usize)
+                && peer_state.inflight() < max_inflight
+            {
+                if !piece_progress.can_request_block(start, &peer_state.peer, now) {
+                    start += BLOCK_SIZE;
+                    continue;
+                }
+                let block_progress = piece_progress.data.get_mut(&start).unwrap();
+
+                requests.push(PeerMessage::create_request(
+                    piece_index,
+                    start,
+                    block_progress.length,
+                ));
+
+                // Reserve the block for this peer until the lease runs out
+                block_progress.reservation = Some(BlockReservation {
+                    peer: peer_state.peer.clone(),
+                    expires_at: now + BLOCK_RESERVATION_TIMEOUT,
+                });
+
+                peer_state
+                    .pending_requests
+                    .insert((piece_index, start), now);
+                start += BLOCK_SIZE;
+            }
+        }
+    }
+
+    requests
+}
+
+/// Gives up on requests the peer has been sitting on for too long. The blocks
+/// are released so other peers can pick them up, and the peer is snubbed until
+/// it sends us data again. Returns the cancel messages to send.
+fn expire_timed_out_requests(
+    peer_state: &mut PeerState,
+    progress: &Arc<RwLock<TorrentProgress>>,
+) -> Vec<PeerMessage> {
+    let now = Instant::now();
+    let timed_out: Vec<(u32, u32)> = peer_state
+        .pending_requests
+        .iter()
+        .filter(|(_, sent_at)| now.duration_since(**sent_at) >= BLOCK_RESERVATION_TIMEOUT)
+        .map(|(key, _)| *key)
+        .collect();
+
+    if timed_out.is_empty() {
+        return vec![];
+    }
+
+    if !peer_state.is_snubbed {
+        println!(
+            "{} - Snubbed after {} requests timed out",
+            peer_state.peer,
+            timed_out.len()
+        );
+    }
+    peer_state.is_snubbed = true;
+
+    let mut cancels = vec![];
+    let mut progress = progress.write().unwrap();
+    for (index, begin) in timed_out {
+        peer_state.pending_requests.remove(&(index, begin));
+
+        let length = match progress.pieces.get(&index) {
+            Some(PieceProgress::InProgress(piece_progress)) => piece_progress
+                .data
+                .get(&begin)
+                .map_or(BLOCK_SIZE, |block| block.length),
+            _ => continue,
+        };
+        progress.release_block(&peer_state.peer, index, begin);
+
+        cancels.push(PeerMessage::create_cancel(index, begin, length));
+    }
+
+    cancels
+}
+
+async fn handle_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    torrent: &Torrent,
+    progress: &Arc<RwLock<TorrentProgress>>,
+    peer_message_stream: &mut PeerMessageStream<S>,
+    peer: String,
+    ip: &IpAddr,
+) -> Result<PeerState, PeerProtocolError> {
+    let mut reserved = [0; 8];
+    reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+    reserved[FAST_EXTENSION_BYTE] |= FAST_EXTENSION_BIT;
+    let our_peer_id = progress.read().unwrap().identity.peer_id;
+    let handshake_request = PeerHandshake {
+        pstr: PROTOCOL_STRING.to_owned(),
+        reserved,
+        info_hash: torrent.info_hash,
+        peer_id: our_peer_id,
+    };
+    let handshake_bytes = Vec::from(&handshake_request);
+    // println!("{} - Sending handshake: {:?}", peer, handshake_bytes);
+    peer_message_stream
+        .write_all(&handshake_bytes)
+        .await
+        .map_err(|_| PeerProtocolError::HandshakeError("Failed to send handshake".to_string()))?;
+    let mut response_buf = [0; 68];
+    peer_message_stream
+        .read_exact(&mut response_buf)
+        .await
+        .map_err(|e| {
+            PeerProtocolError::HandshakeError(format!("Failed to read handshake response: {}", e))
+        })?;
+    let handshake_response =
+        PeerHandshake::try_from(response_buf).map_err(PeerProtocolError::HandshakeError)?;
+    if handshake_response.info_hash != torrent.info_hash {
+        return Err(PeerProtocolError::HandshakeError(
+            "Peer sent a handshake for a different torrent".to_string(),
+        ));
+    }
+    if handshake_response.peer_id == our_peer_id {
+        return Err(PeerProtocolError::HandshakeError(
+            "Connected to ourselves".to_string(),
+        ));
+    }
+    {
+        let mut progress = progress.write().unwrap();
+        if let Some(existing) = progress.peer_ids.get(&handshake_response.peer_id) {
+            return Err(PeerProtocolError::HandshakeError(format!(
+                "Already connected to this peer as {}",
+                existing
+            )));
+        }
+        progress
+            .peer_ids
+            .insert(handshake_response.peer_id, peer.clone());
+    }
+    // println!(
+    //     "{} - Received handshake response: {:?}",
+    //     peer, handshake_response
+    // );
+
+    let num_pieces = torrent.info.pieces.len();
+    let num_bitfield_bytes = num_pieces.div_ceil(8);
+    let mut peer_state = PeerState::new(peer, num_bitfield_bytes);
+    peer_state.supports_fast =
+        handshake_response.reserved[FAST_EXTENSION_BYTE] & FAST_EXTENSION_BIT != 0;
+    peer_state.supports_extensions =
+        handshake_response.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0;
+    peer_state.supports_dht = handshake_response.reserved[DHT_BYTE] & DHT_BIT != 0;
+    peer_state.client = client_name(&handshake_response.peer_id);
+
+    let mut bitfield_payload = vec![0; num_bitfield_bytes];
+    let mut num_completed = 0;
+    {
+        let progress = progress.read().unwrap();
+        for i in 0..num_pieces {
+            let byte_index = i / 8;
+            let bit_index = 7 - (i % 8);
+            if let PieceProgress::Completed(_) = progress.pieces.get(&(i as u32)).unwrap() {
+                bitfield_payload[byte_index] |= 1 << bit_index;
+                num_completed += 1;
+            }
+        }
+    }
+
+    // A super-seed starts out looking like a peer with nothing
+    let have_settings = progress.read().unwrap().have_settings;
+    let super_seeding = have_settings.super_seed && num_completed == num_pieces;
+    if super_seeding {
+        peer_state.revealed_pieces = Some(HashSet::new());
+    }
+
+    // A lazy bitfield leaves a few pieces out and sends them as `Have`s
+    let mut lazy_bitfield = bitfield_payload.clone();
+    let withheld = if have_settings.lazy_bitfield && !super_seeding {
+        withhold_pieces(&mut lazy_bitfield)
+    } else {
+        vec![]
+    };
+
+    // With the Fast Extension an empty or full bitfield can be sent as a
+    // single byte instead
+    let bitfield_message = if super_seeding {
+        peer_state.supports_fast.then(PeerMessage::create_have_none)
+    } else if !withheld.is_empty() {
+        Some(PeerMessage::create_bitfield(lazy_bitfield))
+    } else if peer_state.supports_fast && num_completed == num_pieces {
+        Some(PeerMessage::create_have_all())
+    } else if peer_state.supports_fast && num_completed == 0 {
+        Some(PeerMessage::create_have_none())
+    } else {
+        Some(PeerMessage::create_bitfield(bitfield_payload.clone()))
+    };
+    let mut bitfield_bytes = bitfield_message
+        .map(|message| Vec::from(&message))
+        .unwrap_or_default();
+    for piece_index in withheld {
+        bitfield_bytes.extend_from_slice(&Vec::from(&PeerMessage::create_have(piece_index)));
+    }
+    peer_message_stream
+        .write_all(&bitfield_bytes)
+        .await
+        .map_err(|_| {
+            PeerProtocolError::HandshakeError("Failed to send bitfield message".to_string())
+        })?;
+
+    // Pieces only go out one at a time when super-seeding
+    if peer_state.supports_fast && !super_seeding {
+        peer_state.our_allowed_fast = allowed_fast_set(
+            ip,
+            &torrent.info_hash,
+            num_pieces as u32,
+            ALLOWED_FAST_SET_SIZE,
+        );
+
+        // Only advertise the pieces we can actually serve
+        let mut allowed_fast_bytes = vec![];
+        for &piece_index in &peer_state.our_allowed_fast {
+            if bitfield_contains_piece(&bitfield_payload, piece_index) {
+                let message = PeerMessage::create_allowed_fast(piece_index);
+                allowed_fast_bytes.extend_from_slice(&Vec::from(&message));
+            }
+        }
+        peer_message_stream
+            .write_all(&allowed_fast_bytes)
+            .await
+            .map_err(|_| {
+                PeerProtocolError::HandshakeError("Failed to send allowed fast set".to_string())
+            })?;
+    }
+
+    Ok(peer_state)
+}
+
+fn write_piece_to_file(progress: &TorrentProgress, piece_index: u32) {
+    let data = progress.pieces[&piece_index]
+        .get_final_data()
+        .unwrap()
+        .unwrap();
+    progress.disk.write(progress.piece_path(piece_index), data);
+}
+
+/// Applies a message from the peer to our state, returning any replies to send.
+/// Payload sizes have already been checked by the reader.
+fn handle_message(
+    message: &PeerMessage,
+    peer_state: &mut PeerState,
+    extensions: &mut ExtensionRegistry,
+    torrent: &Torrent,
+    progress: Arc<RwLock<TorrentProgress>>,
+    completed_pieces: Arc<AtomicU64>,
+    uploads: &UploadQueue,
+) -> Result<Vec<PeerMessage>, PeerProtocolError> {
+    // println!("Message ID: {:?}, Length: {}", message.id, message.length);
+    let mut replies = vec![];
+
+    match message.id {
+        PeerMessageID::KeepAlive => {
+            // println!("Received keep-alive message");
+        }
+        PeerMessageID::Choke => {
+            // println!("{} - Choked us", peer_state.peer);
+            peer_state.is_choked = true;
+
+            // A choke discards all of our pending requests, so hand the
+            // blocks back for other peers to pick up. With the Fast Extension
+            // the peer rejects each dropped request explicitly instead.
+            if !peer_state.supports_fast {
+                peer_state.pending_requests.clear();
+                progress
+                    .write()
+                    .unwrap()
+                    .release_peer_reservations(&peer_state.peer);
+            }
+        }
+        PeerMessageID::Unchoke => {
+            // println!("{} - Unchoked us", peer_state.peer);
+            peer_state.is_choked = false;
+        }
+        PeerMessageID::Interested => {
+            // println!("{} - Is interested", peer_state.peer);
+            // The choker decides on the next tick whether it gets a slot
+            peer_state.peer_interested = true;
+        }
+        PeerMessageID::NotInterested => {
+            // println!("{} - Is not interested", peer_state.peer);
+            peer_state.peer_interested = false;
+        }
+        PeerMessageID::Have => {
+            let piece_index = u32::from_be_bytes(message.payload[0..4].try_into().unwrap());
+            // println!("{} - Has piece index: {}", peer_state.peer, piece_index);
+
+            let byte_index = (piece_index / 8) as usize;
+            let bit_index = 7 - (piece_index % 8);
+            if byte_index < peer_state.bitfield.len() {
+                peer_state.bitfield[byte_index] |= 1 << bit_index;
+            }
+            if peer_state.revealed_pieces.is_some() {
+                if let Some(super_seed) = &mut progress.write().unwrap().super_seed {
+                    super_seed.peer_has(&peer_state.peer, piece_index);
+                }
+            }
+            replies.extend(update_interest(peer_state, torrent, &progress));
+        }
+        PeerMessageID::Bitfield => {
+            // println!(
+            //     "{} - Received bitfield: {:?}",
+            //     peer_state.peer, message.payload
+            // );
+            let expected_length = torrent.info.pieces.len().div_ceil(8);
+            if message.payload.len() != expected_length {
+                return Err(PeerProtocolError::InvalidMessage(format!(
+                    "Bitfield of {} bytes, expected {}",
+                    message.payload.len(),
+                    expected_length
+                )));
+            }
+            peer_state.bitfield = message.payload.clone();
+            if peer_state.revealed_pieces.is_some() {
+                if let Some(super_seed) = &mut progress.write().unwrap().super_seed {
+                    for piece_index in 0..torrent.info.pieces.len() as u32 {
+                        if bitfield_contains_piece(&peer_state.bitfield, piece_index) {
+                            super_seed.peer_has(&peer_state.peer, piece_index);
+                        }
+                    }
+                    // We may have offered a piece before hearing the peer
+                    // already had it
+                    super_seed.withdraw_offer(&peer_state.peer);
+                }
+            }
+            replies.extend(update_interest(peer_state, torrent, &progress));
+        }
+        PeerMessageID::Request => {
+            let index = u32::from_be_bytes(message.payload[0..4].try_into().unwrap());
+            let begin = u32::from_be_bytes(message.payload[4..8].try_into().unwrap());
+            let length = u32::from_be_bytes(message.payload[8..12].try_into().unwrap());
+            // println!(
+            //     "Peer requested piece index: {}, begin: {}, length: {}",
+            //     index, begin, length
+            // );
+            let reply = match uploads.len() < MAX_QUEUED_UPLOADS {
+                true => serve_request(peer_state, &progress, index, begin, length),
+                false => peer_state
+                    .supports_fast
+                    .then(|| PeerMessage::create_reject(index, begin, length)),
+            };
+            // Blocks wait in the upload queue, where a Cancel can still reach them
+            match reply {
+                Some(
+                    piece @ PeerMessage {
+                        id: PeerMessageID::Piece,
+                        ..
+                    },
+                ) => uploads.push(piece),
+                reply => replies.extend(reply),
+            }
+        }
+        PeerMessageID::Piece => {
+            let index = u32::from_be_bytes(message.payload[0..4].try_into().unwrap());
+            let begin = u32::from_be_bytes(message.payload[4..8].try_into().unwrap());
+            let block = &message.payload[8..];
+            // println!(
+            //     "{} - Received piece index: {}, begin: {}, block length: {}",
+            //     peer_state.peer,
+            //     index,
+            //     begin,
+            //     block.len()
+            // );
+            let sent_at = peer_state.pending_requests.remove(&(index, begin));
+            peer_state.record_block(block.len(), sent_at, Instant::now());
+            if sent_at.is_some() {
+                peer_state.is_snubbed = false;
+            }
+
+            let completed = receive_block(
+                &mut progress.write().unwrap(),
+                index,
+                begin,
+                block,
+                &peer_state.peer,
+                sent_at.is_some(),
+                &completed_pieces,
+            );
+            if completed {
+                peer_state.requested_pieces.retain(|&i| i != index);
+            }
+        }
+        PeerMessageID::Cancel => {
+            let index = u32::from_be_bytes(message.payload[0..4].try_into().unwrap());
+            let begin = u32::from_be_bytes(message.payload[4..8].try_into().unwrap());
+            let length = u32::from_be_bytes(message.payload[8..12].try_into().unwrap());
+            // A block already on its way can't be taken back. With the Fast
+            // Extension every request gets an answer, so one we dropped is
+            // rejected (BEP 6)
+            if uploads.cancel(index, begin, length) {
+                let progress = progress.read().unwrap();
+                progress.uploaded.fetch_sub(length as u64, SeqCst);
+                if peer_state.supports_fast {
+                    replies.push(PeerMessage::create_reject(index, begin, length));
+                }
+            }
+        }
+        PeerMessageID::Port => {
+            // We don't add peers' DHT nodes to our routing table
+        }
+        PeerMessageID::SuggestPiece => {
+            let piece_index = u32::from_be_bytes(message.payload[0..4].try_into().unwrap());
+            if !peer_state.suggested_pieces.contains(&piece_index)
+                && peer_state.suggested_pieces.len() < MAX_SUGGESTED_PIECES
+            {
+                peer_state.suggested_pieces.push(piece_index);
+            }
+        }
+        PeerMessageID::HaveAll => {
+            let num_pieces = torrent.info.pieces.len();
+            peer_state.bitfield = vec![0; num_pieces.div_ceil(8)];
+            for piece_index in 0..num_pieces {
+                peer_state.bitfield[piece_index / 8] |= 1 << (7 - (piece_index % 8));
+            }
+            replies.extend(update_interest(peer_state, torrent, &progress));
+        }
+        PeerMessageID::HaveNone => {
+            peer_state.bitfield = vec![0; torrent.info.pieces.len().div_ceil(8)];
+            replies.extend(update_interest(peer_state, torrent, &progress));
+        }
+        PeerMessageID::RejectRequest => {
+            let index = u32::from_be_bytes(message.payload[0..4].try_into().unwrap());
+            let begin = u32::from_be_bytes(message.payload[4..8].try_into().unwrap());
+
+            // The block won't come from this peer, let someone else have it
+            if peer_state
+                .pending_requests
+                .remove(&(index, begin))
+                .is_some()
+            {
+                progress
+                    .write()
+                    .unwrap()
+                    .release_block(&peer_state.peer, index, begin);
+            }
+        }
+        PeerMessageID::AllowedFast => {
+            let piece_index = u32::from_be_bytes(message.payload[0..4].try_into().unwrap());
+            if (piece_index as usize) < torrent.info.pieces.len() {
+                peer_state.allowed_fast.insert(piece_index);
+            }
+        }
+        PeerMessageID::Extended => {
+            // println!("Received extension message");
+            replies.extend(extensions.handle_message(
+                &message.payload,
+                peer_state,
+                torrent,
+                &progress,
+            ));
+            // The peer may have retracted a piece we wanted from it
+            replies.extend(update_interest(peer_state, torrent, &progress));
+        }
+    }
+
+    Ok(replies)
+}
+
+/// Stores a block `sender` delivered, checking the piece's hash once it's
+/// whole. Only blocks we asked the sender for, going by `requested` or its
+/// reservation, are taken, and only while we don't have them. Returns
+/// whether that completed the piece.
+pub fn receive_block(
+    progress: &mut TorrentProgress,
+    index: u32,
+    begin: u32,
+    block: &[u8],
+    sender: &str,
+    requested: bool,
+    completed_pieces: &AtomicU64,
+) -> bool {
+    let mut failed_senders = None;
+    let final_data = if let Some(PieceProgress::InProgress(piece_progress)) =
+        progress.pieces.get_mut(&index)
+    {
+        // Anything else, including data that doesn't line up with one of
+        // the piece's blocks, is dropped
+        let Some(block_progress) = piece_progress
+            .data
+            .get_mut(&begin)
+            .filter(|block_progress| {
+                block_progress.length == block.len() as u32
+                    && block_progress.data.is_none()
+                    && (requested
+                        || block_progress
+                            .reservation
+                            .as_ref()
+                            .is_some_and(|reservation| reservation.peer == sender))
+            })
+        else {
+            return false;
+        };
+        block_progress.reservation = None;
+        block_progress.data = Some(block.to_vec());
+        block_progress.sender = Some(sender.to_string());
+        progress.downloaded.fetch_add(block.len() as u64, SeqCst);
+
+        match piece_progress.get_final_data() {
+            Ok(Some(data)) => Some((data, piece_progress.corrupt_senders())),
+            Ok(None) => None,
+            Err(e) => {
+                println!(
+                    "Error validating piece {}: {}, downloading it again",
+                    index, e
+                );
+                failed_senders = Some(piece_progress.senders());
+                piece_progress.fail(Instant::now());
+                None
+            }
+        }
+    } else {
+        println!(
+            "Received piece data for index {} that is not in progress",
+            index
+        );
+        None
+    };
+
+    // One failure isn't proof, a block may have come from someone else
+    for sender in failed_senders.unwrap_or_default() {
+        let failures = progress.hash_failures.entry(sender.clone()).or_default();
+        *failures += 1;
+        if *failures >= MAX_HASH_FAILURES {
+            progress.ban(&sender);
+        }
+    }
+
+    if let Some((data, corrupt_senders)) = final_data {
+        // Blocks from the failed attempt that differ from the good data give
+        // away who sent them
+        for sender in corrupt_senders {
+            progress.ban(&sender);
+        }
+        // println!("Completed piece index: {}, writing to file", index);
+        progress
+            .pieces
+            .insert(index, PieceProgress::Completed(data));
+        write_piece_to_file(progress, index);
+        completed_pieces.fetch_add(1, SeqCst);
+        // Every session, the sender's included, announces it to its peer
+        let _ = progress.have_tx.send(index);
+        return true;
+    }
+
+    false
+}
+
+/// Tells the peer whether it has anything we still need, if that changed since
+/// we last told it.
+fn update_interest(
+    peer_state: &mut PeerState,
+    torrent: &Torrent,
+    progress: &Arc<RwLock<TorrentProgress>>,
+) -> Option<PeerMessage> {
+    let interested = {
+        let progress = progress.read().unwrap();
+        (0..torrent.info.pieces.len() as u32).any(|i| {
+            bitfield_contains_piece(&peer_state.bitfield, i)
+                && progress.is_wanted(i)
+                && !matches!(progress.pieces.get(&i), Some(PieceProgress::Completed(_)))
+        })
+    };
+    if interested == peer_state.am_interested {
+        return None;
+    }
+
+    peer_state.am_interested = interested;
+    Some(if interested {
+        PeerMessage::create_interested()
+    } else {
+        PeerMessage::create_not_interested()
+    })
+}
+
+/// Answers a block request with the data, or with a reject when the Fast
+/// Extension is on and we won't serve it.
+fn serve_request(
+    peer_state: &PeerState,
+    progress: &Arc<RwLock<TorrentProgress>>,
+    index: u32,
+    begin: u32,
+    length: u32,
+) -> Option<PeerMessage> {
+    let allowed = (!peer_state.am_choking || peer_state.our_allowed_fast.contains(&index))
+        && peer_state
+            .revealed_pieces
+            .as_ref()
+            .is_none_or(|revealed| revealed.contains(&index));
+    let piece = if allowed && length <= MAX_REQUEST_LENGTH {
+        let progress = progress.read().unwrap();
+        match progress.pieces.get(&index) {
+            Some(PieceProgress::Completed(data)) => data
+                .get(begin as usize..begin as usize + length as usize)
+                .map(|block| {
+                    progress.uploaded.fetch_add(block.len() as u64, SeqCst);
+                    PeerMessage::create_piece(index, begin, block)
+                }),
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    match piece {
+        Some(piece) => Some(piece),
+        None if peer_state.supports_fast => Some(PeerMessage::create_reject(index, begin, length)),
+        None => None,
+    }
+}
+
+/// Chokes or unchokes the peer as the choker decided, running its round if
+/// one is due.
+fn update_choke(
+    peer_state: &mut PeerState,
+    progress: &Arc<RwLock<TorrentProgress>>,
+    now: Instant,
+) -> Option<PeerMessage> {
+    let mut progress = progress.write().unwrap();
+    let seeding = progress.is_upload_only();
+    let progress = &mut *progress;
+    progress
+        .choker
+        .run_round(&progress.peer_stats, seeding, now);
+    let unchoked = progress
+        .choker
+        .is_unchoked(&peer_state.peer, peer_state.peer_interested);
+    if unchoked != peer_state.am_choking {
+        return None;
+    }
+    peer_state.am_choking = !unchoked;
+    Some(match unchoked {
+        true => PeerMessage::create_unchoke(),
+        false => PeerMessage::create_choke(),
+    })
+}
+
+/// While super-seeding, reveals the peer its next piece once the last one
+/// has spread to someone else.
+fn reveal_next_piece(
+    peer_state: &mut PeerState,
+    progress: &Arc<RwLock<TorrentProgress>>,
+) -> Option<PeerMessage> {
+    let revealed = peer_state.revealed_pieces.as_mut()?;
+    let mut progress = progress.write().unwrap();
+    let bitfield = &peer_state.bitfield;
+    let index = progress
+        .super_seed
+        .as_mut()?
+        .next_offer(&peer_state.peer, |i| bitfield_contains_piece(bitfield, i))?;
+    revealed.insert(index);
+    Some(PeerMessage::create_have(index))
+}
+
+fn bitfield_contains_piece(bitfield: &[u8], piece_index: u32) -> bool {
+    let byte_index = piece_index / 8;
+    let bit_index = 7 - (piece_index % 8);
+    if let Some(byte) = bitfield.get(byte_index as usize) {
+        (byte >> bit_index) & 1 == 1
+    } else {
+        false
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bencoding::torrent::Torrent,
+    connection::Peer,
+    peer::{
+        extensions::ExtensionRegistry,
+        peer_protocol::{
+            handle_message, receive_block, run_connection, UploadQueue, MAX_HASH_FAILURES,
+        },
+        super_seed::SuperSeed,
+        transport::PeerStream,
+        types::{
+            BlockReservation, PeerMessage, PeerMessageID, PeerState, PieceProgress,
+            TorrentProgress, BLOCK_SIZE,
+        },
+    },
+    util::encrypted_stream::EncryptedStream,
+};
+
+const HONEST: &str = "10.0.0.1:6881";
+const OTHER: &str = "10.0.0.2:6881";
+const CORRUPT: &str = "10.0.0.3:6881";
+
+/// A single piece of two blocks.
+fn make_torrent() -> (Torrent, Vec<u8>) {
+    let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    let torrent = Torrent::for_test("blocks.bin", 2 * BLOCK_SIZE as u64, &data, None);
+    (torrent, data)
+}
+
+fn block(data: &[u8], begin: u32) -> &[u8] {
+    &data[begin as usize..(begin + BLOCK_SIZE) as usize]
+}
+
+fn corrupt(block: &[u8]) -> Vec<u8> {
+    block.iter().map(|byte| byte ^ 0xff).collect()
+}
+
+fn is_banned(progress: &TorrentProgress, peer: &str) -> bool {
+    let ip = peer.parse::<std::net::SocketAddr>().unwrap().ip();
+    progress.banned_ips.contains(&ip)
+}
+
+fn is_completed(progress: &TorrentProgress) -> bool {
+    matches!(progress.pieces.get(&0), Some(PieceProgress::Completed(_)))
+}
+
+/// Has `sender` deliver both blocks of the piece, the first one corrupt
+/// when `bad` is set. Returns whether that completed the piece.
+fn send_piece(
+    progress: &mut TorrentProgress,
+    data: &[u8],
+    sender: &str,
+    bad: bool,
+    completed: &AtomicU64,
+) -> bool {
+    let first = match bad {
+        true => corrupt(block(data, 0)),
+        false => block(data, 0).to_vec(),
+    };
+    receive_block(progress, 0, 0, &first, sender, true, completed);
+    receive_block(
+        progress,
+        0,
+        BLOCK_SIZE,
+        block(data, BLOCK_SIZE),
+        sender,
+        true,
+        completed,
+    )
+}
+
+#[test]
+fn one_failure_does_not_ban_the_only_sender() {
+    let (torrent, data) = make_torrent();
+    let mut progress = TorrentProgress::for_test(&torrent);
+    let completed = AtomicU64::new(0);
+
+    assert!(!send_piece(&mut progress, &data, CORRUPT, true, &completed));
+    assert_eq!(progress.hash_failures[CORRUPT], 1);
+    assert!(!is_banned(&progress, CORRUPT));
+}
+
+#[test]
+fn redownload_pins_the_bad_block_on_its_sender() {
+    let (torrent, data) = make_torrent();
+    let mut progress = TorrentProgress::for_test(&torrent);
+    let completed = AtomicU64::new(0);
+
+    // Each sent one block, only the first one was bad
+    let bad = corrupt(block(&data, 0));
+    receive_block(&mut progress, 0, 0, &bad, CORRUPT, true, &completed);
+    let good = block(&data, BLOCK_SIZE);
+    receive_block(&mut progress, 0, BLOCK_SIZE, good, HONEST, true, &completed);
+    assert_eq!(progress.hash_failures[CORRUPT], 1);
+    assert_eq!(progress.hash_failures[HONEST], 1);
+    assert!(progress.banned_ips.is_empty());
+
+    // The good copy of the first block gives the bad one away
+    assert!(send_piece(&mut progress, &data, OTHER, false, &completed));
+    assert!(is_banned(&progress, CORRUPT));
+    assert!(!is_banned(&progress, HONEST));
+    assert!(!is_banned(&progress, OTHER));
+    assert_eq!(completed.load(SeqCst), 1);
+}
+
+#[test]
+fn repeated_failures_get_a_sender_banned() {
+    let (torrent, data) = make_torrent();
+    let mut progress = TorrentProgress::for_test(&torrent);
+    let completed = AtomicU64::new(0);
+
+    for _ in 1..MAX_HASH_FAILURES {
+        send_piece(&mut progress, &data, CORRUPT, true, &completed);
+        assert!(!is_banned(&progress, CORRUPT));
+    }
+    send_piece(&mut progress, &data, CORRUPT, true, &completed);
+    assert!(is_banned(&progress, CORRUPT));
+}
+
+#[test]
+fn unrequested_blocks_are_dropped() {
+    let (torrent, data) = make_torrent();
+    let mut progress = TorrentProgress::for_test(&torrent);
+    let completed = AtomicU64::new(0);
+    let expires_at = Instant::now() + Duration::from_secs(30);
+    if let Some(PieceProgress::InProgress(piece)) = progress.pieces.get_mut(&0) {
+        for block in piece.data.values_mut() {
+            block.reservation = Some(BlockReservation {
+                peer: HONEST.to_string(),
+                expires_at,
+            });
+        }
+    }
+
+    // Another peer's data doesn't take the place of what we asked for...
+    let bad = corrupt(block(&data, 0));
+    assert!(!receive_block(
+        &mut progress,
+        0,
+        0,
+        &bad,
+        CORRUPT,
+        false,
+        &completed
+    ));
+    let Some(PieceProgress::InProgress(piece)) = progress.pieces.get(&0) else {
+        panic!("piece should still be in progress");
+    };
+    assert!(piece.data[&0].data.is_none());
+    assert!(piece.data[&0].reservation.is_some());
+
+    // ...nor does it overwrite data we already have
+    receive_block(
+        &mut progress,
+        0,
+        0,
+        block(&data, 0),
+        HONEST,
+        false,
+        &completed,
+    );
+    receive_block(&mut progress, 0, 0, &bad, CORRUPT, true, &completed);
+    let good = block(&data, BLOCK_SIZE);
+    assert!(receive_block(
+        &mut progress,
+        0,
+        BLOCK_SIZE,
+        good,
+        HONEST,
+        false,
+        &completed
+    ));
+    assert!(is_completed(&progress));
+    assert!(progress.hash_failures.is_empty());
+}
+
+/// Has the peer request the first block and cancel it again before the
+/// writer gets to it, returning our replies to the cancel.
+fn request_and_cancel(supports_fast: bool) -> (UploadQueue, Vec<PeerMessage>) {
+    let (torrent, data) = make_torrent();
+    let mut progress = TorrentProgress::for_test(&torrent);
+    progress.pieces.insert(0, PieceProgress::Completed(data));
+    let progress = Arc::new(RwLock::new(progress));
+    let completed = Arc::new(AtomicU64::new(1));
+    let mut peer_state = PeerState::new(HONEST.to_string(), 1);
+    peer_state.am_choking = false;
+    peer_state.supports_fast = supports_fast;
+    let mut extensions = ExtensionRegistry::new(vec![]);
+    let uploads = UploadQueue::default();
+
+    let mut handle = |message: PeerMessage| {
+        handle_message(
+            &message,
+            &mut peer_state,
+            &mut extensions,
+            &torrent,
+            progress.clone(),
+            completed.clone(),
+            &uploads,
+        )
+        .unwrap()
+    };
+    assert!(handle(PeerMessage::create_request(0, 0, BLOCK_SIZE)).is_empty());
+    let replies = handle(PeerMessage::create_cancel(0, 0, BLOCK_SIZE));
+    assert_eq!(progress.read().unwrap().uploaded.load(SeqCst), 0);
+    (uploads, replies)
+}
+
+#[test]
+fn cancel_takes_back_a_queued_block() {
+    let (uploads, replies) = request_and_cancel(false);
+    assert!(uploads.pop().is_none());
+    assert!(replies.is_empty());
+}
+
+#[test]
+fn cancel_is_answered_with_a_reject_under_the_fast_extension() {
+    let (uploads, replies) = request_and_cancel(true);
+    assert!(uploads.pop().is_none());
+    assert!(matches!(
+        replies.as_slice(),
+        [PeerMessage {
+            id: PeerMessageID::RejectRequest,
+            ..
+        }]
+    ));
+}
+
+/// Runs a peer session over one end of `stream` as if `peer` had connected.
+fn spawn_session(
+    stream: tokio::io::DuplexStream,
+    peer: &str,
+    incoming: bool,
+    torrent: &Arc<Torrent>,
+    progress: &Arc<RwLock<TorrentProgress>>,
+    completed: &Arc<AtomicU64>,
+) {
+    let stream = EncryptedStream::plaintext(Box::new(stream) as Box<dyn PeerStream>, vec![]);
+    let peer = Peer::from(peer.parse::<SocketAddr>().unwrap());
+    let torrent = Arc::clone(torrent);
+    let progress = Arc::clone(progress);
+    let completed = Arc::clone(completed);
+    tokio::spawn(async move {
+        let _ = run_connection(stream, &peer, incoming, &torrent, progress, completed).await;
+    });
+}
+
+#[tokio::test]
+async fn super_seed_reveals_one_piece_at_a_time() {
+    let data: Vec<u8> = (0..4 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    let torrent = Arc::new(Torrent::for_test(
+        "superseed.bin",
+        BLOCK_SIZE as u64,
+        &data,
+        None,
+    ));
+    let num_pieces = torrent.info.pieces.len() as u64;
+
+    let mut seed = TorrentProgress::for_test(&torrent);
+    for (index, piece) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+        seed.pieces
+            .insert(index as u32, PieceProgress::Completed(piece.to_vec()));
+    }
+    seed.have_settings.super_seed = true;
+    seed.super_seed = Some(SuperSeed::new(num_pieces as u32));
+    let seed = Arc::new(RwLock::new(seed));
+    let seed_completed = Arc::new(AtomicU64::new(num_pieces));
+
+    let leecher = Arc::new(RwLock::new(TorrentProgress::for_test(&torrent)));
+    let leecher_completed = Arc::new(AtomicU64::new(0));
+
+    let (seed_end, leecher_end) = tokio::io::duplex(1 << 20);
+    let localhost = |port| SocketAddr::from((Ipv4Addr::LOCALHOST, port)).to_string();
+    spawn_session(
+        seed_end,
+        &localhost(6882),
+        true,
+        &torrent,
+        &seed,
+        &seed_completed,
+    );
+    spawn_session(
+        leecher_end,
+        &localhost(6881),
+        false,
+        &torrent,
+        &leecher,
+        &leecher_completed,
+    );
+
+    // The seed has to unchoke the leecher and reveal it a piece
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while leecher_completed.load(SeqCst) == 0 {
+        assert!(Instant::now() < deadline, "no piece was revealed");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // With no one else to pass it to, the leecher gets nothing more
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(leecher_completed.load(SeqCst), 1);
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+/// Which pieces we hand out while super-seeding (BEP 16). We pretend to have
+/// nothing and reveal one piece at a time to each peer, a different one for
+/// every peer, and only move a peer on to its next piece once the last one
+/// shows up at some other peer, meaning the peer passed it on.
+pub struct SuperSeed {
+    /// The piece each peer was last given, until it spreads.
+    offered: HashMap<String, u32>,
+    /// How many peers we've seen announce each piece.
+    availability: Vec<u32>,
+    /// How many times we've handed out each piece.
+    times_offered: Vec<u32>,
+}
+
+impl SuperSeed {
+    pub fn new(num_pieces: u32) -> Self {
+        SuperSeed {
+            offered: HashMap::new(),
+            availability: vec![0; num_pieces as usize],
+            times_offered: vec![0; num_pieces as usize],
+        }
+    }
+
+    /// The next piece to reveal to `peer`, if its last one has spread. Goes
+    /// for the rarest piece the peer lacks that nobody else is holding an
+    /// offer for, if there is one.
+    pub fn next_offer(&mut self, peer: &str, has_piece: impl Fn(u32) -> bool) -> Option<u32> {
+        if self.offered.contains_key(peer) {
+            return None;
+        }
+
+        let held_by_others: Vec<u32> = self.offered.values().copied().collect();
+        let index = (0..self.availability.len() as u32)
+            .filter(|&i| !has_piece(i))
+            .min_by_key(|&i| {
+                (
+                    held_by_others.contains(&i),
+                    self.availability[i as usize],
+                    self.times_offered[i as usize],
+                )
+            })?;
+
+        self.offered.insert(peer.to_string(), index);
+        self.times_offered[index as usize] += 1;
+        Some(index)
+    }
+
+    /// `peer` announced piece `index`. Whoever else we gave it to passed it
+    /// on, and has earned another piece.
+    pub fn peer_has(&mut self, peer: &str, index: u32) {
+        let Some(availability) = self.availability.get_mut(index as usize) else {
+            return;
+        };
+        *availability += 1;
+        self.offered
+            .retain(|other, offered| other == peer || *offered != index);
+    }
+
+    /// A peer that announced piece `index` doesn't have it after all.
+    pub fn peer_lost(&mut self, index: u32) {
+        if let Some(availability) = self.availability.get_mut(index as usize) {
+            *availability = availability.saturating_sub(1);
+        }
+    }
+
+    /// Lets `peer` have a new piece on the next offer, for when it turned
+    /// out to have its last one already or went away.
+    pub fn withdraw_offer(&mut self, peer: &str) {
+        self.offered.remove(peer);
+    }
+}
@@ -0,0 +1,171 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    net::TcpStream,
+    sync::Semaphore,
+};
+
+use crate::{
+    connection::Peer,
+    peer::{
+        mse::{self, EncryptionPolicy},
+        peer_protocol::PeerProtocolError,
+    },
+    util::encrypted_stream::EncryptedStream,
+    utp::socket::UtpSocket,
+};
+
+/// How long we give a TCP connection attempt.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a peer that connected to us gets to say which torrent it wants.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Anything a peer session can run over, TCP and uTP alike.
+pub trait PeerStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> PeerStream for T {}
+
+/// A connection to a peer, encrypted or not, over either transport.
+pub type PeerConnection = EncryptedStream<Box<dyn PeerStream>>;
+
+/// How we reach peers: over uTP when we have a socket for it and the peer
+/// answers, over TCP otherwise. Either way with encryption per `policy`.
+#[derive(Clone)]
+pub struct Transport {
+    pub policy: EncryptionPolicy,
+    pub utp: Option<Arc<UtpSocket>>,
+    /// One permit per connection attempt allowed in flight at once.
+    half_open: Arc<Semaphore>,
+}
+
+impl Transport {
+    pub fn new(
+        policy: EncryptionPolicy,
+        utp: Option<Arc<UtpSocket>>,
+        max_half_open: usize,
+    ) -> Self {
+        Transport {
+            policy,
+            utp,
+            half_open: Arc::new(Semaphore::new(max_half_open)),
+        }
+    }
+
+    /// How many more connection attempts can start without waiting.
+    pub fn free_half_open_slots(&self) -> usize {
+        self.half_open.available_permits()
+    }
+
+    /// Opens a connection to `peer` for the torrent with `info_hash`.
+    pub async fn connect(
+        &self,
+        peer: &Peer,
+        info_hash: &[u8; 20],
+    ) -> Result<PeerConnection, PeerProtocolError> {
+        self.open(peer, info_hash, true).await
+    }
+
+    /// Like `connect`, but over uTP only. Hole punching only works for UDP.
+    pub async fn connect_utp(
+        &self,
+        peer: &Peer,
+        info_hash: &[u8; 20],
+    ) -> Result<PeerConnection, PeerProtocolError> {
+        self.open(peer, info_hash, false).await
+    }
+
+    /// Opens our NAT to `peer` so its uTP connection attempt gets through.
+    pub fn punch(&self, peer: &Peer) {
+        if let Some(utp) = &self.utp {
+            let _ = utp.punch(SocketAddr::new(peer.ip, peer.port));
+        }
+    }
+
+    async fn open(
+        &self,
+        peer: &Peer,
+        info_hash: &[u8; 20],
+        allow_tcp: bool,
+    ) -> Result<PeerConnection, PeerProtocolError> {
+        // Counts as half-open until the encryption handshake is done
+        let _permit = self
+            .half_open
+            .acquire()
+            .await
+            .map_err(|_| PeerProtocolError::FailedToConnect)?;
+        let addr = SocketAddr::new(peer.ip, peer.port);
+        let (stream, over_utp) = match self.open_utp(addr).await {
+            Some(stream) => (stream, true),
+            None if allow_tcp => (connect_tcp(addr).await?, false),
+            None => return Err(PeerProtocolError::FailedToConnect),
+        };
+
+        match mse::initiate(stream, info_hash, self.policy).await {
+            Ok(stream) => Ok(stream),
+            // Peers without MSE support usually just drop the connection, so
+            // try again without it
+            Err(_) if self.policy.allows_plaintext() => {
+                let stream = if over_utp {
+                    self.open_utp(addr)
+                        .await
+                        .ok_or(PeerProtocolError::FailedToConnect)?
+                } else {
+                    connect_tcp(addr).await?
+                };
+                Ok(EncryptedStream::plaintext(stream, vec![]))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sets up a connection a peer opened to us for any of the torrents in
+    /// `info_hashes`, returning the one it's for.
+    pub async fn accept(
+        &self,
+        stream: Box<dyn PeerStream>,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<(PeerConnection, [u8; 20]), PeerProtocolError> {
+        let (mut stream, info_hash) = mse::accept(stream, info_hashes, self.policy).await?;
+        let info_hash = match info_hash {
+            Some(info_hash) => info_hash,
+            // The plaintext handshake names the torrent itself
+            None => tokio::time::timeout(HANDSHAKE_TIMEOUT, peek_info_hash(&mut stream))
+                .await
+                .map_err(|_| PeerProtocolError::ConnectionClosed)??,
+        };
+        if !info_hashes.contains(&info_hash) {
+            return Err(PeerProtocolError::HandshakeError(
+                "Peer asked for a torrent we don't have".to_string(),
+            ));
+        }
+        Ok((stream, info_hash))
+    }
+
+    async fn open_utp(&self, addr: SocketAddr) -> Option<Box<dyn PeerStream>> {
+        let stream = self.utp.as_ref()?.connect(addr).await.ok()?;
+        Some(Box::new(stream))
+    }
+}
+
+/// Reads the info hash out of the peer's handshake, leaving the handshake
+/// to be read again.
+async fn peek_info_hash(stream: &mut PeerConnection) -> Result<[u8; 20], PeerProtocolError> {
+    // pstrlen, pstr, reserved, info_hash
+    let mut buf = vec![0; 48];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .map_err(|_| PeerProtocolError::ConnectionClosed)?;
+    let info_hash = buf[28..48].try_into().unwrap();
+    stream.unread(buf);
+    Ok(info_hash)
+}
+
+async fn connect_tcp(addr: SocketAddr) -> Result<Box<dyn PeerStream>, PeerProtocolError> {
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| PeerProtocolError::FailedToConnect)?
+        .map_err(|_| PeerProtocolError::FailedToConnect)?;
+    Ok(Box::new(stream))
+}
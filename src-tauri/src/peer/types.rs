@@ -1,8 +1,32 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use sha1::{Digest, Sha1};
 
-use crate::{bencoding::torrent::Torrent, connection::Peer};
+use tokio::sync::broadcast;
+
+#[cfg(test)]
+use crate::bencoding::torrent::{File, Info};
+use crate::{
+    bencoding::torrent::Torrent,
+    connection::Peer,
+    peer::{
+        bandwidth::Bandwidth, choker::Choker, extensions::ut_holepunch::Holepunch,
+        have::HaveSettings, peer_id::ClientIdentity, super_seed::SuperSeed,
+    },
+    util::{disk_io::DiskIo, rate_limiter::RateLimit},
+};
+
+/// The only protocol string a handshake may carry.
+pub const PROTOCOL_STRING: &str = "BitTorrent protocol";
 
 #[derive(Debug)]
 pub struct PeerHandshake {
@@ -25,10 +49,17 @@ impl From<&PeerHandshake> for Vec<u8> {
     }
 }
 
-impl From<[u8; 68]> for PeerHandshake {
-    fn from(bytes: [u8; 68]) -> Self {
+impl TryFrom<[u8; 68]> for PeerHandshake {
+    type Error = String;
+
+    fn try_from(bytes: [u8; 68]) -> Result<Self, Self::Error> {
         let pstr_len = bytes[0] as usize;
-        let pstr = String::from_utf8(bytes[1..1 + pstr_len].to_vec()).unwrap();
+        if pstr_len != PROTOCOL_STRING.len()
+            || &bytes[1..1 + pstr_len] != PROTOCOL_STRING.as_bytes()
+        {
+            return Err("Peer sent a handshake for an unknown protocol".to_string());
+        }
+        let pstr = PROTOCOL_STRING.to_string();
         let mut reserved = [0; 8];
         reserved.copy_from_slice(&bytes[1 + pstr_len..1 + pstr_len + 8]);
         let mut info_hash = [0; 20];
@@ -36,12 +67,12 @@ impl From<[u8; 68]> for PeerHandshake {
         let mut peer_id = [0; 20];
         peer_id.copy_from_slice(&bytes[1 + pstr_len + 28..1 + pstr_len + 48]);
 
-        PeerHandshake {
+        Ok(PeerHandshake {
             pstr,
             reserved,
             info_hash,
             peer_id,
-        }
+        })
     }
 }
 
@@ -58,6 +89,11 @@ pub enum PeerMessageID {
     Piece = 7,
     Cancel = 8,
     Port = 9,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
     Extended = 20,
 }
 
@@ -70,6 +106,10 @@ pub struct PeerMessage {
 
 impl From<&PeerMessage> for Vec<u8> {
     fn from(message: &PeerMessage) -> Self {
+        if let PeerMessageID::KeepAlive = message.id {
+            return vec![0; 4];
+        }
+
         let mut buf = vec![];
         buf.extend_from_slice(&(message.length).to_be_bytes());
         buf.push(message.id as u8);
@@ -92,6 +132,106 @@ impl PeerMessage {
         }
     }
 
+    pub fn create_cancel(index: u32, begin: u32, length: u32) -> Self {
+        let mut payload = Vec::<u8>::new();
+        payload.extend_from_slice(&index.to_be_bytes());
+        payload.extend_from_slice(&begin.to_be_bytes());
+        payload.extend_from_slice(&length.to_be_bytes());
+        PeerMessage {
+            id: PeerMessageID::Cancel,
+            length: 13,
+            payload,
+        }
+    }
+
+    pub fn create_reject(index: u32, begin: u32, length: u32) -> Self {
+        let mut payload = Vec::<u8>::new();
+        payload.extend_from_slice(&index.to_be_bytes());
+        payload.extend_from_slice(&begin.to_be_bytes());
+        payload.extend_from_slice(&length.to_be_bytes());
+        PeerMessage {
+            id: PeerMessageID::RejectRequest,
+            length: 13,
+            payload,
+        }
+    }
+
+    pub fn create_piece(index: u32, begin: u32, block: &[u8]) -> Self {
+        let mut payload = Vec::<u8>::with_capacity(8 + block.len());
+        payload.extend_from_slice(&index.to_be_bytes());
+        payload.extend_from_slice(&begin.to_be_bytes());
+        payload.extend_from_slice(block);
+        PeerMessage {
+            id: PeerMessageID::Piece,
+            length: (1 + payload.len()) as u32,
+            payload,
+        }
+    }
+
+    pub fn create_allowed_fast(index: u32) -> Self {
+        PeerMessage {
+            id: PeerMessageID::AllowedFast,
+            length: 5,
+            payload: index.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn create_have(index: u32) -> Self {
+        PeerMessage {
+            id: PeerMessageID::Have,
+            length: 5,
+            payload: index.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn create_have_all() -> Self {
+        PeerMessage {
+            id: PeerMessageID::HaveAll,
+            length: 1,
+            payload: vec![],
+        }
+    }
+
+    pub fn create_have_none() -> Self {
+        PeerMessage {
+            id: PeerMessageID::HaveNone,
+            length: 1,
+            payload: vec![],
+        }
+    }
+
+    pub fn create_bitfield(bitfield: Vec<u8>) -> Self {
+        PeerMessage {
+            id: PeerMessageID::Bitfield,
+            length: (1 + bitfield.len()) as u32,
+            payload: bitfield,
+        }
+    }
+
+    pub fn create_choke() -> Self {
+        PeerMessage {
+            id: PeerMessageID::Choke,
+            length: 1,
+            payload: vec![],
+        }
+    }
+
+    pub fn create_unchoke() -> Self {
+        PeerMessage {
+            id: PeerMessageID::Unchoke,
+            length: 1,
+            payload: vec![],
+        }
+    }
+
+    pub fn create_keep_alive() -> Self {
+        PeerMessage {
+            id: PeerMessageID::KeepAlive,
+            length: 0,
+            payload: vec![],
+        }
+    }
+
     pub fn create_interested() -> Self {
         PeerMessage {
             id: PeerMessageID::Interested,
@@ -99,47 +239,107 @@ impl PeerMessage {
             payload: vec![],
         }
     }
+
+    pub fn create_not_interested() -> Self {
+        PeerMessage {
+            id: PeerMessageID::NotInterested,
+            length: 1,
+            payload: vec![],
+        }
+    }
 }
 
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// How long after a hash failure a block is kept from the peer that sent it,
+/// in case another peer picks it up.
+const REDOWNLOAD_GRACE: Duration = Duration::from_secs(30);
+/// Request queue depth a peer starts with before we have measured it.
+const INITIAL_QUEUE_DEPTH: u32 = 16;
+const MIN_QUEUE_DEPTH: u32 = 2;
+const MAX_QUEUE_DEPTH: u32 = 500;
+/// Snubbed peers only ever get a single outstanding request.
+const SNUBBED_QUEUE_DEPTH: u32 = 1;
+/// How often a peer's throughput is sampled and its queue depth recomputed.
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct TorrentProgress {
     pub pieces: HashMap<u32, PieceProgress>,
     pub connected_peers: HashSet<Peer>,
+    pub peer_stats: HashMap<String, PeerStats>,
+    /// Peers we heard about from other peers (PEX) but haven't dialed yet.
+    pub candidate_peers: HashSet<Peer>,
+    /// Peer ids of connected peers, to catch a second connection to the same
+    /// client under another address.
+    pub peer_ids: HashMap<[u8; 20], String>,
+    /// The peer ID and client info we use for this session.
+    pub identity: ClientIdentity,
+    pub have_settings: HaveSettings,
+    /// Announces each piece we complete to every session.
+    pub have_tx: broadcast::Sender<u32>,
+    /// Announces each piece we no longer have.
+    pub donthave_tx: broadcast::Sender<u32>,
+    /// Failed pieces each peer sent blocks of.
+    pub hash_failures: HashMap<String, u32>,
+    /// Addresses caught sending corrupt data.
+    pub banned_ips: HashSet<IpAddr>,
+    /// Client-wide limits, and this torrent's own.
+    pub bandwidth: Arc<Bandwidth>,
+    pub rate_limit: Arc<RateLimit>,
+    /// Piece handout when super-seeding is on.
+    pub super_seed: Option<SuperSeed>,
+    /// Which peers we upload to.
+    pub choker: Choker,
+    /// The pieces of the files we selected, `None` when we want them all.
+    pub wanted_pieces: Option<HashSet<u32>>,
+    /// Introductions we relay and the ones we asked for (BEP 55).
+    pub holepunch: Holepunch,
+    /// Writes our pieces, shared with the other torrents.
+    pub disk: Arc<DiskIo>,
+    /// Where this torrent's pieces are kept.
+    pub pieces_dir: PathBuf,
+    /// Payload bytes we took from and served to peers, for announces.
+    pub downloaded: AtomicU64,
+    pub uploaded: AtomicU64,
 }
 
-impl From<&Torrent> for TorrentProgress {
-    fn from(torrent: &Torrent) -> Self {
-        let pieces = torrent
-            .info
-            .pieces
-            .iter()
-            .enumerate()
-            .map(|(i, _)| {
-                let mut data = HashMap::<_, _>::new();
-                let piece_length = torrent.get_piece_length(i);
-                let block_size = 16 * 1024; // 16 KB blocks
-                let mut offset = 0;
-                while offset < piece_length {
-                    let block_length = std::cmp::min(block_size, piece_length - offset);
-                    data.insert(
-                        offset,
-                        BlockProgress {
-                            begin: offset,
-                            length: block_length,
-                            inflight: false,
-                            data: None,
-                        },
-                    );
-                    offset += block_length;
-                }
+/// Snapshot of a connected peer, published by its session for display.
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub download_rate: f64,
+    pub rtt: Option<Duration>,
+    pub queue_depth: u32,
+    pub inflight: u32,
+    pub is_choked: bool,
+    pub is_snubbed: bool,
+    pub is_seed: bool,
+    pub is_incoming: bool,
+    /// The peer wants something from us.
+    pub is_interested: bool,
+    /// The port the peer said it accepts connections on.
+    pub listen_port: Option<u16>,
+    /// Client name decoded from the peer id.
+    pub client: String,
+    /// Protocol extensions the peer advertised in its handshake.
+    pub capabilities: Vec<&'static str>,
+    /// Failed pieces the peer sent blocks of.
+    pub hash_failures: u32,
+}
 
+impl TorrentProgress {
+    pub fn new(
+        torrent: &Torrent,
+        identity: ClientIdentity,
+        have_settings: HaveSettings,
+        bandwidth: Arc<Bandwidth>,
+        rate_limit: RateLimit,
+        disk: Arc<DiskIo>,
+    ) -> Self {
+        let pieces = (0..torrent.info.pieces.len() as u32)
+            .map(|i| {
                 (
-                    i as u32,
-                    PieceProgress::InProgress(PieceProgressData {
-                        index: i as u32,
-                        length: torrent.get_piece_length(i),
-                        data,
-                        expected_hash: torrent.info.pieces[i],
-                    }),
+                    i,
+                    PieceProgress::InProgress(PieceProgressData::new(torrent, i)),
                 )
             })
             .collect();
@@ -147,8 +347,177 @@ impl From<&Torrent> for TorrentProgress {
         TorrentProgress {
             pieces,
             connected_peers: HashSet::new(),
+            peer_stats: HashMap::new(),
+            candidate_peers: HashSet::new(),
+            peer_ids: HashMap::new(),
+            identity,
+            have_settings,
+            // Every piece completes once, so sessions can never lag behind
+            have_tx: broadcast::channel(torrent.info.pieces.len().max(1)).0,
+            donthave_tx: broadcast::channel(torrent.info.pieces.len().max(1)).0,
+            hash_failures: HashMap::new(),
+            banned_ips: HashSet::new(),
+            bandwidth,
+            rate_limit: Arc::new(rate_limit),
+            super_seed: have_settings
+                .super_seed
+                .then(|| SuperSeed::new(torrent.info.pieces.len() as u32)),
+            choker: Choker::new(Instant::now()),
+            wanted_pieces: None,
+            holepunch: Holepunch::default(),
+            pieces_dir: disk.torrent_dir(&torrent.info_hash),
+            disk,
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
         }
     }
+
+    /// Where piece `index` is kept on disk.
+    pub fn piece_path(&self, index: u32) -> PathBuf {
+        self.pieces_dir.join(format!("{}.bin", index))
+    }
+
+    pub fn is_wanted(&self, index: u32) -> bool {
+        self.wanted_pieces
+            .as_ref()
+            .is_none_or(|wanted| wanted.contains(&index))
+    }
+
+    /// Every piece we want is in, so we only upload from here on (BEP 21).
+    /// That's a seed, or a partial seed when only some files were selected.
+    pub fn is_upload_only(&self) -> bool {
+        self.pieces.iter().all(|(&index, piece)| {
+            matches!(piece, PieceProgress::Completed(_)) || !self.is_wanted(index)
+        })
+    }
+
+    /// Bytes of verified data we hold.
+    pub fn completed_bytes(&self) -> u64 {
+        self.pieces
+            .values()
+            .map(|piece| match piece {
+                PieceProgress::Completed(data) => data.len() as u64,
+                PieceProgress::InProgress(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Drops piece `index` after its data turned out to be gone or corrupt,
+    /// so it's downloaded again, and tells every session's peer. Returns
+    /// whether we had the piece.
+    pub fn retract_piece(
+        &mut self,
+        torrent: &Torrent,
+        index: u32,
+        completed_pieces: &AtomicU64,
+    ) -> bool {
+        if !matches!(self.pieces.get(&index), Some(PieceProgress::Completed(_))) {
+            return false;
+        }
+
+        self.pieces.insert(
+            index,
+            PieceProgress::InProgress(PieceProgressData::new(torrent, index)),
+        );
+        completed_pieces.fetch_sub(1, SeqCst);
+        let _ = self.donthave_tx.send(index);
+        true
+    }
+
+    /// Releases every block reserved by `peer`, making it requestable again.
+    /// Returns the number of released blocks.
+    pub fn release_peer_reservations(&mut self, peer: &str) -> u32 {
+        self.release_reservations_where(|reservation| reservation.peer == peer)
+    }
+
+    /// Stops talking to `peer` for good.
+    pub fn ban(&mut self, peer: &str) {
+        if let Ok(addr) = peer.parse::<SocketAddr>() {
+            if self.banned_ips.insert(addr.ip().to_canonical()) {
+                println!("{} - Banned for sending corrupt data", peer);
+            }
+        }
+    }
+
+    /// Releases every block whose reservation lease has run out.
+    pub fn release_expired_reservations(&mut self, now: Instant) -> u32 {
+        self.release_reservations_where(|reservation| reservation.expires_at <= now)
+    }
+
+    /// Releases a single block, but only if it is still reserved by `peer`.
+    pub fn release_block(&mut self, peer: &str, index: u32, begin: u32) -> bool {
+        if let Some(PieceProgress::InProgress(piece_progress)) = self.pieces.get_mut(&index) {
+            if let Some(block) = piece_progress.data.get_mut(&begin) {
+                if block.reservation.as_ref().is_some_and(|r| r.peer == peer) {
+                    block.reservation = None;
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    fn release_reservations_where<F>(&mut self, predicate: F) -> u32
+    where
+        F: Fn(&BlockReservation) -> bool,
+    {
+        let mut released = 0;
+        for piece in self.pieces.values_mut() {
+            if let PieceProgress::InProgress(piece_progress) = piece {
+                for block in piece_progress.data.values_mut() {
+                    if block.reservation.as_ref().is_some_and(&predicate) {
+                        block.reservation = None;
+                        released += 1;
+                    }
+                }
+            }
+        }
+
+        released
+    }
+}
+
+#[cfg(test)]
+impl Torrent {
+    /// A torrent over `data` without trackers or seeds, single-file when
+    /// `files` is `None`. The info hash is made up from `name`.
+    pub fn for_test(name: &str, piece_length: u64, data: &[u8], files: Option<Vec<File>>) -> Self {
+        Torrent {
+            trackers: vec![],
+            info: Info {
+                name: name.to_string(),
+                piece_length,
+                pieces: data
+                    .chunks(piece_length as usize)
+                    .map(|piece| Sha1::digest(piece).into())
+                    .collect(),
+                length: files.is_none().then_some(data.len() as i64),
+                files,
+                private: false,
+            },
+            info_hash: Sha1::digest(name.as_bytes()).into(),
+            web_seeds: vec![],
+            http_seeds: vec![],
+            info_bytes: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+impl TorrentProgress {
+    /// Progress on `torrent` with nothing downloaded, no limits and pieces
+    /// kept in a temporary directory.
+    pub fn for_test(torrent: &Torrent) -> Self {
+        TorrentProgress::new(
+            torrent,
+            ClientIdentity::generate(false),
+            HaveSettings::default(),
+            Arc::new(Bandwidth::new(RateLimit::new(0, 0), 0, 0)),
+            RateLimit::new(0, 0),
+            Arc::new(DiskIo::new(std::env::temp_dir().join("bittorrent-tests"))),
+        )
+    }
 }
 
 pub enum PieceProgress {
@@ -170,9 +539,41 @@ pub struct PieceProgressData {
     pub length: u32,
     pub data: HashMap<u32, BlockProgress>,
     pub expected_hash: [u8; 20],
+    /// When the piece last failed its hash check.
+    pub failed_at: Option<Instant>,
 }
 
 impl PieceProgressData {
+    /// Piece `index` with none of its blocks downloaded.
+    pub fn new(torrent: &Torrent, index: u32) -> Self {
+        let length = torrent.get_piece_length(index as usize);
+        let mut data = HashMap::new();
+        let mut offset = 0;
+        while offset < length {
+            let block_length = std::cmp::min(BLOCK_SIZE, length - offset);
+            data.insert(
+                offset,
+                BlockProgress {
+                    begin: offset,
+                    length: block_length,
+                    reservation: None,
+                    data: None,
+                    sender: None,
+                    previous: None,
+                },
+            );
+            offset += block_length;
+        }
+
+        PieceProgressData {
+            index,
+            length,
+            data,
+            expected_hash: torrent.info.pieces[index as usize],
+            failed_at: None,
+        }
+    }
+
     pub fn get_final_data(&self) -> Result<Option<Vec<u8>>, String> {
         let mut final_data = vec![0; self.length as usize];
 
@@ -204,38 +605,279 @@ impl PieceProgressData {
 
     pub fn reset(&mut self) {
         self.data.iter_mut().for_each(|(_, block)| {
-            block.inflight = false;
+            block.reservation = None;
             block.data = None;
+            block.sender = None;
         });
     }
+
+    /// Every peer that sent a block of the piece.
+    pub fn senders(&self) -> HashSet<String> {
+        self.data
+            .values()
+            .filter_map(|block| block.sender.clone())
+            .collect()
+    }
+
+    /// Puts the piece up for download again after a failed hash check,
+    /// remembering what each block held and who sent it.
+    pub fn fail(&mut self, now: Instant) {
+        for block in self.data.values_mut() {
+            if let (Some(data), Some(sender)) = (&block.data, &block.sender) {
+                block.previous = Some(PreviousBlock {
+                    sender: sender.clone(),
+                    hash: Sha1::digest(data).into(),
+                });
+            }
+        }
+        self.failed_at = Some(now);
+        self.reset();
+    }
+
+    /// Once the piece checks out, the peers whose blocks from the failed
+    /// attempt differ from the good data. They sent the corrupt ones.
+    pub fn corrupt_senders(&self) -> HashSet<String> {
+        self.data
+            .values()
+            .filter_map(|block| {
+                let previous = block.previous.as_ref()?;
+                let hash: [u8; 20] = Sha1::digest(block.data.as_ref()?).into();
+                (hash != previous.hash).then(|| previous.sender.clone())
+            })
+            .collect()
+    }
+
+    /// Whether `peer` may request the block at `begin`. After a hash failure
+    /// each block goes to a different peer than last time, unless nobody
+    /// else has taken it for a while.
+    pub fn can_request_block(&self, begin: u32, peer: &str, now: Instant) -> bool {
+        let Some(block) = self.data.get(&begin) else {
+            return false;
+        };
+        if !block.is_requestable(now) {
+            return false;
+        }
+
+        let sent_it_last = block
+            .previous
+            .as_ref()
+            .is_some_and(|previous| previous.sender == peer);
+        !sent_it_last
+            || self
+                .failed_at
+                .is_some_and(|failed_at| now.duration_since(failed_at) >= REDOWNLOAD_GRACE)
+    }
 }
 
 pub struct BlockProgress {
     pub begin: u32,
     pub length: u32,
-    pub inflight: bool,
+    pub reservation: Option<BlockReservation>,
     pub data: Option<Vec<u8>>,
+    /// The peer `data` came from.
+    pub sender: Option<String>,
+    /// The block as of the last failed hash check of its piece.
+    pub previous: Option<PreviousBlock>,
+}
+
+pub struct PreviousBlock {
+    pub sender: String,
+    pub hash: [u8; 20],
+}
+
+impl BlockProgress {
+    /// A block can be requested when we don't have its data yet and nobody
+    /// holds a live lease on it.
+    pub fn is_requestable(&self, now: Instant) -> bool {
+        self.data.is_none()
+            && self
+                .reservation
+                .as_ref()
+                .is_none_or(|reservation| reservation.expires_at <= now)
+    }
+}
+
+/// Lease on a block held by the peer we requested it from.
+pub struct BlockReservation {
+    pub peer: String,
+    pub expires_at: Instant,
 }
 
 pub struct PeerState {
     pub peer: String,
+    /// The peer connected to us rather than the other way around.
+    pub is_incoming: bool,
     pub is_choked: bool,
-    pub inflight: u32,
+    pub is_snubbed: bool,
+    /// Whether we are choking the peer, and whether it wants anything from us.
+    pub am_choking: bool,
+    pub peer_interested: bool,
+    /// Whether the peer has pieces we still need, as we last told it.
+    pub am_interested: bool,
+    /// Both sides advertised the Fast Extension (BEP 6).
+    pub supports_fast: bool,
+    /// Both sides advertised the extension protocol (BEP 10).
+    pub supports_extensions: bool,
+    /// The peer runs a DHT node (BEP 5).
+    pub supports_dht: bool,
+    /// The peer told us it won't download anything more (BEP 21).
+    pub upload_only: bool,
+    /// Client name decoded from the peer id.
+    pub client: String,
+    /// Pieces the peer lets us request while it's choking us.
+    pub allowed_fast: HashSet<u32>,
+    /// Pieces we let the peer request while we're choking it.
+    pub our_allowed_fast: HashSet<u32>,
+    /// Pieces we revealed to the peer while super-seeding, `None` when we
+    /// aren't.
+    pub revealed_pieces: Option<HashSet<u32>>,
+    /// Pieces the peer suggested we download next.
+    pub suggested_pieces: Vec<u32>,
     pub bitfield: Vec<u8>,
     pub requested_pieces: Vec<u32>,
+    /// Outstanding block requests keyed by (piece index, begin), with the
+    /// time each request was sent.
+    pub pending_requests: HashMap<(u32, u32), Instant>,
+    /// How many requests we keep outstanding with this peer.
+    pub request_queue_depth: u32,
+    /// The `reqq` the peer advertised in its extension handshake.
+    pub peer_reqq: Option<u32>,
+    /// The port the peer said it accepts connections on.
+    pub listen_port: Option<u16>,
+    /// Smoothed download rate in bytes per second.
+    pub download_rate: f64,
+    /// Smoothed and minimum request round-trip times.
+    pub rtt: Option<Duration>,
+    pub min_rtt: Option<Duration>,
+    bytes_since_sample: u64,
+    last_sample: Instant,
 }
 
 impl PeerState {
     pub fn new(peer: String, num_bitfield_bytes: usize) -> Self {
         let is_choked = true;
-        let inflight = 0u32;
         let bitfield: Vec<u8> = vec![0; num_bitfield_bytes];
         PeerState {
             peer,
+            is_incoming: false,
             is_choked,
-            inflight,
+            is_snubbed: false,
+            am_choking: true,
+            peer_interested: false,
+            am_interested: false,
+            supports_fast: false,
+            supports_extensions: false,
+            supports_dht: false,
+            upload_only: false,
+            client: "unknown".to_string(),
+            allowed_fast: HashSet::new(),
+            our_allowed_fast: HashSet::new(),
+            revealed_pieces: None,
+            suggested_pieces: vec![],
             bitfield,
             requested_pieces: vec![],
+            pending_requests: HashMap::new(),
+            request_queue_depth: INITIAL_QUEUE_DEPTH,
+            peer_reqq: None,
+            listen_port: None,
+            download_rate: 0.0,
+            rtt: None,
+            min_rtt: None,
+            bytes_since_sample: 0,
+            last_sample: Instant::now(),
+        }
+    }
+
+    /// Whether we may request blocks of `piece_index` from the peer right now.
+    pub fn can_request_piece(&self, piece_index: u32) -> bool {
+        !self.is_choked || self.allowed_fast.contains(&piece_index)
+    }
+
+    /// Whether the peer has every piece of the torrent.
+    pub fn has_all_pieces(&self, num_pieces: u32) -> bool {
+        // Spare bits at the end of the bitfield are always zero
+        let pieces_had: u32 = self.bitfield.iter().map(|b| b.count_ones()).sum();
+        pieces_had >= num_pieces
+    }
+
+    pub fn inflight(&self) -> u32 {
+        self.pending_requests.len() as u32
+    }
+
+    /// Number of requests we are currently willing to have outstanding.
+    pub fn max_inflight(&self) -> u32 {
+        if self.is_snubbed {
+            SNUBBED_QUEUE_DEPTH
+        } else {
+            self.request_queue_depth
+        }
+    }
+
+    /// Records a block arriving for a request sent at `sent_at`.
+    pub fn record_block(&mut self, length: usize, sent_at: Option<Instant>, now: Instant) {
+        self.bytes_since_sample += length as u64;
+
+        if let Some(sent_at) = sent_at {
+            let sample = now.duration_since(sent_at);
+            self.rtt = Some(match self.rtt {
+                Some(rtt) => (rtt * 7 + sample) / 8,
+                None => sample,
+            });
+            self.min_rtt = Some(self.min_rtt.map_or(sample, |min| min.min(sample)));
+        }
+    }
+
+    /// Resamples the download rate and resizes the request queue to cover the
+    /// bandwidth-delay product, capped by the peer's advertised `reqq`.
+    pub fn update_request_queue_depth(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_sample);
+        if elapsed < RATE_SAMPLE_INTERVAL {
+            return;
+        }
+
+        let sample = self.bytes_since_sample as f64 / elapsed.as_secs_f64();
+        self.download_rate = (self.download_rate + sample) / 2.0;
+        self.bytes_since_sample = 0;
+        self.last_sample = now;
+
+        // Queueing at the peer inflates the smoothed RTT, so size the pipe off
+        // the minimum and leave headroom to probe for more throughput
+        if let Some(min_rtt) = self.min_rtt {
+            let bdp = self.download_rate * min_rtt.as_secs_f64() / BLOCK_SIZE as f64;
+            self.request_queue_depth = (bdp * 1.5).ceil() as u32 + MIN_QUEUE_DEPTH;
+        }
+
+        let max_depth = self
+            .peer_reqq
+            .unwrap_or(MAX_QUEUE_DEPTH)
+            .clamp(1, MAX_QUEUE_DEPTH);
+        self.request_queue_depth = self
+            .request_queue_depth
+            .clamp(MIN_QUEUE_DEPTH.min(max_depth), max_depth);
+    }
+
+    pub fn stats(&self, num_pieces: u32, hash_failures: u32) -> PeerStats {
+        PeerStats {
+            download_rate: self.download_rate,
+            rtt: self.rtt,
+            queue_depth: self.max_inflight(),
+            inflight: self.inflight(),
+            is_choked: self.is_choked,
+            is_snubbed: self.is_snubbed,
+            is_seed: self.has_all_pieces(num_pieces) || self.upload_only,
+            is_incoming: self.is_incoming,
+            is_interested: self.peer_interested,
+            listen_port: self.listen_port,
+            client: self.client.clone(),
+            capabilities: [
+                (self.supports_extensions, "ext"),
+                (self.supports_fast, "fast"),
+                (self.supports_dht, "dht"),
+            ]
+            .into_iter()
+            .filter_map(|(supported, name)| supported.then_some(name))
+            .collect(),
+            hash_failures,
         }
     }
 }
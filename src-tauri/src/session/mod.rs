@@ -0,0 +1,889 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use rayon::prelude::*;
+use sha1::{Digest, Sha1};
+use tokio::{
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    bencoding::torrent::Torrent,
+    connection::{Event, Peer},
+    peer::{
+        bandwidth::{torrent_limit_from_env, Bandwidth, Direction},
+        connection_manager::ConnectionManager,
+        have::HaveSettings,
+        mse::EncryptionPolicy,
+        peer_id::ClientIdentity,
+        peer_protocol::{
+            accept_peer, connect_to_peer, holepunch_peer, PeerProtocolError, LISTEN_PORT,
+        },
+        transport::{PeerConnection, PeerStream, Transport},
+        types::{PieceProgress, TorrentProgress},
+    },
+    tracker::{announce_stopped, get_peers_from_torrent, AnnounceStats},
+    util::{disk_io::DiskIo, dual_stack, ip_filter::Blocklist, rate_limiter::RateLimit},
+    utp::socket::UtpSocket,
+    web_seed::{run_web_seed, WebSeedKind},
+};
+
+#[cfg(test)]
+mod tests;
+
+/// How often, in ticks, the per-peer stats table is printed.
+const PEER_STATS_INTERVAL_TICKS: u64 = 10;
+/// How often, in ticks, we check the files of the pieces we have are still
+/// there.
+const PIECE_CHECK_INTERVAL_TICKS: u64 = 30;
+/// Connections we keep open across all torrents...
+const MAX_CONNECTIONS: usize = 200;
+/// ...and for any one torrent. We stop looking for, and accepting, peers past
+/// this.
+const MAX_CONNECTED_PEERS: usize = 100;
+/// Outgoing connection attempts in flight at once.
+const MAX_HALF_OPEN: usize = 20;
+/// How long a torrent waits between two lookups of its peers.
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(30);
+/// How long we wait on the trackers to hear we stopped before exiting anyway.
+const STOP_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A tracker and DHT lookup running on the blocking pool.
+type PeerLookup = JoinHandle<Result<Vec<Peer>, String>>;
+
+/// One torrent of the session and everything its peer sessions share.
+pub struct TorrentHandle {
+    pub torrent: Arc<Torrent>,
+    pub progress: Arc<RwLock<TorrentProgress>>,
+    pub completed_pieces: Arc<AtomicU64>,
+    /// The files to save when done, `None` for all of them.
+    pub selected_files: Option<Vec<usize>>,
+    pub started_at: Instant,
+    /// Which of the torrent's peers to dial, within its share of connections.
+    manager: Arc<Mutex<ConnectionManager>>,
+    /// Peer and web seed sessions.
+    sessions: JoinSet<()>,
+    /// When we last looked for the torrent's peers.
+    looked_up_at: Option<Instant>,
+    /// Whether we had every piece we want as of the last tick.
+    finished: bool,
+    /// The trackers saw us download, and have yet to hear we completed.
+    announce_completed: bool,
+    /// Trackers only hear we completed once, even if a piece goes missing
+    /// and we complete again.
+    completed_sent: bool,
+}
+
+impl TorrentHandle {
+    fn is_done(&self) -> bool {
+        self.progress.read().unwrap().is_upload_only()
+    }
+
+    /// Whether we have every piece, not just the ones we want.
+    fn is_seed(&self) -> bool {
+        self.completed_pieces.load(SeqCst) == self.torrent.info.pieces.len() as u64
+    }
+
+    fn print_progress(&self) {
+        let completed = self.completed_pieces.load(SeqCst);
+        let total = self.torrent.info.pieces.len() as u64;
+        let percent = (completed as f64 / total as f64) * 100.0;
+        let connected_peers = self.progress.read().unwrap().connected_peers.len();
+        println!(
+            "{} - Progress - {}/{} peices ({:.2}%) - Connected Peers: {}",
+            self.torrent.info.name, completed, total, percent, connected_peers
+        );
+    }
+}
+
+/// What peer sessions of every torrent connect through.
+#[derive(Clone)]
+struct Connections {
+    transport: Transport,
+    /// One permit per open connection.
+    slots: Arc<Semaphore>,
+}
+
+/// How a peer session gets its connection.
+enum Opening {
+    /// The peer connected to us, and asked for this torrent.
+    Accepted(Box<PeerConnection>),
+    Dial,
+    /// A relay introduced us, we connect over uTP through its NAT.
+    Holepunch,
+}
+
+/// Everything we download. The torrents share our listen port, the UDP socket
+/// uTP and the DHT run on, the connection and bandwidth limits and the disk
+/// writer. Incoming connections go to the torrent they ask for.
+pub struct Session {
+    identity: ClientIdentity,
+    utp: Arc<UtpSocket>,
+    blocklist: Arc<Blocklist>,
+    bandwidth: Arc<Bandwidth>,
+    disk: Arc<DiskIo>,
+    connections: Connections,
+    torrents: HashMap<[u8; 20], TorrentHandle>,
+    /// Connections peers opened to us, over TCP and uTP.
+    incoming: mpsc::Receiver<(Box<dyn PeerStream>, Peer)>,
+    /// Incoming connections along with the torrent they turned out to be for.
+    routed_tx: mpsc::Sender<(PeerConnection, Peer, [u8; 20])>,
+    routed_rx: mpsc::Receiver<(PeerConnection, Peer, [u8; 20])>,
+    /// The tracker and DHT lookup in flight, and the torrent it's for. One at
+    /// a time, as DHT replies all arrive on the one socket.
+    lookup: Option<([u8; 20], PeerLookup)>,
+    ticks: u64,
+}
+
+impl Session {
+    /// Starts listening for peers on `LISTEN_PORT` over TCP, and on `utp`.
+    /// Has to be called from within the runtime.
+    pub fn new(identity: ClientIdentity, blocklist: Arc<Blocklist>, utp: Arc<UtpSocket>) -> Self {
+        // Peers can connect to us over IPv4 and IPv6 alike
+        let (incoming_tx, incoming) = mpsc::channel(MAX_CONNECTED_PEERS);
+        let listener = dual_stack::bind_tcp(LISTEN_PORT).and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            tokio::net::TcpListener::from_std(listener)
+        });
+        match listener {
+            Ok(listener) => {
+                tokio::spawn(accept_incoming_peers(listener, incoming_tx.clone()));
+            }
+            Err(e) => println!("Failed to listen on port {}: {}", LISTEN_PORT, e),
+        }
+        tokio::spawn(accept_incoming_utp_peers(Arc::clone(&utp), incoming_tx));
+
+        let (routed_tx, routed_rx) = mpsc::channel(MAX_CONNECTED_PEERS);
+        Session {
+            identity,
+            connections: Connections {
+                transport: Transport::new(
+                    EncryptionPolicy::from_env(),
+                    Some(Arc::clone(&utp)),
+                    MAX_HALF_OPEN,
+                ),
+                slots: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            },
+            utp,
+            blocklist,
+            bandwidth: Arc::new(Bandwidth::from_env()),
+            disk: Arc::new(DiskIo::from_env()),
+            torrents: HashMap::new(),
+            incoming,
+            routed_tx,
+            routed_rx,
+            lookup: None,
+            ticks: 0,
+        }
+    }
+
+    /// The UDP socket uTP and the DHT run on.
+    pub fn utp(&self) -> &Arc<UtpSocket> {
+        &self.utp
+    }
+
+    /// How we connect to peers, for anything outside a torrent's sessions.
+    pub fn transport(&self) -> &Transport {
+        &self.connections.transport
+    }
+
+    /// Starts downloading `torrent`, picking up the pieces already on disk.
+    /// Only `selected_files` are downloaded if given.
+    pub fn add_torrent(&mut self, torrent: Torrent, selected_files: Option<Vec<usize>>) {
+        if self.torrents.contains_key(&torrent.info_hash) {
+            println!("Already downloading {}", torrent.info.name);
+            return;
+        }
+
+        let progress = Arc::new(RwLock::new(TorrentProgress::new(
+            &torrent,
+            self.identity,
+            HaveSettings::from_env(),
+            Arc::clone(&self.bandwidth),
+            torrent_limit_from_env(),
+            Arc::clone(&self.disk),
+        )));
+        // Only download some of the files, leaving us a partial seed when done
+        if let Some(files) = &selected_files {
+            progress.write().unwrap().wanted_pieces = Some(
+                files
+                    .iter()
+                    .flat_map(|&file_index| torrent.file_pieces(file_index))
+                    .collect(),
+            );
+        }
+
+        let completed_pieces = Arc::new(AtomicU64::new(0));
+        load_pieces(&torrent, &progress, &completed_pieces);
+        println!(
+            "{} - Found existing {}/{} pieces",
+            torrent.info.name,
+            completed_pieces.load(SeqCst),
+            torrent.info.pieces.len()
+        );
+
+        let torrent = Arc::new(torrent);
+        let mut sessions = JoinSet::new();
+        // Web seeds have every piece, they are asked for whatever peers aren't
+        // downloading
+        let web_seeds = torrent
+            .web_seeds
+            .iter()
+            .map(|url| (url, WebSeedKind::GetRight));
+        let http_seeds = torrent
+            .http_seeds
+            .iter()
+            .map(|url| (url, WebSeedKind::Hoffman));
+        for (url, kind) in web_seeds.chain(http_seeds) {
+            sessions.spawn(run_web_seed(
+                url.clone(),
+                kind,
+                Arc::clone(&torrent),
+                Arc::clone(&progress),
+                Arc::clone(&completed_pieces),
+            ));
+        }
+
+        self.torrents.insert(
+            torrent.info_hash,
+            TorrentHandle {
+                torrent,
+                progress,
+                completed_pieces,
+                selected_files,
+                started_at: Instant::now(),
+                manager: Arc::new(Mutex::new(ConnectionManager::new(
+                    MAX_CONNECTED_PEERS,
+                    Arc::clone(&self.blocklist),
+                ))),
+                sessions,
+                looked_up_at: None,
+                finished: false,
+                announce_completed: false,
+                completed_sent: false,
+            },
+        );
+    }
+
+    /// Runs one round of upkeep, meant to be called every second. Returns
+    /// the torrents that got every piece we want since the last tick.
+    pub async fn tick(&mut self) -> Vec<&TorrentHandle> {
+        self.ticks += 1;
+        for handle in self.torrents.values() {
+            handle.print_progress();
+            if self.ticks.is_multiple_of(PIECE_CHECK_INTERVAL_TICKS) {
+                recheck_pieces(
+                    &handle.torrent,
+                    &handle.progress,
+                    &handle.completed_pieces,
+                    false,
+                );
+            }
+            if self.ticks.is_multiple_of(PEER_STATS_INTERVAL_TICKS) {
+                print_peer_stats(&handle.progress.read().unwrap());
+            }
+        }
+        if self.ticks.is_multiple_of(PEER_STATS_INTERVAL_TICKS) && self.blocklist.num_ranges() > 0 {
+            println!(
+                "  {} peers blocked by the IP filter",
+                self.blocklist.blocked_count()
+            );
+        }
+
+        // Done torrents stay, we seed them from here on. A piece going
+        // missing makes one unfinished again
+        let mut finished = vec![];
+        for (&info_hash, handle) in self.torrents.iter_mut() {
+            let done = handle.is_done();
+            if done && !handle.finished {
+                finished.push(info_hash);
+                handle.announce_completed =
+                    !handle.completed_sent && handle.looked_up_at.is_some() && handle.is_seed();
+            }
+            handle.finished = done;
+        }
+
+        self.route_incoming();
+        self.collect_lookup().await;
+        self.start_lookup();
+        for handle in self.torrents.values_mut() {
+            dial_peers(handle, &self.connections);
+            // Reap sessions that have already finished
+            while handle.sessions.try_join_next().is_some() {}
+        }
+
+        finished
+            .iter()
+            .map(|info_hash| &self.torrents[info_hash])
+            .collect()
+    }
+
+    /// Waits until every piece written so far is on disk.
+    pub fn flush(&self) {
+        self.disk.flush();
+    }
+
+    /// Applies a command typed while we run: `limit <global|torrent|peer>
+    /// <down|up> <KiB/s>`, where a limit of 0 lifts it and a torrent limit
+    /// applies to each torrent, `blocklist reload` and `recheck`.
+    pub fn run_command(&self, line: &str) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["limit", scope, direction, kib] => {
+                let torrent_limits: Vec<Arc<RateLimit>> = self
+                    .torrents
+                    .values()
+                    .map(|handle| handle.progress.read().unwrap().rate_limit.clone())
+                    .collect();
+                set_limit(&self.bandwidth, &torrent_limits, scope, direction, kib)
+            }
+            ["blocklist", "reload"] => match self.blocklist.reload() {
+                Ok(ranges) => println!("Loaded {} blocked IP ranges", ranges),
+                Err(e) => println!("Failed to reload IP filter: {}", e),
+            },
+            ["recheck"] => {
+                for handle in self.torrents.values() {
+                    let torrent = Arc::clone(&handle.torrent);
+                    let progress = Arc::clone(&handle.progress);
+                    let completed_pieces = Arc::clone(&handle.completed_pieces);
+                    tokio::task::spawn_blocking(move || {
+                        let retracted =
+                            recheck_pieces(&torrent, &progress, &completed_pieces, true);
+                        println!(
+                            "{} - Recheck done, {} pieces retracted",
+                            torrent.info.name, retracted
+                        );
+                    });
+                }
+            }
+            _ => {
+                println!("Usage: limit <global|torrent|peer> <down|up> <KiB/s> | blocklist reload | recheck")
+            }
+        }
+    }
+
+    /// Hands the peers the last lookup found to its torrent, once it's done.
+    async fn collect_lookup(&mut self) {
+        if !self
+            .lookup
+            .as_ref()
+            .is_some_and(|(_, lookup)| lookup.is_finished())
+        {
+            return;
+        }
+        let (info_hash, lookup) = self.lookup.take().unwrap();
+        // The torrent may have been removed in the meantime
+        let Some(handle) = self.torrents.get(&info_hash) else {
+            return;
+        };
+        let name = &handle.torrent.info.name;
+        match lookup.await {
+            Ok(Ok(peers)) => {
+                println!("{} - Found {} peers", name, peers.len());
+                let mut manager = handle.manager.lock().unwrap();
+                for peer in peers {
+                    manager.add_candidate(peer);
+                }
+            }
+            Ok(Err(e)) => println!("{} - Failed to get peers: {}", name, e),
+            Err(e) => println!("{} - Peer lookup panicked: {}", name, e),
+        }
+    }
+
+    /// Looks up the peers of the torrent that went longest without a lookup,
+    /// among those with room for more, unless a lookup is still running.
+    fn start_lookup(&mut self) {
+        if self.lookup.is_some() {
+            return;
+        }
+        let now = Instant::now();
+        let Some(handle) = self
+            .torrents
+            .values_mut()
+            .filter(|handle| {
+                handle.progress.read().unwrap().connected_peers.len() < MAX_CONNECTED_PEERS
+                    && handle
+                        .looked_up_at
+                        .is_none_or(|at| now.duration_since(at) >= LOOKUP_INTERVAL)
+            })
+            .min_by_key(|handle| handle.looked_up_at)
+        else {
+            return;
+        };
+        let first = handle.looked_up_at.replace(now).is_none();
+
+        // Tracker and DHT lookups are still blocking, keep them off the
+        // runtime's worker threads
+        let torrent = Arc::clone(&handle.torrent);
+        let utp = Arc::clone(&self.utp);
+        let peer_id = self.identity.peer_id;
+        let progress = handle.progress.read().unwrap();
+        // Partial seeds announce themselves as paused every time (BEP 21),
+        // the rest of the events are sent once
+        let event = if std::mem::take(&mut handle.announce_completed) {
+            handle.completed_sent = true;
+            Event::Completed
+        } else if progress.is_upload_only() && !handle.is_seed() {
+            Event::Paused
+        } else if first {
+            Event::Started
+        } else {
+            Event::Empty
+        };
+        let stats = AnnounceStats::new(&torrent, &progress);
+        drop(progress);
+        let lookup = tokio::task::spawn_blocking(move || {
+            get_peers_from_torrent(&torrent, &peer_id, &utp, event, stats)
+        });
+        self.lookup = Some((handle.torrent.info_hash, lookup));
+    }
+
+    /// Tells the trackers of every torrent we announced that we're leaving
+    /// the swarm.
+    pub async fn stop(&self) {
+        let mut announces = JoinSet::new();
+        for handle in self.torrents.values() {
+            if handle.looked_up_at.is_none() {
+                continue;
+            }
+            let torrent = Arc::clone(&handle.torrent);
+            let peer_id = self.identity.peer_id;
+            let stats = AnnounceStats::new(&torrent, &handle.progress.read().unwrap());
+            announces.spawn_blocking(move || announce_stopped(&torrent, &peer_id, stats));
+        }
+        // Trackers that don't answer don't get to hold up the exit
+        let _ = tokio::time::timeout(STOP_ANNOUNCE_TIMEOUT, async {
+            while announces.join_next().await.is_some() {}
+        })
+        .await;
+    }
+
+    /// Works out which torrent each new incoming connection is for, and
+    /// starts sessions for the ones that are done with that.
+    fn route_incoming(&mut self) {
+        let info_hashes: Arc<[[u8; 20]]> = self.torrents.keys().copied().collect();
+        while let Ok((stream, peer)) = self.incoming.try_recv() {
+            if self.blocklist.is_blocked(&peer.ip) {
+                self.blocklist.record_blocked();
+                continue;
+            }
+            // The handshakes take a round trip or two, keep them off the loop
+            let transport = self.connections.transport.clone();
+            let routed = self.routed_tx.clone();
+            let info_hashes = Arc::clone(&info_hashes);
+            tokio::spawn(async move {
+                if let Ok((stream, info_hash)) = transport.accept(stream, &info_hashes).await {
+                    let _ = routed.send((stream, peer, info_hash)).await;
+                }
+            });
+        }
+
+        while let Ok((stream, peer, info_hash)) = self.routed_rx.try_recv() {
+            // The torrent may have finished in the meantime
+            let Some(handle) = self.torrents.get_mut(&info_hash) else {
+                continue;
+            };
+            if handle.progress.read().unwrap().connected_peers.len() >= MAX_CONNECTED_PEERS {
+                continue;
+            }
+            spawn_peer_session(
+                handle,
+                peer,
+                Opening::Accepted(Box::new(stream)),
+                &self.connections,
+            );
+        }
+    }
+}
+
+/// Dials the best candidates the torrent has room for, and the peers relays
+/// introduced us to.
+fn dial_peers(handle: &mut TorrentHandle, connections: &Connections) {
+    // Blocks held by peers that went quiet become requestable again
+    handle
+        .progress
+        .write()
+        .unwrap()
+        .release_expired_reservations(Instant::now());
+
+    // Peers other peers told us about over PEX
+    let candidates: Vec<Peer> = handle
+        .progress
+        .write()
+        .unwrap()
+        .candidate_peers
+        .drain()
+        .collect();
+    let to_dial = {
+        let progress = handle.progress.read().unwrap();
+        let mut manager = handle.manager.lock().unwrap();
+        for peer in candidates {
+            manager.add_candidate(peer);
+        }
+        for peer in &progress.connected_peers {
+            if let Some(stats) = progress.peer_stats.get(&peer.to_string()) {
+                manager.record_stats(peer, stats.download_rate, stats.hash_failures);
+            }
+        }
+
+        let limit = manager
+            .free_slots()
+            .min(MAX_CONNECTED_PEERS.saturating_sub(progress.connected_peers.len()))
+            .min(connections.transport.free_half_open_slots())
+            .min(connections.slots.available_permits());
+        manager.candidates(limit, &progress.connected_peers, Instant::now())
+    };
+    for peer in to_dial {
+        spawn_peer_session(handle, peer, Opening::Dial, connections);
+    }
+
+    // Introductions relayed to us (BEP 55)
+    let (to_holepunch, to_punch) = handle.progress.write().unwrap().holepunch.take_connects();
+    for peer in &to_punch {
+        connections.transport.punch(peer);
+    }
+    for peer in to_holepunch {
+        spawn_peer_session(handle, peer, Opening::Holepunch, connections);
+    }
+}
+
+/// Starts a session with `peer` over the connection `opening` describes, if
+/// the connection limits leave room for it.
+fn spawn_peer_session(
+    handle: &mut TorrentHandle,
+    peer: Peer,
+    opening: Opening,
+    connections: &Connections,
+) {
+    if handle
+        .progress
+        .read()
+        .unwrap()
+        .banned_ips
+        .contains(&peer.ip)
+    {
+        return;
+    }
+    let Ok(slot) = Arc::clone(&connections.slots).try_acquire_owned() else {
+        return;
+    };
+    if !handle.manager.lock().unwrap().opened(&peer) {
+        return;
+    }
+    handle
+        .progress
+        .write()
+        .unwrap()
+        .connected_peers
+        .insert(peer.clone());
+
+    let progress = Arc::clone(&handle.progress);
+    let torrent = Arc::clone(&handle.torrent);
+    let completed_pieces = Arc::clone(&handle.completed_pieces);
+    let manager = Arc::clone(&handle.manager);
+    let transport = connections.transport.clone();
+    handle.sessions.spawn(async move {
+        let _slot: OwnedSemaphorePermit = slot;
+        let mut closed = SessionClosed {
+            peer: peer.clone(),
+            manager,
+            progress: Arc::clone(&progress),
+            succeeded: false,
+        };
+        let result = match opening {
+            Opening::Accepted(stream) => {
+                accept_peer(*stream, &peer, &torrent, progress.clone(), completed_pieces).await
+            }
+            Opening::Dial => {
+                connect_to_peer(
+                    &peer,
+                    &torrent,
+                    &transport,
+                    progress.clone(),
+                    completed_pieces,
+                )
+                .await
+            }
+            Opening::Holepunch => {
+                holepunch_peer(
+                    &peer,
+                    &torrent,
+                    &transport,
+                    progress.clone(),
+                    completed_pieces,
+                )
+                .await
+            }
+        };
+        // Peers we never got a handshake out of are backed off
+        closed.succeeded = !matches!(
+            result,
+            Err(PeerProtocolError::FailedToConnect | PeerProtocolError::HandshakeError(_))
+        );
+        drop(closed);
+        let unreachable = matches!(result, Err(PeerProtocolError::FailedToConnect));
+        match result {
+            Ok(_) => {}
+            Err(err) => match err {
+                PeerProtocolError::InvalidMessage(e) => {
+                    println!("Protocol error from peer {}:{} - {}", peer.ip, peer.port, e);
+                }
+                PeerProtocolError::ReceivedError(e) => {
+                    println!("Receive error with peer {}:{} - {}", peer.ip, peer.port, e);
+                }
+                _ => {}
+            },
+        }
+
+        // Maybe it's behind a NAT, and the peer that told us about it can
+        // introduce us
+        if unreachable
+            && transport.utp.is_some()
+            && progress.write().unwrap().holepunch.request(&peer)
+        {
+            println!("Asking for an introduction to {}", peer.to_string());
+        }
+    });
+}
+
+/// Hands a session's connection back once its task ends, even if the session
+/// panicked on the way.
+struct SessionClosed {
+    peer: Peer,
+    manager: Arc<Mutex<ConnectionManager>>,
+    progress: Arc<RwLock<TorrentProgress>>,
+    /// Whether we got past the handshake, so the peer isn't backed off.
+    succeeded: bool,
+}
+
+impl Drop for SessionClosed {
+    fn drop(&mut self) {
+        self.manager
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .closed(&self.peer, self.succeeded, Instant::now());
+        self.progress
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .connected_peers
+            .remove(&self.peer);
+    }
+}
+
+/// Hands connections made to our listener over to the session.
+async fn accept_incoming_peers(
+    listener: tokio::net::TcpListener,
+    incoming: mpsc::Sender<(Box<dyn PeerStream>, Peer)>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        if incoming
+            .send((Box::new(stream), Peer::from(addr)))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Hands uTP connections peers opened to us over to the session.
+async fn accept_incoming_utp_peers(
+    utp: Arc<UtpSocket>,
+    incoming: mpsc::Sender<(Box<dyn PeerStream>, Peer)>,
+) {
+    while let Some(stream) = utp.accept().await {
+        let peer = Peer::from(stream.peer_addr());
+        if incoming.send((Box::new(stream), peer)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Marks the pieces whose files are already on disk, and check out, as
+/// completed.
+fn load_pieces(
+    torrent: &Torrent,
+    progress: &RwLock<TorrentProgress>,
+    completed_pieces: &AtomicU64,
+) {
+    let pieces_dir = progress.read().unwrap().pieces_dir.clone();
+    let Ok(files) = std::fs::read_dir(&pieces_dir) else {
+        return;
+    };
+    let files: Vec<_> = files.collect();
+
+    let loaded_pieces: Vec<(u32, Vec<u8>)> = files
+        .par_iter()
+        .filter_map(|entry| {
+            let entry = entry.as_ref().expect("Failed to read directory entry");
+            let path = entry.path();
+
+            if !path.is_file() {
+                return None;
+            }
+
+            let piece_index = path.file_stem()?.to_str()?.parse::<u32>().ok()?;
+            if piece_index as usize >= torrent.info.pieces.len() {
+                return None;
+            }
+
+            let data = std::fs::read(&path).expect("Failed to read piece file");
+
+            let expected_length = torrent.get_piece_length(piece_index as usize);
+            if data.len() as u32 != expected_length {
+                println!("Warning: Piece {} incorrect length", piece_index);
+                return None;
+            }
+
+            let expected_hash = torrent.info.pieces[piece_index as usize];
+            let actual_hash: [u8; 20] = Sha1::digest(&data).into();
+            if expected_hash != actual_hash {
+                println!("Warning: Piece {} incorrect hash", piece_index);
+                return None;
+            }
+
+            Some((piece_index, data))
+        })
+        .collect();
+
+    let count = loaded_pieces.len() as u64;
+    let mut progress = progress.write().unwrap();
+    for (piece_index, data) in loaded_pieces {
+        progress
+            .pieces
+            .insert(piece_index, PieceProgress::Completed(data));
+    }
+    completed_pieces.fetch_add(count, SeqCst);
+}
+
+/// Checks the pieces we have against their files on disk, retracting the
+/// ones whose file is gone or, with `verify`, fails its hash check. Returns
+/// how many were retracted.
+fn recheck_pieces(
+    torrent: &Torrent,
+    progress: &RwLock<TorrentProgress>,
+    completed_pieces: &AtomicU64,
+    verify: bool,
+) -> usize {
+    let (completed, disk): (Vec<(u32, std::path::PathBuf)>, _) = {
+        let progress = progress.read().unwrap();
+        let completed = progress
+            .pieces
+            .iter()
+            .filter(|(_, piece)| matches!(piece, PieceProgress::Completed(_)))
+            .map(|(&index, _)| (index, progress.piece_path(index)))
+            .collect();
+        (completed, Arc::clone(&progress.disk))
+    };
+    // A piece is queued for writing as it completes, wait for those writes
+    // so none of them looks like a removed file
+    disk.flush();
+
+    let failed: Vec<(u32, &str)> = completed
+        .par_iter()
+        .filter_map(|(index, path)| {
+            let index = *index;
+            if !verify {
+                return (!path.exists()).then_some((index, "file removed"));
+            }
+            match std::fs::read(path) {
+                Err(_) => Some((index, "file removed")),
+                Ok(data) if Sha1::digest(&data)[..] != torrent.info.pieces[index as usize] => {
+                    Some((index, "failed its hash check"))
+                }
+                Ok(_) => None,
+            }
+        })
+        .collect();
+
+    let mut progress = progress.write().unwrap();
+    for &(index, reason) in &failed {
+        if progress.retract_piece(torrent, index, completed_pieces) {
+            println!("Retracted piece {}, {}", index, reason);
+        }
+    }
+    failed.len()
+}
+
+fn set_limit(
+    bandwidth: &Bandwidth,
+    torrent_limits: &[Arc<RateLimit>],
+    scope: &str,
+    direction: &str,
+    kib: &str,
+) {
+    let direction = match direction {
+        "down" => Direction::Download,
+        "up" => Direction::Upload,
+        _ => {
+            println!("Unknown direction: {}", direction);
+            return;
+        }
+    };
+    let Ok(rate) = kib.parse::<u64>().map(|kib| kib * 1024) else {
+        println!("Invalid limit: {}", kib);
+        return;
+    };
+
+    let limits: Vec<&RateLimit> = match scope {
+        "global" => vec![&bandwidth.global],
+        "torrent" => torrent_limits.iter().map(|limit| &**limit).collect(),
+        "peer" => {
+            bandwidth.set_peer_limit(direction, rate);
+            return;
+        }
+        _ => {
+            println!("Unknown scope: {}", scope);
+            return;
+        }
+    };
+    for limit in limits {
+        match direction {
+            Direction::Download => limit.download.set_rate(rate),
+            Direction::Upload => limit.upload.set_rate(rate),
+        }
+    }
+}
+
+fn print_peer_stats(progress: &TorrentProgress) {
+    let mut stats: Vec<_> = progress.peer_stats.iter().collect();
+    stats.sort_by(|(_, a), (_, b)| b.download_rate.total_cmp(&a.download_rate));
+
+    for (peer, stats) in stats {
+        let hash_failures = match stats.hash_failures {
+            0 => String::new(),
+            count => format!("  hashfails {}", count),
+        };
+        println!(
+            "  {:<22} {:<20} {:>9.1} KiB/s  rtt {:>5}ms  queue {:>3}  inflight {:>3}  [{}]{}{}{}",
+            peer,
+            stats.client,
+            stats.download_rate / 1024.0,
+            stats.rtt.map_or(0, |rtt| rtt.as_millis()),
+            stats.queue_depth,
+            stats.inflight,
+            stats.capabilities.join(","),
+            if stats.is_choked { "  choked" } else { "" },
+            if stats.is_snubbed { "  snubbed" } else { "" },
+            hash_failures,
+        );
+    }
+    if !progress.banned_ips.is_empty() {
+        println!("  {} banned", progress.banned_ips.len());
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::{atomic::AtomicU64, RwLock};
+
+use crate::{
+    bencoding::torrent::Torrent,
+    peer::types::{PieceProgress, TorrentProgress},
+    session::recheck_pieces,
+};
+
+#[test]
+fn recheck_waits_for_pending_writes() {
+    let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+    let torrent = Torrent::for_test("recheck.bin", 16 * 1024, &data, None);
+    let mut progress = TorrentProgress::for_test(&torrent);
+    let _ = std::fs::remove_dir_all(progress.piece_path(0).parent().unwrap());
+
+    // Keep the disk thread busy, so the piece's own write is still queued
+    // when the recheck starts
+    for i in 0..4 {
+        let path = progress.piece_path(0).with_file_name(format!("busy{}", i));
+        progress.disk.write(path, vec![0; 4 * 1024 * 1024]);
+    }
+    let piece = data[..16 * 1024].to_vec();
+    progress.disk.write(progress.piece_path(0), piece.clone());
+    progress.pieces.insert(0, PieceProgress::Completed(piece));
+    let progress = RwLock::new(progress);
+    let completed_pieces = AtomicU64::new(1);
+
+    assert_eq!(
+        recheck_pieces(&torrent, &progress, &completed_pieces, false),
+        0
+    );
+    assert!(matches!(
+        progress.read().unwrap().pieces.get(&0),
+        Some(PieceProgress::Completed(_))
+    ));
+}
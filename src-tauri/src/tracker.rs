@@ -0,0 +1,316 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{atomic::Ordering::SeqCst, Arc},
+};
+
+use crate::{
+    bencoding::torrent::{Torrent, Tracker},
+    connection::{
+        Action, AnnounceRequest, AnnounceResponse, ConnectRequest, ConnectResponse, Event,
+        HTTPResponse, Peer, ToUrl, TrackerRequest, TrackerResponse,
+    },
+    dht::dht_node::DhtClient,
+    peer::{peer_protocol::LISTEN_PORT, types::TorrentProgress},
+    utp::socket::UtpSocket,
+};
+
+/// What we report to trackers about our transfer of a torrent.
+#[derive(Clone, Copy, Debug)]
+pub struct AnnounceStats {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub left: u64,
+}
+
+impl AnnounceStats {
+    pub fn new(torrent: &Torrent, progress: &TorrentProgress) -> Self {
+        AnnounceStats {
+            downloaded: progress.downloaded.load(SeqCst),
+            uploaded: progress.uploaded.load(SeqCst),
+            left: torrent.total_length() - progress.completed_bytes(),
+        }
+    }
+}
+
+/// Peers for `torrent` from its trackers, or from the DHT when it has none.
+pub fn get_peers_from_torrent(
+    torrent: &Torrent,
+    peer_id: &[u8; 20],
+    utp: &Arc<UtpSocket>,
+    event: Event,
+    stats: AnnounceStats,
+) -> Result<Vec<Peer>, String> {
+    let http_trackers = torrent
+        .trackers
+        .iter()
+        .filter(|t| matches!(t, Tracker::Http(_)))
+        .map(|t| String::from(t.clone()))
+        .collect::<Vec<_>>();
+    let udp_trackers = torrent
+        .trackers
+        .iter()
+        .filter(|t| matches!(t, Tracker::Udp(url) if url.starts_with("udp://")))
+        .map(|t| String::from(t.clone()))
+        .collect::<Vec<_>>();
+    let dht_trackers: Vec<_> = torrent
+        .trackers
+        .iter()
+        .filter_map(|t| {
+            if let Tracker::Dht(addr) = t {
+                Some(addr.clone())
+            } else {
+                None
+            }
+        })
+        .chain([
+            "router.bittorrent.com:6881".to_string(),
+            "dht.transmissionbt.com:6881".to_string(),
+            "router.utorrent.com:6881".to_string(),
+        ])
+        .collect();
+
+    if http_trackers.is_empty() && udp_trackers.is_empty() {
+        return get_peers_dht(utp, &torrent.info_hash, dht_trackers);
+    }
+
+    let udp_peers: Vec<Peer> = udp_trackers
+        .iter()
+        .flat_map(
+            |tracker| match get_peers_udp(torrent, peer_id, tracker, event, stats) {
+                Ok(peers) => peers,
+                Err(err) => {
+                    println!("Error getting peers from tracker {}: {}", tracker, err);
+                    vec![]
+                }
+            },
+        )
+        .collect();
+
+    Ok(http_trackers
+        .into_iter()
+        .flat_map(|tracker| {
+            let response = match get_peers_http(torrent, peer_id, &tracker, event, stats) {
+                Ok(res) => res,
+                Err(err) => {
+                    println!("Error getting peers from tracker {}: {}", tracker, err);
+                    return vec![];
+                }
+            };
+
+            println!("Tracker Response: {:?}", response);
+
+            if let Some(err) = response.failure {
+                println!("Tracker failure reason: {:?}", err);
+                return vec![];
+            }
+
+            let response = response.success.expect("No success response from tracker");
+            println!("Interval: {}", response.interval);
+            println!("Leechers: {}", response.incomplete.unwrap_or(0));
+            println!("Seeders: {}", response.complete.unwrap_or(0));
+            println!("Peers: {}", response.peers.len());
+
+            if response.peers.is_empty() {
+                println!("No peers available from tracker");
+                return vec![];
+            }
+
+            response.peers
+        })
+        .chain(udp_peers)
+        .collect())
+}
+
+/// Tells `torrent`'s trackers we're leaving its swarm. The DHT has nothing
+/// like it, our entries there just expire.
+pub fn announce_stopped(torrent: &Torrent, peer_id: &[u8; 20], stats: AnnounceStats) {
+    for tracker in &torrent.trackers {
+        let result = match tracker {
+            Tracker::Http(url) => {
+                get_peers_http(torrent, peer_id, url, Event::Stopped, stats).map(|_| ())
+            }
+            Tracker::Udp(url) if url.starts_with("udp://") => {
+                get_peers_udp(torrent, peer_id, url, Event::Stopped, stats).map(|_| ())
+            }
+            _ => continue,
+        };
+        if let Err(err) = result {
+            println!(
+                "Error announcing stop to tracker {}: {}",
+                String::from(tracker.clone()),
+                err
+            );
+        }
+    }
+}
+
+pub fn get_peers_dht(
+    utp: &Arc<UtpSocket>,
+    info_hash: &[u8; 20],
+    trackers: Vec<String>,
+) -> Result<Vec<Peer>, String> {
+    println!("No HTTP trackers found, falling back to DHT");
+    DhtClient::new(Arc::clone(utp), trackers).get_peers(info_hash)
+}
+
+/// How long we wait for each reply from a UDP tracker.
+const UDP_TRACKER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Announces to a UDP tracker (BEP 15) over every address family it has an
+/// address for, as trackers only hand out peers of the family they were
+/// reached over.
+fn get_peers_udp(
+    torrent: &Torrent,
+    peer_id: &[u8; 20],
+    tracker: &str,
+    event: Event,
+    stats: AnnounceStats,
+) -> Result<Vec<Peer>, String> {
+    println!("Testing UDP tracker: {}", tracker);
+
+    let url = url::Url::parse(tracker).map_err(|e| format!("Invalid tracker URL: {}", e))?;
+    let addrs = url
+        .socket_addrs(|| None)
+        .map_err(|e| format!("Failed to resolve tracker: {}", e))?;
+
+    let mut peers = vec![];
+    let mut errors = vec![];
+    for addr in [
+        addrs.iter().find(|a| a.is_ipv4()),
+        addrs.iter().find(|a| a.is_ipv6()),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        match announce_udp(torrent, peer_id, *addr, event, stats) {
+            Ok(response) => {
+                println!(
+                    "{} - Leechers: {} Seeders: {} Peers: {}",
+                    addr,
+                    response.leechers,
+                    response.seeders,
+                    response.peers.len()
+                );
+                peers.extend(response.peers);
+            }
+            Err(e) => errors.push(format!("{}: {}", addr, e)),
+        }
+    }
+
+    if peers.is_empty() && !errors.is_empty() {
+        return Err(errors.join(", "));
+    }
+    Ok(peers)
+}
+
+fn announce_udp(
+    torrent: &Torrent,
+    peer_id: &[u8; 20],
+    addr: SocketAddr,
+    event: Event,
+    stats: AnnounceStats,
+) -> Result<AnnounceResponse, String> {
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).map_err(|e| format!("Failed to bind socket: {}", e))?;
+    socket
+        .set_read_timeout(Some(UDP_TRACKER_TIMEOUT))
+        .map_err(|e| format!("Failed to set timeout: {}", e))?;
+    socket
+        .connect(addr)
+        .map_err(|e| format!("Failed to connect: {}", e))?;
+
+    let mut buf = [0; 65536];
+    let transaction_id = rand::random::<u32>();
+    socket
+        .send(&Vec::from(ConnectRequest { transaction_id }))
+        .map_err(|e| format!("Failed to send connect request: {}", e))?;
+    let size = socket
+        .recv(&mut buf)
+        .map_err(|e| format!("No connect response: {}", e))?;
+    let connect_response = ConnectResponse::try_from(&buf[..size])?;
+    if connect_response.action != Action::Connect as u32
+        || connect_response.transaction_id != transaction_id
+    {
+        return Err("Invalid connect response".to_string());
+    }
+
+    let transaction_id = rand::random::<u32>();
+    let request = AnnounceRequest {
+        connection_id: connect_response.connection_id,
+        action: Action::Announce,
+        transaction_id,
+        info_hash: torrent.info_hash,
+        peer_id: *peer_id,
+        downloaded: stats.downloaded,
+        left: stats.left,
+        uploaded: stats.uploaded,
+        event,
+        ip: None,
+        key: rand::random::<u32>(),
+        num_want: -1,
+        port: LISTEN_PORT,
+    };
+    socket
+        .send(&Vec::from(request))
+        .map_err(|e| format!("Failed to send announce request: {}", e))?;
+    let size = socket
+        .recv(&mut buf)
+        .map_err(|e| format!("No announce response: {}", e))?;
+    if size < 20 {
+        return Err(format!("Announce response too short: {} bytes", size));
+    }
+
+    let response = AnnounceResponse::from_bytes(&buf[..size], addr.is_ipv6());
+    if response.action != Action::Announce as u32 || response.transaction_id != transaction_id {
+        return Err("Invalid announce response".to_string());
+    }
+    Ok(response)
+}
+
+fn get_peers_http(
+    torrent: &Torrent,
+    peer_id: &[u8; 20],
+    tracker: &str,
+    event: Event,
+    stats: AnnounceStats,
+) -> Result<TrackerResponse, String> {
+    println!("Testing HTTP tracker: {}", tracker);
+
+    // send a connect request
+    let connection_request = TrackerRequest {
+        info_hash: torrent.info_hash,
+        peer_id: *peer_id,
+        downloaded: stats.downloaded,
+        left: stats.left,
+        uploaded: stats.uploaded,
+        event,
+        ip: None,
+        key: None,
+        num_want: Some(100),
+        port: LISTEN_PORT,
+        compact: 1,
+        no_peer_id: false,
+        tracker_id: None,
+    };
+
+    let url = format!("{}{}", tracker, connection_request.to_url_params());
+    println!("Request URL: {}", url);
+    let response = reqwest::blocking::get(&url).map_err(|_| "Failed to send request")?;
+    let status = response.status();
+    println!("Response Status: {}", status);
+
+    let bytes = response
+        .bytes()
+        .map_err(|e| format!("Failed to read response: {}", e))?;
+    let text = String::from_utf8_lossy(&bytes);
+    println!("Response Body: {:?}", text);
+
+    if !status.is_success() {
+        return Err("Failed to get a successful response from the tracker".to_string());
+    }
+
+    Ok(TrackerResponse::from_http_response(bytes.as_ref()))
+}
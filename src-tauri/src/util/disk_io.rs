@@ -0,0 +1,71 @@
+use std::{
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+    sync::mpsc,
+};
+
+enum DiskJob {
+    Write(PathBuf, Vec<u8>),
+    /// Answered once every write queued before it is done.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Writes completed pieces for every torrent from one thread, so sessions
+/// finishing a piece never wait on the disk and torrents don't compete for
+/// it. Each torrent keeps its pieces in its own directory under `root`.
+pub struct DiskIo {
+    root: PathBuf,
+    jobs: mpsc::Sender<DiskJob>,
+}
+
+impl DiskIo {
+    pub fn new(root: PathBuf) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        std::thread::spawn(move || {
+            for job in queue {
+                match job {
+                    DiskJob::Write(path, data) => {
+                        if let Err(e) = write_file(&path, &data) {
+                            println!("Failed to write {}: {}", path.display(), e);
+                        }
+                    }
+                    DiskJob::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        DiskIo { root, jobs }
+    }
+
+    /// Keeps pieces under `PIECES_DIR`, `/pieces` by default.
+    pub fn from_env() -> Self {
+        let root = std::env::var("PIECES_DIR").unwrap_or_else(|_| "/pieces".to_string());
+        DiskIo::new(PathBuf::from(root))
+    }
+
+    /// Where the torrent with `info_hash` keeps its pieces.
+    pub fn torrent_dir(&self, info_hash: &[u8; 20]) -> PathBuf {
+        self.root.join(hex::encode(info_hash))
+    }
+
+    /// Queues `data` to be written to `path`.
+    pub fn write(&self, path: PathBuf, data: Vec<u8>) {
+        let _ = self.jobs.send(DiskJob::Write(path, data));
+    }
+
+    /// Blocks until everything queued so far is on disk.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.jobs.send(DiskJob::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+fn write_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    std::fs::write(path, data)
+}
@@ -0,0 +1,59 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Binds a UDP socket that serves IPv4 and IPv6 alike. Falls back to plain
+/// IPv4 on hosts without IPv6.
+pub fn bind_udp(port: u16) -> std::io::Result<UdpSocket> {
+    match bind_v6(port, Type::DGRAM, Protocol::UDP) {
+        Ok(socket) => Ok(socket.into()),
+        Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)),
+    }
+}
+
+/// Binds a TCP listener that accepts IPv4 and IPv6 connections. Falls back to
+/// plain IPv4 on hosts without IPv6.
+pub fn bind_tcp(port: u16) -> std::io::Result<TcpListener> {
+    match bind_v6(port, Type::STREAM, Protocol::TCP) {
+        Ok(socket) => {
+            socket.listen(128)?;
+            Ok(socket.into())
+        }
+        Err(_) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)),
+    }
+}
+
+fn bind_v6(port: u16, ty: Type, protocol: Protocol) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, ty, Some(protocol))?;
+    // Some platforms default to IPv6 only
+    socket.set_only_v6(false)?;
+    if ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+    Ok(socket)
+}
+
+/// Resolves `location` to an address a socket bound to `local` can send to.
+pub fn resolve_for(local: SocketAddr, location: &str) -> Result<SocketAddr, String> {
+    let mut addrs = location
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve {}: {}", location, e))?;
+
+    let addr = match local {
+        SocketAddr::V4(_) => addrs.find(SocketAddr::is_ipv4),
+        SocketAddr::V6(_) => addrs.next(),
+    };
+    addr.map(|addr| map_for(local, addr))
+        .ok_or_else(|| format!("No usable address for {}", location))
+}
+
+/// A dual-stack socket reaches IPv4 hosts through IPv4-mapped addresses.
+pub fn map_for(local: SocketAddr, addr: SocketAddr) -> SocketAddr {
+    match (local, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        _ => addr,
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[cfg(test)]
+mod tests;
+
+/// RC4 as used by Message Stream Encryption.
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// MSE throws away the first 1024 bytes of keystream, they leak
+    /// information about the key.
+    const DISCARD: usize = 1024;
+
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        let mut rc4 = Rc4 { state, i: 0, j: 0 };
+        rc4.apply(&mut [0; Self::DISCARD]);
+        rc4
+    }
+
+    /// Encrypts or decrypts `data` in place.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+/// A peer connection that may be RC4 encrypted in either direction. Sits
+/// below `PeerMessageStream`, so the wire protocol never knows whether the
+/// connection is encrypted.
+pub struct EncryptedStream<S> {
+    stream: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    /// Already decrypted bytes received during the encryption handshake.
+    read_prefix: Vec<u8>,
+    /// Encrypted bytes accepted from the caller but not yet written.
+    write_pending: Vec<u8>,
+}
+
+impl<S> EncryptedStream<S> {
+    pub fn plaintext(stream: S, read_prefix: Vec<u8>) -> Self {
+        EncryptedStream {
+            stream,
+            read_cipher: None,
+            write_cipher: None,
+            read_prefix,
+            write_pending: vec![],
+        }
+    }
+
+    pub fn encrypted(stream: S, read_cipher: Rc4, write_cipher: Rc4, read_prefix: Vec<u8>) -> Self {
+        EncryptedStream {
+            stream,
+            read_cipher: Some(read_cipher),
+            write_cipher: Some(write_cipher),
+            read_prefix,
+            write_pending: vec![],
+        }
+    }
+}
+
+impl<S> EncryptedStream<S> {
+    /// Puts already read bytes back in front of whatever comes next.
+    pub fn unread(&mut self, bytes: Vec<u8>) {
+        self.read_prefix.splice(0..0, bytes);
+    }
+
+    #[cfg(test)]
+    pub fn is_encrypted(&self) -> bool {
+        self.read_cipher.is_some()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> EncryptedStream<S> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pending.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for EncryptedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_prefix.is_empty() {
+            let n = this.read_prefix.len().min(buf.remaining());
+            buf.put_slice(&this.read_prefix[..n]);
+            this.read_prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for EncryptedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        }
+
+        // The keystream can't be rewound, so once bytes are encrypted they
+        // have to be written eventually. Finish the previous write first.
+        ready!(this.poll_write_pending(cx))?;
+        let mut encrypted = buf.to_vec();
+        if let Some(cipher) = &mut this.write_cipher {
+            cipher.apply(&mut encrypted);
+        }
+        this.write_pending = encrypted;
+        // Whatever doesn't go out now is sent by the next write or flush
+        let _ = this.poll_write_pending(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
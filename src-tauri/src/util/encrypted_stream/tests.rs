@@ -0,0 +1,52 @@
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+use crate::util::encrypted_stream::{EncryptedStream, Rc4};
+
+#[test]
+fn rc4_skips_the_first_kilobyte_of_keystream() {
+    // RFC 6229, 40-bit key, keystream at offset 1024
+    let mut keystream = [0; 16];
+    Rc4::new(&[1, 2, 3, 4, 5]).apply(&mut keystream);
+    assert_eq!(hex::encode(keystream), "30abbcc7c20b01609f23ee2d5f6bb7df");
+}
+
+#[tokio::test]
+async fn encrypted_streams_round_trip() {
+    // Small enough that writes have to wait for the other side to read
+    let (a, b) = duplex(1024);
+    let mut a = EncryptedStream::encrypted(a, Rc4::new(b"ba"), Rc4::new(b"ab"), vec![]);
+    let mut b = EncryptedStream::encrypted(b, Rc4::new(b"ab"), Rc4::new(b"ba"), vec![]);
+    let sent: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+
+    let writer = {
+        let sent = sent.clone();
+        tokio::spawn(async move {
+            a.write_all(&sent).await.unwrap();
+            a.flush().await.unwrap();
+            a
+        })
+    };
+    let mut received = vec![0; sent.len()];
+    b.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, sent);
+
+    let mut a = writer.await.unwrap();
+    b.write_all(b"pong").await.unwrap();
+    b.flush().await.unwrap();
+    let mut reply = [0; 4];
+    a.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"pong");
+}
+
+#[tokio::test]
+async fn read_prefix_comes_first() {
+    let (a, mut b) = duplex(1024);
+    let mut a = EncryptedStream::plaintext(a, b"handshake".to_vec());
+    b.write_all(b" then the stream").await.unwrap();
+    a.unread(b"the ".to_vec());
+
+    let mut received = vec![0; 29];
+    a.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, b"the handshake then the stream");
+    assert!(!a.is_encrypted());
+}
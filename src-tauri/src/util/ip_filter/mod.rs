@@ -0,0 +1,220 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        RwLock,
+    },
+};
+
+#[cfg(test)]
+mod tests;
+
+/// eMule DAT entries with an access level below this are blocked.
+const DAT_BLOCK_LEVEL: u32 = 128;
+
+/// Blocked address ranges, merged and sorted so a lookup is a binary search
+/// even with millions of them.
+#[derive(Default)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    /// Adds the ranges of a blocklist, one per line, in PeerGuardian P2P
+    /// (`name:1.2.3.0-1.2.3.255`), eMule DAT (`1.2.3.0 - 1.2.3.255 , 0 , name`)
+    /// or CIDR (`1.2.3.0/24`) format. Formats can be mixed, and lines that
+    /// don't parse are skipped.
+    pub fn extend(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+
+            match parse_line(line) {
+                Some((IpAddr::V4(start), IpAddr::V4(end))) => {
+                    self.v4.push((u32::from(start), u32::from(end)))
+                }
+                Some((IpAddr::V6(start), IpAddr::V6(end))) => {
+                    self.v6.push((u128::from(start), u128::from(end)))
+                }
+                _ => {}
+            }
+        }
+
+        merge_ranges(&mut self.v4, |end| end.checked_add(1));
+        merge_ranges(&mut self.v6, |end| end.checked_add(1));
+    }
+
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => contains(&self.v6, u128::from(ip)),
+        }
+    }
+
+    pub fn num_ranges(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+}
+
+/// The filter loaded from the files listed in `IP_FILTER`, reloadable while
+/// we run, along with how many peers it has kept us from.
+pub struct Blocklist {
+    paths: Vec<PathBuf>,
+    filter: RwLock<IpFilter>,
+    blocked: AtomicU64,
+}
+
+impl Blocklist {
+    /// `IP_FILTER` holds a comma-separated list of blocklist files.
+    pub fn from_env() -> Self {
+        let paths = std::env::var("IP_FILTER")
+            .map(|paths| paths.split(',').map(PathBuf::from).collect())
+            .unwrap_or_default();
+        let blocklist = Blocklist {
+            paths,
+            filter: RwLock::new(IpFilter::default()),
+            blocked: AtomicU64::new(0),
+        };
+        match blocklist.reload() {
+            Ok(0) => {}
+            Ok(ranges) => println!("Loaded {} blocked IP ranges", ranges),
+            Err(e) => println!("Failed to load IP filter: {}", e),
+        }
+        blocklist
+    }
+
+    /// Reads the blocklist files again, returning the number of ranges. The
+    /// old filter stays in place if any of them can't be read.
+    pub fn reload(&self) -> Result<usize, String> {
+        let mut filter = IpFilter::default();
+        for path in &self.paths {
+            let text = std::fs::read(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            filter.extend(&String::from_utf8_lossy(&text));
+        }
+
+        let ranges = filter.num_ranges();
+        *self.filter.write().unwrap() = filter;
+        Ok(ranges)
+    }
+
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        self.filter.read().unwrap().is_blocked(ip)
+    }
+
+    /// Counts a peer we didn't connect to because of the filter.
+    pub fn record_blocked(&self) {
+        self.blocked.fetch_add(1, Relaxed);
+    }
+
+    pub fn blocked_count(&self) -> u64 {
+        self.blocked.load(Relaxed)
+    }
+
+    pub fn num_ranges(&self) -> usize {
+        self.filter.read().unwrap().num_ranges()
+    }
+}
+
+/// The first and last address a line blocks.
+fn parse_line(line: &str) -> Option<(IpAddr, IpAddr)> {
+    // DAT: range, access level, description. P2P descriptions can have
+    // commas too, but not followed by a number
+    if let Some((range, rest)) = line.split_once(',') {
+        let level = rest.split(',').next().unwrap_or_default().trim();
+        if let Ok(level) = level.parse::<u32>() {
+            if level >= DAT_BLOCK_LEVEL {
+                return None;
+            }
+            return parse_range(range);
+        }
+    }
+
+    if line.contains('-') {
+        return parse_range(line);
+    }
+
+    if let Some((network, prefix_length)) = line.split_once('/') {
+        return parse_cidr(network.trim(), prefix_length.trim().parse().ok()?);
+    }
+
+    let ip = parse_ip(line)?;
+    Some((ip, ip))
+}
+
+/// `start-end`, optionally after a P2P description and a colon.
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    let (start, end) = range.rsplit_once('-')?;
+    let start = start.trim();
+    let start = parse_ip(start).or_else(|| parse_ip(start.rsplit_once(':')?.1))?;
+    let end = parse_ip(end.trim())?;
+    match (start, end) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) if start <= end => {
+            Some((start, end))
+        }
+        _ => None,
+    }
+}
+
+fn parse_cidr(network: &str, prefix_length: u32) -> Option<(IpAddr, IpAddr)> {
+    match parse_ip(network)? {
+        IpAddr::V4(ip) if prefix_length <= 32 => {
+            let host_mask = u32::MAX.checked_shr(prefix_length).unwrap_or(0);
+            let start = u32::from(ip) & !host_mask;
+            Some((
+                IpAddr::V4(Ipv4Addr::from(start)),
+                IpAddr::V4(Ipv4Addr::from(start | host_mask)),
+            ))
+        }
+        IpAddr::V6(ip) if prefix_length <= 128 => {
+            let host_mask = u128::MAX.checked_shr(prefix_length).unwrap_or(0);
+            let start = u128::from(ip) & !host_mask;
+            Some((
+                IpAddr::V6(Ipv6Addr::from(start)),
+                IpAddr::V6(Ipv6Addr::from(start | host_mask)),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Also takes IPv4 addresses padded with zeros, like `001.002.003.004` in
+/// DAT files.
+fn parse_ip(text: &str) -> Option<IpAddr> {
+    if let Ok(ip) = text.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+
+    let octets: Vec<u8> = text
+        .split('.')
+        .map(|octet| octet.parse().ok())
+        .collect::<Option<_>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+/// Sorts `ranges` and joins the ones that overlap or touch.
+fn merge_ranges<T: Ord + Copy>(ranges: &mut Vec<(T, T)>, next: impl Fn(T) -> Option<T>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if next(last.1).is_none_or(|after| start <= after) => {
+                last.1 = last.1.max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    // The last range starting at or before `ip` is the only one that can
+    // hold it
+    let index = ranges.partition_point(|&(start, _)| start <= ip);
+    index > 0 && ranges[index - 1].1 >= ip
+}
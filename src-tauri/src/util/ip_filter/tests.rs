@@ -0,0 +1,122 @@
+use std::net::IpAddr;
+
+use crate::util::ip_filter::IpFilter;
+
+fn load(text: &str) -> IpFilter {
+    let mut filter = IpFilter::default();
+    filter.extend(text);
+    filter
+}
+
+fn blocked(filter: &IpFilter, ip: &str) -> bool {
+    filter.is_blocked(&ip.parse::<IpAddr>().unwrap())
+}
+
+#[test]
+fn parses_p2p_ranges() {
+    let filter = load(
+        "# comment\n\
+         Some org, with a comma:1.2.3.0-1.2.3.255\n\
+         other:10.0.0.5 - 10.0.0.9\n",
+    );
+    assert_eq!(filter.num_ranges(), 2);
+    assert!(blocked(&filter, "1.2.3.0"));
+    assert!(blocked(&filter, "1.2.3.255"));
+    assert!(!blocked(&filter, "1.2.4.0"));
+    assert!(blocked(&filter, "10.0.0.7"));
+    assert!(!blocked(&filter, "10.0.0.4"));
+    assert!(!blocked(&filter, "10.0.0.10"));
+}
+
+#[test]
+fn dat_entries_at_level_128_and_up_are_skipped() {
+    let filter = load(
+        "001.002.003.000 - 001.002.003.255 , 000 , blocked\n\
+         005.006.007.000 - 005.006.007.255 , 127 , blocked too\n\
+         008.008.008.000 - 008.008.008.255 , 128 , allowed\n\
+         009.009.009.000 - 009.009.009.255 , 200 , allowed\n",
+    );
+    assert_eq!(filter.num_ranges(), 2);
+    assert!(blocked(&filter, "1.2.3.4"));
+    assert!(blocked(&filter, "5.6.7.8"));
+    assert!(!blocked(&filter, "8.8.8.8"));
+    assert!(!blocked(&filter, "9.9.9.9"));
+}
+
+#[test]
+fn parses_cidr_ranges() {
+    let filter = load("192.168.1.77/24\n172.16.0.1/32\n");
+    assert!(blocked(&filter, "192.168.1.0"));
+    assert!(blocked(&filter, "192.168.1.255"));
+    assert!(!blocked(&filter, "192.168.2.0"));
+    assert!(blocked(&filter, "172.16.0.1"));
+    assert!(!blocked(&filter, "172.16.0.0"));
+    assert!(!blocked(&filter, "172.16.0.2"));
+
+    // /0 is everything
+    let everything = load("0.0.0.0/0");
+    assert!(blocked(&everything, "0.0.0.0"));
+    assert!(blocked(&everything, "255.255.255.255"));
+    assert!(!blocked(&everything, "::1"));
+
+    assert_eq!(load("1.2.3.4/33").num_ranges(), 0);
+}
+
+#[test]
+fn parses_ipv6_ranges() {
+    let filter = load(
+        "2001:db8::/32\n\
+         fe80::1 - fe80::ff\n\
+         ::1\n",
+    );
+    assert_eq!(filter.num_ranges(), 3);
+    assert!(blocked(&filter, "2001:db8:ffff::1"));
+    assert!(!blocked(&filter, "2001:db9::"));
+    assert!(blocked(&filter, "fe80::80"));
+    assert!(!blocked(&filter, "fe80::100"));
+    assert!(blocked(&filter, "::1"));
+    assert!(!blocked(&filter, "::2"));
+
+    let everything = load("::/0");
+    assert!(blocked(
+        &everything,
+        "ffff:ffff:ffff:ffff:ffff:ffff:ffff:fffe"
+    ));
+}
+
+#[test]
+fn ipv4_mapped_addresses_match_ipv4_ranges() {
+    let filter = load("1.2.3.0/24");
+    assert!(blocked(&filter, "::ffff:1.2.3.4"));
+    assert!(!blocked(&filter, "::ffff:1.2.4.4"));
+}
+
+#[test]
+fn merges_overlapping_and_adjacent_ranges() {
+    let filter = load(
+        "a:10.0.0.0-10.0.0.9\n\
+         b:10.0.0.10-10.0.0.19\n\
+         c:10.0.0.5-10.0.0.12\n\
+         d:10.0.0.21-10.0.0.30\n\
+         e:255.255.255.0-255.255.255.255\n\
+         f:255.255.255.128-255.255.255.255\n",
+    );
+    // 10.0.0.20 is the only gap
+    assert_eq!(filter.num_ranges(), 3);
+    assert!(blocked(&filter, "10.0.0.19"));
+    assert!(!blocked(&filter, "10.0.0.20"));
+    assert!(blocked(&filter, "10.0.0.21"));
+    assert!(blocked(&filter, "255.255.255.255"));
+}
+
+#[test]
+fn skips_lines_that_dont_parse() {
+    let filter = load(
+        "garbage\n\
+         // comment\n\
+         x:1.2.3.4-1.2.3.1\n\
+         y:1.2.3.4-::1\n\
+         300.1.1.1/8\n",
+    );
+    assert_eq!(filter.num_ranges(), 0);
+}
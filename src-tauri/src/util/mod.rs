@@ -1,2 +1,6 @@
-pub mod greedy_tcp_stream;
+pub mod disk_io;
+pub mod dual_stack;
+pub mod encrypted_stream;
+pub mod ip_filter;
 pub mod peer_message_stream;
+pub mod rate_limiter;
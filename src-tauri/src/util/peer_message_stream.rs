@@ -1,66 +1,143 @@
-use std::{io::Write, net::TcpStream};
-
-use crate::{
-    peer::{
-        peer_protocol::PeerProtocolError,
-        types::{PeerMessage, PeerMessageID},
-    },
-    util::greedy_tcp_stream::{GreedyTcpStream, MessageParser},
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+use crate::peer::{
+    peer_protocol::PeerProtocolError,
+    types::{PeerMessage, PeerMessageID},
 };
 
-pub struct PeerMessageStream {
-    pub stream: GreedyTcpStream<PeerMessage>,
+/// How many bytes we try to pull off the socket per read.
+const READ_CHUNK_SIZE: usize = 32768;
+/// Largest message we accept. Fits the biggest block we serve and the
+/// bitfield of a torrent with eight million pieces.
+const MAX_MESSAGE_LENGTH: usize = 1024 * 1024 + 9;
+
+/// A peer connection before it is split into its read and write halves. Only
+/// used for the handshake, which is not length-prefixed like the messages.
+pub struct PeerMessageStream<S> {
+    stream: S,
 }
 
-impl PeerMessageStream {
-    pub fn new(stream: TcpStream) -> Self {
-        let parser: MessageParser<PeerMessage> = Box::new(parse_next_peer_message);
-        Self {
-            stream: GreedyTcpStream {
-                stream,
+impl<S: AsyncRead + AsyncWrite + Unpin> PeerMessageStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.stream.write_all(buf).await?;
+        self.stream.flush().await
+    }
+
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.stream.read_exact(buf).await.map(|_| ())
+    }
+
+    pub fn split(
+        self,
+    ) -> (
+        PeerMessageReader<ReadHalf<S>>,
+        PeerMessageWriter<WriteHalf<S>>,
+    ) {
+        let (reader, writer) = split(self.stream);
+        (
+            PeerMessageReader {
+                reader,
                 bytes_left: Vec::new(),
-                parser,
             },
-        }
+            PeerMessageWriter { writer },
+        )
     }
+}
+
+pub struct PeerMessageReader<R> {
+    reader: R,
+    bytes_left: Vec<u8>,
+}
 
-    // Add a convenience method to get the next peer message
-    pub fn get_next_message(&mut self) -> PeerMessage {
-        self.stream.get_next_message()
+impl<R: AsyncRead + Unpin> PeerMessageReader<R> {
+    /// Reads the next message off the wire. Partially received messages stay
+    /// buffered, so this is safe to cancel inside `tokio::select!`.
+    pub async fn read_message(&mut self) -> Result<PeerMessage, PeerProtocolError> {
+        loop {
+            if let Some((message, bytes_used)) = parse_next_peer_message(&self.bytes_left)? {
+                self.bytes_left.drain(0..bytes_used);
+                match message {
+                    Some(message) => return Ok(message),
+                    None => continue,
+                }
+            }
+
+            self.bytes_left.reserve(READ_CHUNK_SIZE);
+            match self.reader.read_buf(&mut self.bytes_left).await {
+                Ok(0) | Err(_) => return Err(PeerProtocolError::ConnectionClosed),
+                Ok(_) => {}
+            }
+        }
     }
+}
 
-    pub fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        self.stream.stream.write_all(buf)
+pub struct PeerMessageWriter<W> {
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin> PeerMessageWriter<W> {
+    pub async fn write_message(&mut self, message: &PeerMessage) -> std::io::Result<()> {
+        self.writer.write_all(&Vec::from(message)).await?;
+        // Encrypted streams may hold on to part of the message until flushed
+        self.writer.flush().await
     }
 
-    pub fn try_read_message(&mut self) -> Result<Option<PeerMessage>, PeerProtocolError> {
-        self.stream.try_read_message()
+    pub async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.writer.shutdown().await
     }
 }
 
-fn parse_next_peer_message(buf: &[u8]) -> Option<(PeerMessage, usize)> {
+/// Splits the next frame off `buf`, `None` when more bytes are needed. The
+/// message is `None` for IDs we don't know, which get skipped.
+fn parse_next_peer_message(
+    buf: &[u8],
+) -> Result<Option<(Option<PeerMessage>, usize)>, PeerProtocolError> {
     if buf.len() < 4 {
-        return None;
+        return Ok(None);
     }
 
     let length = u32::from_be_bytes(buf[0..4].try_into().unwrap()) as usize;
+    // Refuse before buffering, a bad length prefix must not make us allocate
+    // up to 4 GiB
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(PeerProtocolError::InvalidMessage(format!(
+            "Message of {} bytes is over the {} byte limit",
+            length, MAX_MESSAGE_LENGTH
+        )));
+    }
     if buf.len() < 4 + length {
-        return None;
+        return Ok(None);
     }
 
     if length == 0 {
         // Keep-alive message
-        return Some((
-            PeerMessage {
-                id: PeerMessageID::KeepAlive,
-                length: 0,
-                payload: vec![],
-            },
-            4,
-        ));
+        let message = PeerMessage {
+            id: PeerMessageID::KeepAlive,
+            length: 0,
+            payload: vec![],
+        };
+        return Ok(Some((Some(message), 4)));
     }
 
-    let id = match buf[4] {
+    let Some(id) = message_id(buf[4]) else {
+        return Ok(Some((None, 4 + length)));
+    };
+    let payload = buf[5..4 + length].to_vec();
+    check_payload_length(id, payload.len())?;
+    let message = PeerMessage {
+        id,
+        length: (length - 1) as u32,
+        payload,
+    };
+    Ok(Some((Some(message), 4 + length)))
+}
+
+fn message_id(id: u8) -> Option<PeerMessageID> {
+    Some(match id {
         0 => PeerMessageID::Choke,
         1 => PeerMessageID::Unchoke,
         2 => PeerMessageID::Interested,
@@ -71,19 +148,46 @@ fn parse_next_peer_message(buf: &[u8]) -> Option<(PeerMessage, usize)> {
         7 => PeerMessageID::Piece,
         8 => PeerMessageID::Cancel,
         9 => PeerMessageID::Port,
+        13 => PeerMessageID::SuggestPiece,
+        14 => PeerMessageID::HaveAll,
+        15 => PeerMessageID::HaveNone,
+        16 => PeerMessageID::RejectRequest,
+        17 => PeerMessageID::AllowedFast,
         20 => PeerMessageID::Extended,
-        _ => {
-            println!("Unknown message ID: {}", buf[4]);
-            return None;
+        _ => return None,
+    })
+}
+
+/// Makes sure the payload is the size its message ID calls for, so handlers
+/// can read their fields without checking.
+fn check_payload_length(id: PeerMessageID, length: usize) -> Result<(), PeerProtocolError> {
+    let valid = match id {
+        PeerMessageID::KeepAlive
+        | PeerMessageID::Choke
+        | PeerMessageID::Unchoke
+        | PeerMessageID::Interested
+        | PeerMessageID::NotInterested
+        | PeerMessageID::HaveAll
+        | PeerMessageID::HaveNone => length == 0,
+        PeerMessageID::Have | PeerMessageID::SuggestPiece | PeerMessageID::AllowedFast => {
+            length == 4
+        }
+        PeerMessageID::Request | PeerMessageID::Cancel | PeerMessageID::RejectRequest => {
+            length == 12
         }
+        PeerMessageID::Port => length == 2,
+        PeerMessageID::Piece => length >= 8,
+        PeerMessageID::Extended => length >= 1,
+        // Checked against the piece count once handled
+        PeerMessageID::Bitfield => true,
     };
-    let payload = buf[5..4 + length].to_vec();
-    Some((
-        PeerMessage {
-            id,
-            length: (length - 1) as u32,
-            payload,
-        },
-        4 + length,
-    ))
+
+    if valid {
+        Ok(())
+    } else {
+        Err(PeerProtocolError::InvalidMessage(format!(
+            "{:?} message with a {} byte payload",
+            id, length
+        )))
+    }
 }
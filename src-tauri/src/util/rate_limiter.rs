@@ -0,0 +1,76 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// A token bucket refilled at `rate` bytes per second, holding up to one
+/// second's worth. A rate of 0 means unlimited.
+pub struct TokenBucket {
+    rate: AtomicU64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    /// Negative while callers are waiting on bytes they already took.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: AtomicU64::new(rate),
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Relaxed);
+    }
+
+    /// Takes `bytes` tokens, going into debt if there aren't enough, and
+    /// returns how long the caller has to wait before moving them.
+    pub fn take(&self, bytes: usize) -> Duration {
+        let rate = self.rate() as f64;
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.last_refill = now;
+        if rate == 0.0 {
+            state.tokens = 0.0;
+            return Duration::ZERO;
+        }
+
+        state.tokens = (state.tokens + elapsed * rate).min(rate) - bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate)
+        }
+    }
+}
+
+/// A download and an upload bucket.
+pub struct RateLimit {
+    pub download: TokenBucket,
+    pub upload: TokenBucket,
+}
+
+impl RateLimit {
+    pub fn new(download: u64, upload: u64) -> Self {
+        RateLimit {
+            download: TokenBucket::new(download),
+            upload: TokenBucket::new(upload),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::utp::packet::MAX_PAYLOAD_SIZE;
+
+/// Queuing delay LEDBAT aims for. Above it we back off to make room for
+/// other traffic on the link.
+const TARGET_DELAY_MICROS: f64 = 100_000.0;
+/// Most the window grows by in one round trip once out of slow start.
+const MAX_WINDOW_INCREASE_PER_RTT: f64 = 3000.0;
+pub const MIN_WINDOW: usize = 2 * MAX_PAYLOAD_SIZE;
+const MAX_WINDOW: usize = 4 * 1024 * 1024;
+/// The base delay is the lowest delay seen over this many minutes, so it can
+/// follow route changes.
+const BASE_DELAY_HISTORY: usize = 2;
+const BASE_DELAY_BUCKET: Duration = Duration::from_secs(60);
+
+/// LEDBAT congestion control (RFC 6817) as uTP uses it. The window is the
+/// number of bytes we may have in flight.
+pub struct Ledbat {
+    window: usize,
+    slow_start: bool,
+    /// Lowest one-way delay per minute, newest last.
+    base_delays: VecDeque<(Instant, u32)>,
+    last_decrease: Option<Instant>,
+}
+
+impl Ledbat {
+    pub fn new() -> Self {
+        Ledbat {
+            window: MIN_WINDOW,
+            slow_start: true,
+            base_delays: VecDeque::new(),
+            last_decrease: None,
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Grows or shrinks the window after `bytes_acked` bytes were acked by a
+    /// packet reporting a one-way delay of `delay` microseconds.
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32, now: Instant) {
+        self.update_base_delay(delay, now);
+        let base_delay = self.base_delay();
+        let queuing_delay = delay.wrapping_sub(base_delay) as f64;
+        let off_target = (TARGET_DELAY_MICROS - queuing_delay) / TARGET_DELAY_MICROS;
+
+        if self.slow_start && off_target > 0.0 {
+            self.window += bytes_acked;
+        } else {
+            // Queues are building up, slow start is over
+            self.slow_start = false;
+            let change =
+                MAX_WINDOW_INCREASE_PER_RTT * off_target * bytes_acked as f64 / self.window as f64;
+            self.window = (self.window as f64 + change) as usize;
+        }
+        self.window = self.window.clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    /// A packet was lost while others got through. Halves the window, at most
+    /// once per round trip.
+    pub fn on_loss(&mut self, rtt: Duration, now: Instant) {
+        if self
+            .last_decrease
+            .is_some_and(|last| now.duration_since(last) < rtt)
+        {
+            return;
+        }
+        self.last_decrease = Some(now);
+        self.slow_start = false;
+        self.window = (self.window / 2).max(MIN_WINDOW);
+    }
+
+    /// Nothing got through for a whole retransmission timeout.
+    pub fn on_timeout(&mut self) {
+        self.slow_start = false;
+        self.window = MIN_WINDOW;
+    }
+
+    fn update_base_delay(&mut self, delay: u32, now: Instant) {
+        match self.base_delays.back_mut() {
+            Some((start, lowest)) if now.duration_since(*start) < BASE_DELAY_BUCKET => {
+                *lowest = (*lowest).min(delay);
+            }
+            _ => {
+                self.base_delays.push_back((now, delay));
+                if self.base_delays.len() > BASE_DELAY_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+    }
+
+    fn base_delay(&self) -> u32 {
+        self.base_delays
+            .iter()
+            .map(|(_, delay)| *delay)
+            .min()
+            .unwrap_or(0)
+    }
+}
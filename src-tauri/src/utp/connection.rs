@@ -0,0 +1,598 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+    time::sleep_until,
+};
+
+use crate::utp::{
+    congestion::Ledbat,
+    packet::{Packet, PacketType, MAX_PAYLOAD_SIZE},
+};
+
+/// Bytes of out-of-order and undelivered data we buffer per connection.
+const RECEIVE_WINDOW: usize = 1024 * 1024;
+/// Furthest ahead of the next expected packet we buffer.
+const MAX_REORDER_DISTANCE: u16 = 1024;
+/// Longest selective ack bitmask we send, covering 256 packets.
+const MAX_SELECTIVE_ACK_BYTES: usize = 32;
+/// The SYN is resent this often, without backing off, until we give up.
+const SYN_INTERVAL: Duration = Duration::from_secs(1);
+const SYN_ATTEMPTS: u32 = 3;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+/// Timeouts in a row before the connection is considered dead.
+const MAX_TIMEOUTS: u32 = 8;
+/// A packet counts as lost once a packet this far past it was acked.
+const REORDER_THRESHOLD: u16 = 3;
+/// We send an empty ack when we haven't sent anything for this long, to keep
+/// NAT mappings open...
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(29);
+/// ...and give up on peers we haven't heard from for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub type ConnectionKey = (SocketAddr, u16);
+pub type ConnectionMap = Arc<Mutex<HashMap<ConnectionKey, mpsc::Sender<Packet>>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+}
+
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    needs_resend: bool,
+}
+
+/// One uTP connection. Runs as its own task, moving bytes between the
+/// application's end of a duplex pipe and packets on the shared socket.
+pub struct Connection {
+    socket: Arc<UdpSocket>,
+    connections: ConnectionMap,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: State,
+    /// Next sequence number we send.
+    seq_nr: u16,
+    /// Last sequence number received in order.
+    ack_nr: u16,
+
+    in_flight: VecDeque<SentPacket>,
+    bytes_in_flight: usize,
+    peer_window: usize,
+    congestion: Ledbat,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    rto_deadline: Option<Instant>,
+    timeouts: u32,
+
+    /// Packets received ahead of `ack_nr + 1`.
+    reorder: HashMap<u16, Vec<u8>>,
+    reorder_bytes: usize,
+    /// In-order data the application hasn't read yet.
+    undelivered: Vec<u8>,
+    /// Sequence number of the remote's FIN, once we have seen it.
+    eof_seq_nr: Option<u16>,
+    remote_closed: bool,
+    local_closed: bool,
+    fin_sent: bool,
+    /// The application dropped its end, received data goes nowhere.
+    reader_gone: bool,
+    reply_micros: u32,
+    last_sent: Instant,
+    last_received: Instant,
+
+    connected: Option<oneshot::Sender<()>>,
+}
+
+impl Connection {
+    /// Sets up the initiating side. `connected` fires once the remote
+    /// answered our SYN.
+    pub fn outgoing(
+        socket: Arc<UdpSocket>,
+        connections: ConnectionMap,
+        remote: SocketAddr,
+        recv_id: u16,
+        connected: oneshot::Sender<()>,
+    ) -> Self {
+        let mut connection = Connection::new(
+            socket,
+            connections,
+            remote,
+            recv_id,
+            recv_id.wrapping_add(1),
+        );
+        connection.state = State::SynSent;
+        connection.seq_nr = 1;
+        connection.connected = Some(connected);
+        connection
+    }
+
+    /// Sets up the receiving side for the connection `syn` asks for.
+    pub fn incoming(
+        socket: Arc<UdpSocket>,
+        connections: ConnectionMap,
+        remote: SocketAddr,
+        syn: &Packet,
+    ) -> Self {
+        let mut connection = Connection::new(
+            socket,
+            connections,
+            remote,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+        );
+        connection.state = State::Connected;
+        connection.seq_nr = rand::random();
+        connection.ack_nr = syn.seq_nr;
+        connection.reply_micros = now_micros().wrapping_sub(syn.timestamp);
+        connection
+    }
+
+    fn new(
+        socket: Arc<UdpSocket>,
+        connections: ConnectionMap,
+        remote: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+    ) -> Self {
+        let now = Instant::now();
+        Connection {
+            socket,
+            connections,
+            remote,
+            recv_id,
+            send_id,
+            state: State::SynSent,
+            seq_nr: 0,
+            ack_nr: 0,
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            peer_window: RECEIVE_WINDOW,
+            congestion: Ledbat::new(),
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_RTO,
+            rto_deadline: None,
+            timeouts: 0,
+            reorder: HashMap::new(),
+            reorder_bytes: 0,
+            undelivered: vec![],
+            eof_seq_nr: None,
+            remote_closed: false,
+            local_closed: false,
+            fin_sent: false,
+            reader_gone: false,
+            reply_micros: 0,
+            last_sent: now,
+            last_received: now,
+            connected: None,
+        }
+    }
+
+    pub async fn run(mut self, app: DuplexStream, mut packets: mpsc::Receiver<Packet>) {
+        let (mut app_reader, mut app_writer) = tokio::io::split(app);
+        let mut buf = vec![0; MAX_PAYLOAD_SIZE];
+        let mut app_writer_closed = false;
+
+        match self.state {
+            State::SynSent => {
+                let syn = Packet::new(PacketType::Syn, self.recv_id, self.seq_nr, 0);
+                self.send_tracked(syn).await;
+            }
+            State::Connected => self.send_ack().await,
+        }
+
+        loop {
+            if self.is_done() {
+                break;
+            }
+
+            // Deliver EOF once everything before the FIN has been read
+            if self.remote_closed && self.undelivered.is_empty() && !app_writer_closed {
+                let _ = app_writer.shutdown().await;
+                app_writer_closed = true;
+            }
+
+            let room = self.send_room();
+            let can_send = self.state == State::Connected
+                && !self.local_closed
+                && (room >= MAX_PAYLOAD_SIZE || self.in_flight.is_empty());
+            let send_length = room.clamp(1, MAX_PAYLOAD_SIZE);
+            let deadline = self.next_deadline();
+
+            tokio::select! {
+                packet = packets.recv() => {
+                    let Some(packet) = packet else { break };
+                    if !self.on_packet(packet).await {
+                        break;
+                    }
+                }
+                read = app_reader.read(&mut buf[..send_length]), if can_send => {
+                    match read {
+                        Ok(0) | Err(_) => self.local_closed = true,
+                        Ok(n) => {
+                            let mut packet = Packet::new(PacketType::Data, self.send_id, self.seq_nr, self.ack_nr);
+                            packet.payload = buf[..n].to_vec();
+                            self.send_tracked(packet).await;
+                        }
+                    }
+                }
+                written = app_writer.write(&self.undelivered), if !self.undelivered.is_empty() && !self.reader_gone => {
+                    match written {
+                        Ok(n) => {
+                            let was_full = self.receive_room() < MAX_PAYLOAD_SIZE;
+                            self.undelivered.drain(..n);
+                            // Let the remote know it can send again
+                            if was_full {
+                                self.send_ack().await;
+                            }
+                        }
+                        Err(_) => {
+                            self.reader_gone = true;
+                            self.undelivered.clear();
+                        }
+                    }
+                }
+                _ = sleep_until(deadline.into()) => {
+                    if !self.on_timer().await {
+                        break;
+                    }
+                }
+            }
+
+            // Our FIN goes out once all data before it is acked
+            if self.local_closed
+                && !self.fin_sent
+                && self.in_flight.is_empty()
+                && self.state == State::Connected
+            {
+                self.fin_sent = true;
+                let fin = Packet::new(PacketType::Fin, self.send_id, self.seq_nr, self.ack_nr);
+                self.send_tracked(fin).await;
+            }
+        }
+
+        self.connections
+            .lock()
+            .unwrap()
+            .remove(&(self.remote, self.recv_id));
+    }
+
+    /// Both sides sent their FIN and had it acked.
+    fn is_done(&self) -> bool {
+        self.fin_sent
+            && self.in_flight.is_empty()
+            && (self.remote_closed || self.reader_gone)
+            && (self.undelivered.is_empty() || self.reader_gone)
+    }
+
+    /// Handles a packet from the remote. Returns false once the connection
+    /// is over.
+    async fn on_packet(&mut self, packet: Packet) -> bool {
+        let now = Instant::now();
+        self.last_received = now;
+        self.reply_micros = now_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window_size as usize;
+
+        match packet.packet_type {
+            PacketType::Reset => return false,
+            PacketType::Syn => {
+                // Our answer to the SYN got lost
+                if self.state == State::Connected {
+                    self.send_ack().await;
+                }
+                return true;
+            }
+            _ => {}
+        }
+
+        if self.state == State::SynSent {
+            self.state = State::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            if let Some(connected) = self.connected.take() {
+                let _ = connected.send(());
+            }
+        }
+
+        self.on_ack(&packet, now);
+
+        match packet.packet_type {
+            PacketType::Data | PacketType::Fin => {
+                if packet.packet_type == PacketType::Fin {
+                    self.eof_seq_nr = Some(packet.seq_nr);
+                }
+                self.on_data(packet.seq_nr, packet.payload);
+                self.send_ack().await;
+            }
+            PacketType::State => {}
+            PacketType::Reset | PacketType::Syn => unreachable!(),
+        }
+        true
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        let mut acked_packets = 0;
+        let mut bytes_acked = 0;
+        let mut rtt_sample = None;
+        let mut highest_acked = packet.ack_nr;
+        let mut latest_sent_at = None;
+        let mut still_in_flight = VecDeque::with_capacity(self.in_flight.len());
+        for sent in self.in_flight.drain(..) {
+            let seq_nr = sent.packet.seq_nr;
+            let acked =
+                !seq_less_than(packet.ack_nr, seq_nr) || packet.is_selectively_acked(seq_nr);
+            if !acked {
+                still_in_flight.push_back(sent);
+                continue;
+            }
+
+            acked_packets += 1;
+            bytes_acked += sent.packet.payload.len();
+            if seq_less_than(highest_acked, seq_nr) {
+                highest_acked = seq_nr;
+            }
+            latest_sent_at = latest_sent_at.max(Some(sent.sent_at));
+            // Retransmitted packets can't tell which copy was acked
+            if sent.transmissions == 1 {
+                rtt_sample = Some(now.duration_since(sent.sent_at));
+            }
+        }
+        self.in_flight = still_in_flight;
+        if acked_packets == 0 {
+            return;
+        }
+
+        self.bytes_in_flight -= bytes_acked;
+        // Something got through, so stop backing off
+        if let Some(sample) = rtt_sample {
+            self.update_rtt(sample);
+        }
+        if let Some(rtt) = self.rtt {
+            self.rto = (rtt + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+        }
+        self.timeouts = 0;
+        self.congestion
+            .on_ack(bytes_acked, packet.timestamp_difference, now);
+        self.rto_deadline = (!self.in_flight.is_empty()).then(|| now + self.rto);
+
+        // Packets well before one that got through, or sent a while before
+        // one that got through, were most likely lost. Resent copies get a
+        // round trip before we try again.
+        let rtt = self.rtt.unwrap_or(INITIAL_RTO);
+        let mut lost = false;
+        for sent in self.in_flight.iter_mut() {
+            let age = now.duration_since(sent.sent_at);
+            let overtaken = highest_acked.wrapping_sub(sent.packet.seq_nr) >= REORDER_THRESHOLD
+                && seq_less_than(sent.packet.seq_nr, highest_acked)
+                && (sent.transmissions == 1 || age >= rtt);
+            let outlived =
+                latest_sent_at.is_some_and(|latest| sent.sent_at < latest) && age >= rtt + rtt / 4;
+            if overtaken || outlived {
+                sent.needs_resend = true;
+                lost = true;
+            }
+        }
+        if lost {
+            self.congestion.on_loss(rtt, now);
+        }
+    }
+
+    fn on_data(&mut self, seq_nr: u16, payload: Vec<u8>) {
+        let distance = seq_nr.wrapping_sub(self.ack_nr);
+        if distance == 0 || distance > MAX_REORDER_DISTANCE {
+            // Already have it, or too far ahead to buffer
+            return;
+        }
+        if distance > 1 {
+            if self.receive_room() >= payload.len() && !self.reorder.contains_key(&seq_nr) {
+                self.reorder_bytes += payload.len();
+                self.reorder.insert(seq_nr, payload);
+            }
+            return;
+        }
+
+        self.deliver(payload);
+        self.ack_nr = seq_nr;
+        while let Some(payload) = self.reorder.remove(&self.ack_nr.wrapping_add(1)) {
+            self.reorder_bytes -= payload.len();
+            self.deliver(payload);
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+        }
+
+        if self.eof_seq_nr == Some(self.ack_nr) {
+            self.remote_closed = true;
+        }
+    }
+
+    fn deliver(&mut self, payload: Vec<u8>) {
+        if !self.reader_gone {
+            self.undelivered.extend(payload);
+        }
+    }
+
+    /// Handles whichever timer went off. Returns false once the connection
+    /// is over.
+    async fn on_timer(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_received) >= IDLE_TIMEOUT {
+            self.send_reset().await;
+            return false;
+        }
+
+        if self.rto_deadline.is_some_and(|deadline| now >= deadline) {
+            self.timeouts += 1;
+            if self.state == State::SynSent {
+                if self.timeouts >= SYN_ATTEMPTS {
+                    return false;
+                }
+            } else {
+                if self.timeouts >= MAX_TIMEOUTS {
+                    self.send_reset().await;
+                    return false;
+                }
+                self.congestion.on_timeout();
+                self.rto = (self.rto * 2).min(MAX_RTO);
+            }
+
+            if let Some(oldest) = self.in_flight.front_mut() {
+                oldest.needs_resend = true;
+            }
+            self.resend_marked().await;
+            let rto = match self.state {
+                State::SynSent => SYN_INTERVAL,
+                State::Connected => self.rto,
+            };
+            self.rto_deadline = (!self.in_flight.is_empty()).then(|| now + rto);
+            return true;
+        }
+
+        self.resend_marked().await;
+        if now.duration_since(self.last_sent) >= KEEP_ALIVE_INTERVAL {
+            self.send_ack().await;
+        }
+        true
+    }
+
+    async fn resend_marked(&mut self) {
+        let now = Instant::now();
+        let mut resend = vec![];
+        for sent in self.in_flight.iter_mut() {
+            if sent.needs_resend {
+                sent.needs_resend = false;
+                sent.transmissions += 1;
+                sent.sent_at = now;
+                sent.packet.ack_nr = self.ack_nr;
+                resend.push(sent.packet.clone());
+            }
+        }
+        for packet in resend {
+            self.send(packet).await;
+        }
+    }
+
+    fn next_deadline(&self) -> Instant {
+        let mut deadline =
+            (self.last_sent + KEEP_ALIVE_INTERVAL).min(self.last_received + IDLE_TIMEOUT);
+        if let Some(rto_deadline) = self.rto_deadline {
+            deadline = deadline.min(rto_deadline);
+        }
+        // Packets marked for a fast resend go out right away
+        if self.in_flight.iter().any(|sent| sent.needs_resend) {
+            deadline = Instant::now();
+        }
+        deadline
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+    }
+
+    /// Bytes we may still put on the wire.
+    fn send_room(&self) -> usize {
+        self.congestion
+            .window()
+            .min(self.peer_window)
+            .saturating_sub(self.bytes_in_flight)
+    }
+
+    fn receive_room(&self) -> usize {
+        RECEIVE_WINDOW.saturating_sub(self.undelivered.len() + self.reorder_bytes)
+    }
+
+    /// Sends a packet that takes up a sequence number and has to be acked.
+    async fn send_tracked(&mut self, packet: Packet) {
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.bytes_in_flight += packet.payload.len();
+        self.in_flight.push_back(SentPacket {
+            packet: packet.clone(),
+            sent_at: Instant::now(),
+            transmissions: 1,
+            needs_resend: false,
+        });
+        if self.rto_deadline.is_none() {
+            let rto = match self.state {
+                State::SynSent => SYN_INTERVAL,
+                State::Connected => self.rto,
+            };
+            self.rto_deadline = Some(Instant::now() + rto);
+        }
+        self.send(packet).await;
+    }
+
+    async fn send_ack(&mut self) {
+        let mut ack = Packet::new(PacketType::State, self.send_id, self.seq_nr, self.ack_nr);
+        ack.selective_ack = self.selective_ack();
+        self.send(ack).await;
+    }
+
+    async fn send_reset(&mut self) {
+        let reset = Packet::new(PacketType::Reset, self.send_id, self.seq_nr, self.ack_nr);
+        self.send(reset).await;
+    }
+
+    async fn send(&mut self, mut packet: Packet) {
+        packet.timestamp = now_micros();
+        packet.timestamp_difference = self.reply_micros;
+        packet.window_size = self.receive_room() as u32;
+        self.last_sent = Instant::now();
+        let _ = self.socket.send_to(&Vec::from(&packet), self.remote).await;
+    }
+
+    /// Which packets past `ack_nr + 1` we already have.
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.reorder.is_empty() {
+            return None;
+        }
+
+        let mut bitmask = vec![0u8; 4];
+        for seq_nr in self.reorder.keys() {
+            let bit = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            if bit >= MAX_SELECTIVE_ACK_BYTES * 8 {
+                continue;
+            }
+            // The bitmask has to stay a multiple of 4 bytes
+            let needed = (bit / 32 + 1) * 4;
+            if bitmask.len() < needed {
+                bitmask.resize(needed, 0);
+            }
+            bitmask[bit / 8] |= 1 << (bit % 8);
+        }
+        Some(bitmask)
+    }
+}
+
+/// Whether `a` comes before `b`, allowing for sequence numbers wrapping.
+fn seq_less_than(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+pub fn now_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u32
+}
@@ -0,0 +1,8 @@
+pub mod congestion;
+pub mod connection;
+pub mod packet;
+pub mod socket;
+pub mod stream;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,150 @@
+pub const UTP_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 20;
+/// Largest payload we put in a packet, small enough to get through common
+/// links without IP fragmentation.
+pub const MAX_PAYLOAD_SIZE: usize = 1200;
+
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(format!("Unknown uTP packet type {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    pub window_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Bit `i` is set if packet `ack_nr + 2 + i` was received.
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Packet {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window_size: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: vec![],
+        }
+    }
+
+    /// Cheap check to tell uTP packets apart from the DHT's bencoded
+    /// messages arriving on the same socket.
+    pub fn is_utp(bytes: &[u8]) -> bool {
+        bytes.len() >= HEADER_SIZE && bytes[0] & 0x0f == UTP_VERSION && bytes[0] >> 4 <= 4
+    }
+
+    /// Whether packet `seq_nr` is marked as received in the selective ack.
+    pub fn is_selectively_acked(&self, seq_nr: u16) -> bool {
+        let Some(bitmask) = &self.selective_ack else {
+            return false;
+        };
+        let bit = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+        bit < bitmask.len() * 8 && bitmask[bit / 8] & (1 << (bit % 8)) != 0
+    }
+}
+
+impl From<&Packet> for Vec<u8> {
+    fn from(packet: &Packet) -> Self {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + packet.payload.len());
+        buf.push((packet.packet_type as u8) << 4 | UTP_VERSION);
+        buf.push(if packet.selective_ack.is_some() {
+            EXTENSION_SELECTIVE_ACK
+        } else {
+            EXTENSION_NONE
+        });
+        buf.extend_from_slice(&packet.connection_id.to_be_bytes());
+        buf.extend_from_slice(&packet.timestamp.to_be_bytes());
+        buf.extend_from_slice(&packet.timestamp_difference.to_be_bytes());
+        buf.extend_from_slice(&packet.window_size.to_be_bytes());
+        buf.extend_from_slice(&packet.seq_nr.to_be_bytes());
+        buf.extend_from_slice(&packet.ack_nr.to_be_bytes());
+        if let Some(bitmask) = &packet.selective_ack {
+            buf.push(EXTENSION_NONE);
+            buf.push(bitmask.len() as u8);
+            buf.extend_from_slice(bitmask);
+        }
+        buf.extend_from_slice(&packet.payload);
+        buf
+    }
+}
+
+impl TryFrom<&[u8]> for Packet {
+    type Error = String;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(format!("uTP packet too short: {} bytes", bytes.len()));
+        }
+        if bytes[0] & 0x0f != UTP_VERSION {
+            return Err(format!("Unsupported uTP version {}", bytes[0] & 0x0f));
+        }
+
+        let mut packet = Packet {
+            packet_type: PacketType::try_from(bytes[0] >> 4)?,
+            connection_id: u16::from_be_bytes(bytes[2..4].try_into().unwrap()),
+            timestamp: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            timestamp_difference: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            window_size: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
+            seq_nr: u16::from_be_bytes(bytes[16..18].try_into().unwrap()),
+            ack_nr: u16::from_be_bytes(bytes[18..20].try_into().unwrap()),
+            selective_ack: None,
+            payload: vec![],
+        };
+
+        // Walk the extension chain, keeping the ones we understand
+        let mut extension = bytes[1];
+        let mut offset = HEADER_SIZE;
+        while extension != EXTENSION_NONE {
+            if bytes.len() < offset + 2 {
+                return Err("Truncated uTP extension header".to_string());
+            }
+            let next = bytes[offset];
+            let length = bytes[offset + 1] as usize;
+            let data = bytes
+                .get(offset + 2..offset + 2 + length)
+                .ok_or("Truncated uTP extension")?;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                packet.selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            offset += 2 + length;
+        }
+
+        packet.payload = bytes[offset..].to_vec();
+        Ok(packet)
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{mpsc as std_mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle};
+
+use crate::{
+    util::dual_stack,
+    utp::{
+        connection::{Connection, ConnectionMap},
+        packet::{Packet, PacketType},
+        stream::UtpStream,
+    },
+};
+
+/// Packets queued for a connection before further ones are dropped.
+const CONNECTION_QUEUE_SIZE: usize = 256;
+/// Incoming connections waiting for `accept`.
+const ACCEPT_QUEUE_SIZE: usize = 32;
+/// Datagrams waiting for the DHT.
+const DATAGRAM_QUEUE_SIZE: usize = 256;
+/// Bytes buffered between a connection task and its stream.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A UDP socket carrying uTP connections (BEP 29) in both directions. Any
+/// datagram that isn't uTP, such as the DHT's KRPC messages, is passed on
+/// through `send_datagram` and `recv_datagram` so both can share a port.
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    connections: ConnectionMap,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+    datagrams: Mutex<std_mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    receiver: JoinHandle<()>,
+}
+
+impl UtpSocket {
+    /// Takes over a bound socket. Has to be called from within the runtime.
+    pub fn from_std(socket: std::net::UdpSocket) -> std::io::Result<Arc<Self>> {
+        socket.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket)?);
+        let connections: ConnectionMap = Arc::new(Mutex::new(HashMap::new()));
+        let (incoming_tx, incoming_rx) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+        let (datagrams_tx, datagrams_rx) = std_mpsc::sync_channel(DATAGRAM_QUEUE_SIZE);
+
+        let receiver = tokio::spawn(receive_packets(
+            Arc::clone(&socket),
+            Arc::clone(&connections),
+            incoming_tx,
+            datagrams_tx,
+        ));
+
+        Ok(Arc::new(UtpSocket {
+            socket,
+            connections,
+            incoming: tokio::sync::Mutex::new(incoming_rx),
+            datagrams: Mutex::new(datagrams_rx),
+            receiver,
+        }))
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Opens a uTP connection to `addr`.
+    pub async fn connect(&self, addr: SocketAddr) -> Result<UtpStream, String> {
+        let local = self
+            .local_addr()
+            .map_err(|e| format!("Failed to get local address: {}", e))?;
+        let remote = dual_stack::map_for(local, addr);
+
+        // Pick a connection id nobody else is using with this remote
+        let (packets_tx, packets_rx) = mpsc::channel(CONNECTION_QUEUE_SIZE);
+        let recv_id = {
+            let mut connections = self.connections.lock().unwrap();
+            let recv_id = loop {
+                let recv_id: u16 = rand::random();
+                if !connections.contains_key(&(remote, recv_id)) {
+                    break recv_id;
+                }
+            };
+            connections.insert((remote, recv_id), packets_tx);
+            recv_id
+        };
+
+        let (connected_tx, connected_rx) = tokio::sync::oneshot::channel();
+        let connection = Connection::outgoing(
+            Arc::clone(&self.socket),
+            Arc::clone(&self.connections),
+            remote,
+            recv_id,
+            connected_tx,
+        );
+        let (stream, app) = UtpStream::pair(addr, STREAM_BUFFER_SIZE);
+        tokio::spawn(connection.run(app, packets_rx));
+
+        connected_rx
+            .await
+            .map_err(|_| format!("uTP connection to {} timed out", addr))?;
+        Ok(stream)
+    }
+
+    /// Waits for the next connection a remote opened to us.
+    pub async fn accept(&self) -> Option<UtpStream> {
+        self.incoming.lock().await.recv().await
+    }
+
+    /// Sends `addr` a STATE packet for no connection in particular. The
+    /// remote drops it, but on the way out it opens our NAT for the remote's
+    /// SYN.
+    pub fn punch(&self, addr: SocketAddr) -> std::io::Result<()> {
+        let remote = dual_stack::map_for(self.local_addr()?, addr);
+        let packet = Packet::new(PacketType::State, rand::random(), 0, 0);
+        self.socket.try_send_to(&Vec::from(&packet), remote)?;
+        Ok(())
+    }
+
+    /// Sends a datagram that isn't part of a uTP connection.
+    pub fn send_datagram(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        self.socket.try_send_to(buf, addr)
+    }
+
+    /// Blocks for up to `timeout` for a datagram that isn't uTP.
+    pub fn recv_datagram(&self, timeout: Duration) -> Option<(Vec<u8>, SocketAddr)> {
+        self.datagrams.lock().unwrap().recv_timeout(timeout).ok()
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// Reads the socket and routes each datagram to its connection, a new
+/// incoming connection, or the datagram queue.
+async fn receive_packets(
+    socket: Arc<UdpSocket>,
+    connections: ConnectionMap,
+    incoming: mpsc::Sender<UtpStream>,
+    datagrams: std_mpsc::SyncSender<(Vec<u8>, SocketAddr)>,
+) {
+    let mut buf = vec![0; 65536];
+    loop {
+        // Errors here are ICMP reports for earlier sends, not fatal
+        let Ok((size, addr)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+
+        if !Packet::is_utp(&buf[..size]) {
+            let _ = datagrams.try_send((buf[..size].to_vec(), addr));
+            continue;
+        }
+        let Ok(packet) = Packet::try_from(&buf[..size]) else {
+            continue;
+        };
+
+        // A SYN's connection id is the one the remote receives on, we
+        // receive on the next one
+        let recv_id = match packet.packet_type {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+
+        let mut connections_guard = connections.lock().unwrap();
+        if let Some(connection) = connections_guard.get(&(addr, recv_id)) {
+            let _ = connection.try_send(packet);
+            continue;
+        }
+        if packet.packet_type != PacketType::Syn {
+            continue;
+        }
+
+        let (packets_tx, packets_rx) = mpsc::channel(CONNECTION_QUEUE_SIZE);
+        connections_guard.insert((addr, recv_id), packets_tx);
+        drop(connections_guard);
+
+        let connection =
+            Connection::incoming(Arc::clone(&socket), Arc::clone(&connections), addr, &packet);
+        let (stream, app) = UtpStream::pair(addr, STREAM_BUFFER_SIZE);
+        tokio::spawn(connection.run(app, packets_rx));
+        // If nobody is accepting, dropping the stream closes the connection
+        let _ = incoming.try_send(stream);
+    }
+}
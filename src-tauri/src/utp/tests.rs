@@ -0,0 +1,286 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    time::timeout,
+};
+
+use crate::utp::{
+    congestion::{Ledbat, MIN_WINDOW},
+    packet::{Packet, PacketType},
+    socket::UtpSocket,
+    stream::UtpStream,
+};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What the proxy between the two ends does to each datagram.
+#[derive(Clone, Copy, Default)]
+struct Impairment {
+    loss: f64,
+    reorder: f64,
+    duplicate: f64,
+}
+
+async fn bind() -> Arc<UtpSocket> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    UtpSocket::from_std(socket).unwrap()
+}
+
+/// Forwards datagrams between whoever talks to it first and `server`,
+/// dropping, delaying and duplicating them as `impairment` says.
+async fn start_proxy(server: SocketAddr, impairment: Impairment, seed: u64) -> SocketAddr {
+    let socket = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap());
+    let addr = socket.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut client = None;
+        let mut buf = vec![0; 65536];
+        loop {
+            let (size, from) = socket.recv_from(&mut buf).await.unwrap();
+            let to = if from == server {
+                match client {
+                    Some(client) => client,
+                    None => continue,
+                }
+            } else {
+                client = Some(from);
+                server
+            };
+
+            if rng.random_bool(impairment.loss) {
+                continue;
+            }
+            let copies = if rng.random_bool(impairment.duplicate) {
+                2
+            } else {
+                1
+            };
+            let delay = if rng.random_bool(impairment.reorder) {
+                Duration::from_millis(rng.random_range(1..30))
+            } else {
+                Duration::ZERO
+            };
+
+            let datagram = buf[..size].to_vec();
+            let socket = Arc::clone(&socket);
+            tokio::spawn(async move {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                for _ in 0..copies {
+                    let _ = socket.send_to(&datagram, to).await;
+                }
+            });
+        }
+    });
+
+    addr
+}
+
+/// Connects two uTP sockets, through an impairing proxy if given one. The
+/// sockets have to outlive the streams.
+async fn connected_pair(
+    impairment: Option<Impairment>,
+) -> (UtpStream, UtpStream, [Arc<UtpSocket>; 2]) {
+    let client = bind().await;
+    let server = bind().await;
+    let server_addr = server.local_addr().unwrap();
+    let target = match impairment {
+        Some(impairment) => start_proxy(server_addr, impairment, 7).await,
+        None => server_addr,
+    };
+
+    let (outgoing, incoming) = tokio::join!(client.connect(target), server.accept());
+    let outgoing = outgoing.expect("Failed to connect");
+    let incoming = incoming.expect("No incoming connection");
+    (outgoing, incoming, [client, server])
+}
+
+fn test_data(length: usize, seed: u64) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..length).map(|_| rng.random()).collect()
+}
+
+/// Sends `length` bytes each way at once and checks both arrive intact,
+/// followed by EOF.
+async fn exchange(a: UtpStream, b: UtpStream, length: usize) {
+    let a_data = test_data(length, 1);
+    let b_data = test_data(length, 2);
+
+    let run = async {
+        let (a_received, b_received) = tokio::join!(
+            send_and_receive(a, a_data.clone(), length),
+            send_and_receive(b, b_data.clone(), length)
+        );
+        assert!(a_received == b_data, "a received corrupted data");
+        assert!(b_received == a_data, "b received corrupted data");
+    };
+    timeout(TEST_TIMEOUT, run)
+        .await
+        .expect("Transfer timed out");
+}
+
+async fn send_and_receive(stream: UtpStream, data: Vec<u8>, expected: usize) -> Vec<u8> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let write = async move {
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+        writer
+    };
+    let read = async move {
+        let mut received = vec![];
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), expected);
+        received
+    };
+    let (_writer, received) = tokio::join!(write, read);
+    received
+}
+
+#[test]
+fn packet_round_trip() {
+    let mut packet = Packet::new(PacketType::Data, 4660, 65535, 100);
+    packet.timestamp = 123456;
+    packet.timestamp_difference = 789;
+    packet.window_size = 1 << 20;
+    packet.selective_ack = Some(vec![0b0000_0101, 0, 0, 0x80]);
+    packet.payload = b"hello".to_vec();
+
+    let bytes = Vec::from(&packet);
+    assert!(Packet::is_utp(&bytes));
+    let decoded = Packet::try_from(&bytes[..]).unwrap();
+    assert_eq!(decoded, packet);
+
+    // Bits count from ack_nr + 2
+    assert!(decoded.is_selectively_acked(102));
+    assert!(!decoded.is_selectively_acked(103));
+    assert!(decoded.is_selectively_acked(104));
+    assert!(decoded.is_selectively_acked(133));
+    assert!(!decoded.is_selectively_acked(101));
+    assert!(!decoded.is_selectively_acked(134));
+}
+
+#[test]
+fn rejects_malformed_packets() {
+    assert!(Packet::try_from(&[0x41, 0, 0, 0][..]).is_err());
+    // Selective ack extension that runs past the end
+    let mut bytes = Vec::from(&Packet::new(PacketType::State, 1, 1, 1));
+    bytes[1] = 1;
+    bytes.extend_from_slice(&[0, 8, 0xff]);
+    assert!(Packet::try_from(&bytes[..]).is_err());
+}
+
+#[test]
+fn dht_messages_are_not_utp() {
+    let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    assert!(!Packet::is_utp(ping));
+}
+
+#[test]
+fn ledbat_grows_below_target_and_backs_off_above_it() {
+    let now = Instant::now();
+    let mut ledbat = Ledbat::new();
+    // Establish a base delay, then stay close to it
+    for _ in 0..20 {
+        ledbat.on_ack(1200, 10_000, now);
+    }
+    let grown = ledbat.window();
+    assert!(grown > MIN_WINDOW);
+
+    // Queuing delay well past the 100ms target
+    for _ in 0..20 {
+        ledbat.on_ack(1200, 400_000, now);
+    }
+    assert!(ledbat.window() < grown);
+
+    ledbat.on_loss(Duration::from_millis(100), now);
+    let after_loss = ledbat.window();
+    // Only one decrease per round trip
+    ledbat.on_loss(Duration::from_millis(100), now);
+    assert_eq!(ledbat.window(), after_loss);
+
+    ledbat.on_timeout();
+    assert_eq!(ledbat.window(), MIN_WINDOW);
+}
+
+#[tokio::test]
+async fn transfers_in_both_directions() {
+    let (a, b, _sockets) = connected_pair(None).await;
+    exchange(a, b, 512 * 1024).await;
+}
+
+#[tokio::test]
+async fn survives_packet_loss() {
+    let impairment = Impairment {
+        loss: 0.1,
+        ..Default::default()
+    };
+    let (a, b, _sockets) = connected_pair(Some(impairment)).await;
+    exchange(a, b, 128 * 1024).await;
+}
+
+#[tokio::test]
+async fn survives_reordering() {
+    let impairment = Impairment {
+        reorder: 0.3,
+        ..Default::default()
+    };
+    let (a, b, _sockets) = connected_pair(Some(impairment)).await;
+    exchange(a, b, 256 * 1024).await;
+}
+
+#[tokio::test]
+async fn survives_loss_reordering_and_duplication() {
+    let impairment = Impairment {
+        loss: 0.05,
+        reorder: 0.2,
+        duplicate: 0.1,
+    };
+    let (a, b, _sockets) = connected_pair(Some(impairment)).await;
+    exchange(a, b, 128 * 1024).await;
+}
+
+#[tokio::test]
+async fn connect_fails_without_a_listener() {
+    let client = bind().await;
+    // Bound, but never answers
+    let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+    let result = client.connect(silent.local_addr().unwrap()).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn passes_other_datagrams_through() {
+    let utp = bind().await;
+    let other = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+
+    other
+        .send_to(ping, utp.local_addr().unwrap())
+        .await
+        .unwrap();
+    let received = tokio::task::spawn_blocking({
+        let utp = Arc::clone(&utp);
+        move || utp.recv_datagram(Duration::from_secs(5))
+    })
+    .await
+    .unwrap();
+    let (datagram, from) = received.expect("Datagram was not passed through");
+    assert_eq!(datagram, ping);
+    assert_eq!(from, other.local_addr().unwrap());
+
+    utp.send_datagram(b"reply", other.local_addr().unwrap())
+        .unwrap();
+    let mut buf = [0; 16];
+    let (size, _) = other.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..size], b"reply");
+}
@@ -0,0 +1,112 @@
+use reqwest::{header::RANGE, StatusCode};
+
+use crate::bencoding::torrent::Torrent;
+
+/// Part of a piece stored in one file: where the web seed serves the file,
+/// and the range of bytes in it.
+#[derive(Debug, PartialEq)]
+pub struct FileRange {
+    pub url: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// The URL a BEP 19 web seed serves a file at. Single-file torrents are
+/// served at `url` itself unless it ends with a slash, multi-file ones under
+/// a directory named after the torrent.
+pub fn file_url(url: &str, torrent: &Torrent, path: &[String]) -> String {
+    let name = urlencoding::encode(&torrent.info.name);
+    if torrent.info.files.is_none() {
+        return if url.ends_with('/') {
+            format!("{}{}", url, name)
+        } else {
+            url.to_string()
+        };
+    }
+
+    let mut file_url = format!("{}/{}", url.trim_end_matches('/'), name);
+    for component in path {
+        file_url.push('/');
+        file_url.push_str(&urlencoding::encode(component));
+    }
+    file_url
+}
+
+/// The file ranges piece `index` is made of, in order.
+pub fn piece_ranges(url: &str, torrent: &Torrent, index: u32) -> Vec<FileRange> {
+    let piece_start = index as u64 * torrent.info.piece_length;
+    let piece_end = piece_start + torrent.get_piece_length(index as usize) as u64;
+
+    let Some(files) = &torrent.info.files else {
+        return vec![FileRange {
+            url: file_url(url, torrent, &[]),
+            offset: piece_start,
+            length: piece_end - piece_start,
+        }];
+    };
+
+    let mut ranges = vec![];
+    let mut file_start = 0;
+    for file in files {
+        let file_end = file_start + file.length as u64;
+        let start = piece_start.max(file_start);
+        let end = piece_end.min(file_end);
+        if start < end {
+            ranges.push(FileRange {
+                url: file_url(url, torrent, &file.path),
+                offset: start - file_start,
+                length: end - start,
+            });
+        }
+        file_start = file_end;
+    }
+    ranges
+}
+
+/// Downloads piece `index` with a range request for each file it spans.
+pub async fn fetch_piece(
+    client: &reqwest::Client,
+    url: &str,
+    torrent: &Torrent,
+    index: u32,
+) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(torrent.get_piece_length(index as usize) as usize);
+    for range in piece_ranges(url, torrent, index) {
+        let end = range.offset + range.length;
+        let response = client
+            .get(&range.url)
+            .header(RANGE, format!("bytes={}-{}", range.offset, end - 1))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to request {}: {}", range.url, e))?;
+
+        let status = response.status();
+        if status != StatusCode::PARTIAL_CONTENT && status != StatusCode::OK {
+            return Err(format!("{} answered {}", range.url, status));
+        }
+        // Servers that don't do ranges send the whole file. That's only the
+        // range we asked for if the file is no bigger, otherwise we'd fetch
+        // all of it for every piece
+        if status == StatusCode::OK
+            && (range.offset != 0 || response.content_length() != Some(range.length))
+        {
+            return Err(format!("{} doesn't support range requests", range.url));
+        }
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", range.url, e))?;
+
+        if body.len() as u64 != range.length {
+            return Err(format!(
+                "{} sent {} bytes, expected {}",
+                range.url,
+                body.len(),
+                range.length
+            ));
+        }
+        data.extend_from_slice(&body);
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use reqwest::{header::RETRY_AFTER, StatusCode};
+
+use crate::{bencoding::torrent::Torrent, web_seed::WebSeedError};
+
+/// The request for the bytes `ranges` of piece `index` from a BEP 17 seeding
+/// script. Ranges are inclusive and relative to the start of the piece.
+pub fn piece_url(url: &str, info_hash: &[u8; 20], index: u32, ranges: &[(u32, u32)]) -> String {
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|(start, end)| format!("{}-{}", start, end))
+        .collect();
+    format!(
+        "{}{}info_hash={}&piece={}&ranges={}",
+        url,
+        if url.contains('?') { '&' } else { '?' },
+        urlencoding::encode_binary(info_hash),
+        index,
+        ranges.join(",")
+    )
+}
+
+/// Downloads piece `index` in one request. A busy seed answers 503 with the
+/// number of seconds to wait in the body or a `Retry-After` header.
+pub async fn fetch_piece(
+    client: &reqwest::Client,
+    url: &str,
+    torrent: &Torrent,
+    index: u32,
+) -> Result<Vec<u8>, WebSeedError> {
+    let length = torrent.get_piece_length(index as usize);
+    let response = client
+        .get(piece_url(
+            url,
+            &torrent.info_hash,
+            index,
+            &[(0, length - 1)],
+        ))
+        .send()
+        .await
+        .map_err(|e| WebSeedError::Failed(format!("Failed to request piece {}: {}", index, e)))?;
+
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok()?.trim().parse::<u64>().ok());
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| WebSeedError::Failed(format!("Failed to read piece {}: {}", index, e)))?;
+
+    match status {
+        StatusCode::OK if body.len() as u32 == length => Ok(body.to_vec()),
+        StatusCode::OK => Err(WebSeedError::Failed(format!(
+            "Got {} bytes of piece {}, expected {}",
+            body.len(),
+            index,
+            length
+        ))),
+        StatusCode::SERVICE_UNAVAILABLE => {
+            let seconds =
+                retry_after.or_else(|| std::str::from_utf8(&body).ok()?.trim().parse::<u64>().ok());
+            match seconds {
+                Some(seconds) => Err(WebSeedError::RetryAfter(Duration::from_secs(seconds))),
+                None => Err(WebSeedError::Failed("Seed is unavailable".to_string())),
+            }
+        }
+        status => Err(WebSeedError::Failed(format!(
+            "Seed answered {} for piece {}",
+            status, index
+        ))),
+    }
+}
@@ -0,0 +1,231 @@
+pub mod getright;
+pub mod hoffman;
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    sync::{atomic::AtomicU64, Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bencoding::torrent::Torrent,
+    peer::{
+        peer_protocol::receive_block,
+        types::{BlockReservation, PieceProgress, TorrentProgress},
+    },
+};
+
+/// Wait before trying a web seed again after the first failure, doubling
+/// with each failure after that...
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+/// ...up to this.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// How long a web seed gets to deliver a piece. Its blocks stay reserved
+/// for it until then.
+const PIECE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait when every piece we still need is being downloaded from
+/// somewhere else.
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How a web seed serves pieces.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WebSeedKind {
+    /// Plain files on an HTTP server, fetched with range requests (BEP 19).
+    GetRight,
+    /// A script handing out pieces by index (BEP 17).
+    Hoffman,
+}
+
+#[derive(Debug)]
+pub enum WebSeedError {
+    /// The seed is busy and asked us to come back after this long.
+    RetryAfter(Duration),
+    Failed(String),
+}
+
+/// An HTTP server holding the whole torrent. It has every piece, so it is
+/// asked for whichever piece no peer is working on.
+pub struct WebSeed {
+    pub url: String,
+    pub kind: WebSeedKind,
+    /// Failed pieces since the last one that worked.
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl WebSeed {
+    pub fn new(url: String, kind: WebSeedKind) -> Self {
+        WebSeed {
+            url,
+            kind,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    /// Backs the seed off exponentially.
+    pub fn failed(&mut self, now: Instant) -> Duration {
+        self.failures += 1;
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(MAX_BACKOFF);
+        self.retry_at = Some(now + backoff);
+        backoff
+    }
+
+    /// Holds off without counting it against the seed, it's only busy.
+    pub fn retry_after(&mut self, now: Instant, delay: Duration) {
+        self.retry_at = Some(now + delay);
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    pub fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    /// Fetches piece `index`, then hands its blocks to the same hash check
+    /// peer data goes through.
+    pub async fn download_piece(
+        &self,
+        client: &reqwest::Client,
+        torrent: &Torrent,
+        progress: &RwLock<TorrentProgress>,
+        completed_pieces: &AtomicU64,
+        index: u32,
+    ) -> Result<(), WebSeedError> {
+        let data = match self.kind {
+            WebSeedKind::GetRight => getright::fetch_piece(client, &self.url, torrent, index)
+                .await
+                .map_err(WebSeedError::Failed)?,
+            WebSeedKind::Hoffman => hoffman::fetch_piece(client, &self.url, torrent, index).await?,
+        };
+
+        let mut progress = progress.write().unwrap();
+        let blocks: Vec<(u32, u32)> = match progress.pieces.get(&index) {
+            Some(PieceProgress::InProgress(piece_progress)) => piece_progress
+                .data
+                .values()
+                .map(|block| (block.begin, block.length))
+                .collect(),
+            // A peer beat us to it
+            _ => return Ok(()),
+        };
+        let mut completed = false;
+        for (begin, length) in blocks {
+            let block = &data[begin as usize..(begin + length) as usize];
+            completed |= receive_block(
+                &mut progress,
+                index,
+                begin,
+                block,
+                &self.url,
+                false,
+                completed_pieces,
+            );
+        }
+
+        if completed {
+            Ok(())
+        } else {
+            Err(WebSeedError::Failed(format!(
+                "Piece {} failed its hash check",
+                index
+            )))
+        }
+    }
+}
+
+/// Downloads from the web seed at `url` until every piece is in.
+pub async fn run_web_seed(
+    url: String,
+    kind: WebSeedKind,
+    torrent: Arc<Torrent>,
+    progress: Arc<RwLock<TorrentProgress>>,
+    completed_pieces: Arc<AtomicU64>,
+) {
+    let client = match reqwest::Client::builder().timeout(PIECE_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            println!("{} - Failed to create HTTP client: {}", url, e);
+            return;
+        }
+    };
+    let mut seed = WebSeed::new(url, kind);
+
+    loop {
+        if let Some(retry_at) = seed.retry_at() {
+            tokio::time::sleep_until(retry_at.into()).await;
+        }
+
+        let index = {
+            let mut progress = progress.write().unwrap();
+            if progress.is_upload_only() {
+                return;
+            }
+            reserve_piece(&mut progress, &seed.url, Instant::now())
+        };
+        let Some(index) = index else {
+            tokio::time::sleep(IDLE_INTERVAL).await;
+            continue;
+        };
+
+        match seed
+            .download_piece(&client, &torrent, &progress, &completed_pieces, index)
+            .await
+        {
+            Ok(()) => seed.succeeded(),
+            Err(e) => {
+                progress
+                    .write()
+                    .unwrap()
+                    .release_peer_reservations(&seed.url);
+                match e {
+                    WebSeedError::RetryAfter(delay) => {
+                        seed.retry_after(Instant::now(), delay);
+                        println!("{} - Busy, retrying in {:?}", seed.url, delay);
+                    }
+                    WebSeedError::Failed(e) => {
+                        let backoff = seed.failed(Instant::now());
+                        println!("{} - {}, retrying in {:?}", seed.url, e, backoff);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reserves every block of the first wanted piece nobody has started on for the
+/// web seed at `url`. Partly downloaded pieces are left to the peers
+/// working on them.
+pub fn reserve_piece(progress: &mut TorrentProgress, url: &str, now: Instant) -> Option<u32> {
+    let mut indices: Vec<u32> = progress.pieces.keys().copied().collect();
+    indices.sort_unstable();
+
+    let index = indices.into_iter().find(|index| {
+        progress.is_wanted(*index)
+            && matches!(
+                progress.pieces.get(index),
+                Some(PieceProgress::InProgress(piece_progress))
+                    if piece_progress
+                        .data
+                        .keys()
+                        .all(|&begin| piece_progress.can_request_block(begin, url, now))
+            )
+    })?;
+
+    if let Some(PieceProgress::InProgress(piece_progress)) = progress.pieces.get_mut(&index) {
+        for block in piece_progress.data.values_mut() {
+            block.reservation = Some(BlockReservation {
+                peer: url.to_string(),
+                expires_at: now + PIECE_TIMEOUT,
+            });
+        }
+    }
+    Some(index)
+}
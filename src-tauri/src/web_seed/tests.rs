@@ -0,0 +1,533 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering::SeqCst},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::{
+    bencoding::torrent::{File, Torrent},
+    peer::types::{PieceProgress, TorrentProgress},
+    web_seed::{
+        getright::{piece_ranges, FileRange},
+        hoffman::piece_url,
+        reserve_piece, run_web_seed, WebSeed, WebSeedError, WebSeedKind,
+    },
+};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+const PIECE_LENGTH: u64 = 32 * 1024;
+
+/// What the test server sends back.
+struct Response {
+    status: &'static str,
+    headers: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: &'static str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body,
+        }
+    }
+}
+
+/// Answers HTTP/1.1 requests, one per connection, with whatever `respond`
+/// makes of the request target and its `Range` header.
+async fn start_server<F>(respond: F) -> String
+where
+    F: Fn(&str, Option<(usize, usize)>) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let respond = Arc::new(respond);
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let respond = Arc::clone(&respond);
+            tokio::spawn(async move { serve(stream, &*respond).await });
+        }
+    });
+    format!("http://{}", addr)
+}
+
+async fn serve<F>(mut stream: TcpStream, respond: &F)
+where
+    F: Fn(&str, Option<(usize, usize)>) -> Response,
+{
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.unwrap();
+        if n == 0 {
+            return;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8(request).unwrap();
+    let target = request.split(' ').nth(1).unwrap();
+    let range = request.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        let (start, end) = name
+            .eq_ignore_ascii_case("range")
+            .then_some(value.trim().strip_prefix("bytes=")?.split_once('-')?)?;
+        Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+    });
+
+    let response = respond(target, range);
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for header in &response.headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(&response.body).await.unwrap();
+    stream.shutdown().await.unwrap();
+}
+
+/// Serves `files` by path. Single `Range` headers are honoured unless
+/// `ranges` is off, in which case the whole file is sent.
+async fn start_file_server(files: HashMap<String, Vec<u8>>, ranges: bool) -> String {
+    start_server(move |path, range| match (files.get(path), range) {
+        (None, _) => Response::new("404 Not Found", vec![]),
+        (Some(file), Some((start, end))) if ranges => Response::new(
+            "206 Partial Content",
+            file[start..=end.min(file.len() - 1)].to_vec(),
+        ),
+        (Some(file), _) => Response::new("200 OK", file.clone()),
+    })
+    .await
+}
+
+fn content(length: usize, seed: u8) -> Vec<u8> {
+    (0..length)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+fn make_torrent(name: &str, data: &[u8], files: Option<Vec<File>>) -> Torrent {
+    Torrent::for_test(name, PIECE_LENGTH, data, files)
+}
+
+fn new_progress(torrent: &Torrent) -> Arc<RwLock<TorrentProgress>> {
+    Arc::new(RwLock::new(TorrentProgress::for_test(torrent)))
+}
+
+/// Lets the web seed at `url` download the whole torrent, returning what it
+/// assembled.
+async fn download(url: String, kind: WebSeedKind, torrent: Torrent) -> Vec<u8> {
+    let torrent = Arc::new(torrent);
+    let progress = new_progress(&torrent);
+    let completed_pieces = Arc::new(AtomicU64::new(0));
+    timeout(
+        TEST_TIMEOUT,
+        run_web_seed(
+            url,
+            kind,
+            Arc::clone(&torrent),
+            Arc::clone(&progress),
+            completed_pieces,
+        ),
+    )
+    .await
+    .expect("web seed didn't finish");
+
+    let progress = progress.read().unwrap();
+    (0..torrent.info.pieces.len() as u32)
+        .flat_map(|index| match &progress.pieces[&index] {
+            PieceProgress::Completed(data) => data.clone(),
+            PieceProgress::InProgress(_) => panic!("piece {} missing", index),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn downloads_single_file_torrent() {
+    let data = content(100_000, 1);
+    let base =
+        start_file_server(HashMap::from([("/payload.bin".into(), data.clone())]), true).await;
+
+    let torrent = make_torrent("payload.bin", &data, None);
+    assert_eq!(
+        download(format!("{}/", base), WebSeedKind::GetRight, torrent).await,
+        data
+    );
+}
+
+#[tokio::test]
+async fn downloads_single_piece_from_server_without_range_support() {
+    let data = content(20_000, 2);
+    let base = start_file_server(
+        HashMap::from([("/mirror/file".into(), data.clone())]),
+        false,
+    )
+    .await;
+
+    // The whole file is the range we asked for
+    let torrent = make_torrent("payload.bin", &data, None);
+    assert_eq!(
+        download(
+            format!("{}/mirror/file", base),
+            WebSeedKind::GetRight,
+            torrent
+        )
+        .await,
+        data
+    );
+}
+
+#[tokio::test]
+async fn server_without_range_support_fails_larger_files() {
+    let data = content(70_000, 2);
+    let base = start_file_server(
+        HashMap::from([("/mirror/file".into(), data.clone())]),
+        false,
+    )
+    .await;
+
+    let torrent = make_torrent("payload.bin", &data, None);
+    let seed = WebSeed::new(format!("{}/mirror/file", base), WebSeedKind::GetRight);
+    for index in [0, 1] {
+        let result = seed
+            .download_piece(
+                &reqwest::Client::new(),
+                &torrent,
+                &new_progress(&torrent),
+                &AtomicU64::new(0),
+                index,
+            )
+            .await;
+        assert!(
+            matches!(&result, Err(WebSeedError::Failed(e)) if e.contains("range requests")),
+            "piece {}",
+            index
+        );
+    }
+}
+
+#[tokio::test]
+async fn downloads_pieces_spanning_files() {
+    let files = [
+        (vec!["a.txt"], content(10_000, 3)),
+        (vec!["sub dir", "b.bin"], content(50_000, 4)),
+        (vec!["empty"], vec![]),
+        (vec!["c"], content(7, 5)),
+    ];
+    let data: Vec<u8> = files.iter().flat_map(|(_, data)| data.clone()).collect();
+    let base = start_file_server(
+        HashMap::from([
+            ("/multi/a.txt".into(), files[0].1.clone()),
+            ("/multi/sub%20dir/b.bin".into(), files[1].1.clone()),
+            ("/multi/c".into(), files[3].1.clone()),
+        ]),
+        true,
+    )
+    .await;
+
+    let torrent = make_torrent(
+        "multi",
+        &data,
+        Some(
+            files
+                .iter()
+                .map(|(path, data)| File {
+                    length: data.len() as i64,
+                    path: path.iter().map(|s| s.to_string()).collect(),
+                })
+                .collect(),
+        ),
+    );
+    assert_eq!(download(base, WebSeedKind::GetRight, torrent).await, data);
+}
+
+#[test]
+fn maps_pieces_to_file_ranges() {
+    let data = content(80_000, 0);
+    let torrent = make_torrent(
+        "dir",
+        &data,
+        Some(vec![
+            File {
+                length: 40_000,
+                path: vec!["one".into()],
+            },
+            File {
+                length: 40_000,
+                path: vec!["two".into()],
+            },
+        ]),
+    );
+
+    assert_eq!(
+        piece_ranges("http://seed/", &torrent, 1),
+        vec![
+            FileRange {
+                url: "http://seed/dir/one".into(),
+                offset: 32_768,
+                length: 7_232,
+            },
+            FileRange {
+                url: "http://seed/dir/two".into(),
+                offset: 0,
+                length: 25_536,
+            },
+        ]
+    );
+    assert_eq!(
+        piece_ranges("http://seed", &torrent, 2),
+        vec![FileRange {
+            url: "http://seed/dir/two".into(),
+            offset: 25_536,
+            length: 14_464,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn corrupt_piece_fails_hash_check() {
+    let data = content(40_000, 6);
+    let base = start_file_server(
+        HashMap::from([("/payload.bin".into(), content(40_000, 7))]),
+        true,
+    )
+    .await;
+    let url = format!("{}/payload.bin", base);
+
+    let torrent = make_torrent("payload.bin", &data, None);
+    let progress = new_progress(&torrent);
+    let seed = WebSeed::new(url.clone(), WebSeedKind::GetRight);
+    let index = reserve_piece(&mut progress.write().unwrap(), &url, Instant::now()).unwrap();
+    let result = seed
+        .download_piece(
+            &reqwest::Client::new(),
+            &torrent,
+            &progress,
+            &AtomicU64::new(0),
+            index,
+        )
+        .await;
+
+    assert!(result.is_err());
+    let progress = progress.read().unwrap();
+    assert_eq!(progress.hash_failures.get(&url), Some(&1));
+    assert!(matches!(
+        progress.pieces[&index],
+        PieceProgress::InProgress(_)
+    ));
+}
+
+#[tokio::test]
+async fn missing_file_is_an_error() {
+    let data = content(40_000, 8);
+    let base = start_file_server(HashMap::new(), true).await;
+
+    let torrent = make_torrent("payload.bin", &data, None);
+    let seed = WebSeed::new(format!("{}/payload.bin", base), WebSeedKind::GetRight);
+    let result = seed
+        .download_piece(
+            &reqwest::Client::new(),
+            &torrent,
+            &new_progress(&torrent),
+            &AtomicU64::new(0),
+            0,
+        )
+        .await;
+
+    assert!(matches!(result, Err(WebSeedError::Failed(e)) if e.contains("404")));
+}
+
+#[test]
+fn failures_back_off_exponentially() {
+    let mut seed = WebSeed::new("http://seed/file".into(), WebSeedKind::GetRight);
+    let now = Instant::now();
+    assert_eq!(seed.failed(now), Duration::from_secs(30));
+    assert_eq!(seed.failed(now), Duration::from_secs(60));
+    assert_eq!(seed.retry_at(), Some(now + Duration::from_secs(60)));
+
+    seed.succeeded();
+    assert_eq!(seed.retry_at(), None);
+    assert_eq!(seed.failed(now), Duration::from_secs(30));
+}
+
+#[test]
+fn web_seeds_take_different_pieces() {
+    let data = content(100_000, 9);
+    let torrent = make_torrent("payload.bin", &data, None);
+    let progress = new_progress(&torrent);
+    let mut progress = progress.write().unwrap();
+    let now = Instant::now();
+
+    assert_eq!(reserve_piece(&mut progress, "http://a/", now), Some(0));
+    assert_eq!(reserve_piece(&mut progress, "http://b/", now), Some(1));
+    progress.release_peer_reservations("http://a/");
+    assert_eq!(reserve_piece(&mut progress, "http://b/", now), Some(0));
+}
+
+/// The server side of BEP 17: hands out byte ranges of the pieces of `data`,
+/// answering the first `busy` requests with a 503 and a retry delay.
+struct HoffmanServer {
+    info_hash: [u8; 20],
+    data: Vec<u8>,
+    busy: AtomicU32,
+    /// Send the delay in a `Retry-After` header rather than the body.
+    retry_header: bool,
+}
+
+impl HoffmanServer {
+    fn respond(&self, target: &str) -> Response {
+        let Some((_, query)) = target.split_once('?') else {
+            return Response::new("400 Bad Request", vec![]);
+        };
+        let params: HashMap<&str, &str> = query
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .collect();
+        if *urlencoding::decode_binary(params["info_hash"].as_bytes()) != self.info_hash[..] {
+            return Response::new("404 Not Found", vec![]);
+        }
+
+        if self
+            .busy
+            .fetch_update(SeqCst, SeqCst, |busy| busy.checked_sub(1))
+            .is_ok()
+        {
+            return if self.retry_header {
+                Response {
+                    status: "503 Service Unavailable",
+                    headers: vec!["Retry-After: 1".into()],
+                    body: vec![],
+                }
+            } else {
+                Response::new("503 Service Unavailable", b"1".to_vec())
+            };
+        }
+
+        let piece_start = params["piece"].parse::<usize>().unwrap() * PIECE_LENGTH as usize;
+        let mut body = vec![];
+        for range in params["ranges"].split(',') {
+            let (start, end) = range.split_once('-').unwrap();
+            let start = piece_start + start.parse::<usize>().unwrap();
+            let end = piece_start + end.parse::<usize>().unwrap();
+            body.extend_from_slice(&self.data[start..=end]);
+        }
+        Response::new("200 OK", body)
+    }
+}
+
+async fn start_hoffman_server(
+    torrent: &Torrent,
+    data: &[u8],
+    busy: u32,
+    retry_header: bool,
+) -> String {
+    let server = HoffmanServer {
+        info_hash: torrent.info_hash,
+        data: data.to_vec(),
+        busy: AtomicU32::new(busy),
+        retry_header,
+    };
+    let base = start_server(move |target, _| server.respond(target)).await;
+    format!("{}/seed.php", base)
+}
+
+#[test]
+fn builds_http_seed_requests() {
+    assert_eq!(
+        piece_url(
+            "http://seed/seed.php",
+            &[0xab; 20],
+            3,
+            &[(0, 16383), (32768, 49151)]
+        ),
+        format!(
+            "http://seed/seed.php?info_hash={}&piece=3&ranges=0-16383,32768-49151",
+            "%AB".repeat(20)
+        )
+    );
+    assert!(piece_url("http://seed/?torrent=1", &[0; 20], 0, &[(0, 1)])
+        .starts_with("http://seed/?torrent=1&info_hash="));
+}
+
+#[tokio::test]
+async fn downloads_from_http_seed() {
+    let data = content(100_000, 10);
+    let torrent = make_torrent("payload.bin", &data, None);
+    let url = start_hoffman_server(&torrent, &data, 0, false).await;
+
+    assert_eq!(download(url, WebSeedKind::Hoffman, torrent).await, data);
+}
+
+#[tokio::test]
+async fn busy_http_seed_asks_us_to_retry() {
+    let data = content(40_000, 11);
+    let torrent = make_torrent("payload.bin", &data, None);
+
+    for retry_header in [false, true] {
+        let url = start_hoffman_server(&torrent, &data, 1, retry_header).await;
+        let seed = WebSeed::new(url, WebSeedKind::Hoffman);
+        let result = seed
+            .download_piece(
+                &reqwest::Client::new(),
+                &torrent,
+                &new_progress(&torrent),
+                &AtomicU64::new(0),
+                0,
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(WebSeedError::RetryAfter(delay)) if delay == Duration::from_secs(1)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn waits_out_busy_http_seed() {
+    let data = content(70_000, 12);
+    let torrent = make_torrent("payload.bin", &data, None);
+    let url = start_hoffman_server(&torrent, &data, 2, false).await;
+
+    // Being busy isn't a failure, so the seed is back a second later rather
+    // than after the failure backoff
+    let started = Instant::now();
+    assert_eq!(download(url, WebSeedKind::Hoffman, torrent).await, data);
+    assert!(started.elapsed() < Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn http_seed_rejects_unknown_torrent() {
+    let data = content(40_000, 13);
+    let torrent = make_torrent("payload.bin", &data, None);
+    let mut other = make_torrent("other.bin", &data, None);
+    other.info_hash = [2; 20];
+    let url = start_hoffman_server(&other, &data, 0, false).await;
+
+    let seed = WebSeed::new(url, WebSeedKind::Hoffman);
+    let result = seed
+        .download_piece(
+            &reqwest::Client::new(),
+            &torrent,
+            &new_progress(&torrent),
+            &AtomicU64::new(0),
+            0,
+        )
+        .await;
+    assert!(matches!(result, Err(WebSeedError::Failed(e)) if e.contains("404")));
+}